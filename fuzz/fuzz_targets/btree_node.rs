@@ -0,0 +1,38 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use apfs::{fletcher64, APFS, ApfsValue, LeafValue, OmapVal, Oid, StorageType, NX_DEFAULT_BLOCK_SIZE, NX_MAGIC};
+
+/* Block 0 holds just enough of a superblock to open the container and
+   block 1 holds the fuzzed node, re-checksummed so decoding gets past
+   the checksum test */
+fn build_image(data: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; NX_DEFAULT_BLOCK_SIZE * 2];
+    image[32..36].copy_from_slice(&NX_MAGIC.to_le_bytes());
+    image[36..40].copy_from_slice(&(NX_DEFAULT_BLOCK_SIZE as u32).to_le_bytes());
+    let block = &mut image[NX_DEFAULT_BLOCK_SIZE..];
+    let len = data.len().min(block.len());
+    block[..len].copy_from_slice(&data[..len]);
+    let checksum = fletcher64(&block[8..]);
+    block[0..8].copy_from_slice(&checksum.to_le_bytes());
+    image
+}
+
+fn walk<V: LeafValue>(apfs: &APFS<Vec<u8>>) {
+    if let Ok(btree) = apfs.load_btree::<V>(Oid(1), StorageType::Physical) {
+        for record in btree.iter(apfs) {
+            if record.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let apfs = match APFS::open_source(build_image(data)) {
+        Ok(apfs) => apfs,
+        Err(_) => return,
+    };
+    walk::<OmapVal>(&apfs);
+    walk::<ApfsValue>(&apfs);
+});
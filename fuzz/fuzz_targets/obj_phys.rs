@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use apfs::ObjPhys;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ObjPhys::parse(data);
+    let _ = ObjPhys::import(&mut &data[..]);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use apfs::{import_drec_xfields, import_inode_xfields};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = import_inode_xfields(data);
+    let _ = import_drec_xfields(data);
+});
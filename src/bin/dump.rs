@@ -1,25 +1,25 @@
-use std::{fs::File, cmp::min, io::{Cursor, BufRead}, env::VarError};
+use std::{io::{Cursor, BufRead, Read}, env::VarError};
 // use std::{convert::TryInto, borrow::Borrow, io::Write, os::unix::prelude::OsStrExt};
 
 // use aes_keywrap::Aes128KeyWrap;
 // use aes_keywrap_rs::{aes_unwrap_key, aes_unwrap_key_and_iv};
-use apfs::{APFS, APFSObject, Btree, Oid, Paddr, StorageType, OvFlags, OmapVal, OmapRecord, ApfsValue, AnyRecords, InoExtType, InodeXdata, OmapKey, ObjectType, SpacemanFreeQueueValue, NX_EFI_JUMPSTART_MAGIC, NX_EFI_JUMPSTART_VERSION, load_btree_generic, LeafValue, BtreeTypes, MediaKeybag, ObjPhys, KbTag, Prange};
+use apfs::{APFS, APFSMount, Volume, ApfsError, ImageSource, APFSObject, Btree, Oid, Paddr, StorageType, OmapVal, ApfsValue, AnyRecords, InoExtType, ObjectType, SpacemanFreeQueueValue, NX_EFI_JUMPSTART_MAGIC, NX_EFI_JUMPSTART_VERSION, load_btree_generic, LeafValue, BtreeTypes, MediaKeybag, ObjPhys, KbTag, Prange};
 use der::{Decoder, TagNumber, asn1::OctetString, DecodeValue, FixedTag, Any};
 // use lzy_pbkdf2::pbkdf2_hmac_sha256;
 use fastpbkdf2::pbkdf2_hmac_sha256;
 // use der_derive::Sequence;
 
-use std::{env, collections::HashMap};
+use std::env;
 
 use aes::{Aes128, cipher::KeyInit, cipher::generic_array::GenericArray};
 use xts_mode::{Xts128, get_tweak_default};
 
-fn dump_btree_records<V>(name: &str, btree: &Btree<V>, apfs: &mut APFS<File>, records: &AnyRecords<V>) where V: LeafValue {
+fn dump_btree_records<V>(name: &str, btree: &Btree<V>, apfs: &APFS<ImageSource>, records: &AnyRecords<V>) where V: LeafValue {
     match records {
         AnyRecords::Leaf(_) => {},
         AnyRecords::NonLeaf(children, _) => {
             for child in children {
-                let node_result = btree.load_btree_node(apfs, child.value.oid, StorageType::Physical);
+                let node_result = btree.load_child_node(apfs, child.value.oid);
                 if node_result.is_err() {
                     println!("Error: {:?}", node_result.as_ref().err());
                 }
@@ -32,7 +32,7 @@ fn dump_btree_records<V>(name: &str, btree: &Btree<V>, apfs: &mut APFS<File>, re
     };
 }
 
-fn dump_btree(name: &str, apfs: &mut APFS<File>, oid: Oid) {
+fn dump_btree(name: &str, apfs: &APFS<ImageSource>, oid: Oid) {
     let btree = load_btree_generic(apfs, oid, StorageType::Physical)
         .expect("Bad b-tree load");
     println!("{} B-Tree: {:#?}", name, &btree);
@@ -43,7 +43,7 @@ fn dump_btree(name: &str, apfs: &mut APFS<File>, oid: Oid) {
     }
 }
 
-fn dump_omap_apfs_records(btree: &Btree<OmapVal>, apfs: &mut APFS<File>, records: &AnyRecords<OmapVal>) {
+fn dump_omap_apfs_records(btree: &Btree<OmapVal>, apfs: &APFS<ImageSource>, records: &AnyRecords<OmapVal>) {
     match records {
         AnyRecords::Leaf(_) => {},
         AnyRecords::NonLeaf(children, _) => {
@@ -61,37 +61,21 @@ fn dump_omap_apfs_records(btree: &Btree<OmapVal>, apfs: &mut APFS<File>, records
     };
 }
 
-fn dump_apfs_records(btree: &Btree<ApfsValue>, apfs: &mut APFS<File>, omap_btree: &Btree<OmapVal>, records: &AnyRecords<ApfsValue>) {
-    let empty = vec![];
-    let file_records = match records {
-        AnyRecords::Leaf(ref x) => x,
-        AnyRecords::NonLeaf(children, _) => {
-            for child in children {
-                let root_object = omap_btree.get_record(apfs, &OmapKey::new(child.value.oid.0, u64::MAX))
-                    .expect("I/O error")
-                    .expect("Failed to find address for Volume root B-tree");
-                let node_result = btree.load_btree_node(apfs, Oid(root_object.value.paddr.0 as u64), StorageType::Physical);
-                let node = node_result.expect("Bad b-tree node load");
-                println!("Volume Root sub B-Tree: {:#?}", &node);
-                dump_apfs_records(btree, apfs, omap_btree, &node.records);
-            }
-            &empty
-        },
-    };
-    let mut sizes = HashMap::<u64, u64>::new();
-    for file_record in file_records {
+fn dump_apfs_records(volume: &Volume<ImageSource>) {
+    for file_record in volume.root_tree().iter(volume.apfs()) {
+        let file_record = file_record.expect("Bad b-tree record");
+        println!("Volume Root record: {:#?}", &file_record);
         if let ApfsValue::Inode(ref y) = file_record.value {
-            if let Some(&InodeXdata::Dstream(ref z)) = y.xdata.get(&InoExtType::Dstream) {
-                sizes.insert(file_record.key.key.obj_id_and_type.id(), z.size);
-            }
-        } else if let ApfsValue::FileExtent(ref y) = file_record.value {
-            if let Some(&length) = sizes.get(&file_record.key.key.obj_id_and_type.id()) {
-                println!("Reading block: {} ({} bytes)", y.phys_block_num, length);
-                if let Ok(block) = apfs.load_block(Paddr(y.phys_block_num as i64)) {
-                    println!("Body: '{}'", String::from_utf8((&block[0..min(length as usize, block.len())]).to_owned()).unwrap_or_else(|_| String::from("(binary)")));
+            if y.xdata.contains_key(&InoExtType::Dstream) {
+                let ino = file_record.key.key.obj_id_and_type.id();
+                let mut body = vec![];
+                match volume.open_file(ino).and_then(|mut file| Ok(file.read_to_end(&mut body)?)) {
+                    Ok(length) => {
+                        println!("Read file {} ({} bytes)", ino, length);
+                        println!("Body: '{}'", String::from_utf8(body).unwrap_or_else(|_| String::from("(binary)")));
+                    },
+                    Err(err) => { println!("Error reading file {}: {}", ino, err); },
                 }
-            } else {
-                println!("Missing inode for file!");
             }
         }
     }
@@ -171,9 +155,9 @@ fn verify_key_blob(key: &KeyBlob) -> bool {
 
 fn main() {
     println!("Dumping file");
-    let mut apfs = APFS::open(env::args().skip(1).next().unwrap()).unwrap();
-    let superblock = match apfs.load_object_addr(Paddr(0)).unwrap() {
-        APFSObject::Superblock(x) => x,
+    let apfs = APFS::open(env::args().skip(1).next().unwrap()).unwrap();
+    let superblock = match *apfs.load_object_addr(Paddr(0)).unwrap() {
+        APFSObject::Superblock(ref x) => x.clone(),
         _ => { panic!("Wrong object type!"); },
     };
     println!("Container Superblock: {:#?}", superblock);
@@ -186,7 +170,7 @@ fn main() {
     for idx in 0..superblock.body.xp_data_blocks {
         let object = apfs.load_object_addr(Paddr(superblock.body.xp_data_base.0+idx as i64));//.unwrap();
         println!("Checkpoint data object: {:#?}", &object);
-        if let Ok(APFSObject::Spaceman(body)) = object {
+        if let Ok(APFSObject::Spaceman(body)) = object.as_deref() {
             let subobject_result = apfs.load_object_addr(body.body.ip_base);
             if let Ok(subobject) = subobject_result {
                 println!("Internal pool data object: {:#?}", &subobject);
@@ -199,7 +183,7 @@ fn main() {
             // }
             // let subobject = apfs.load_object_addr(body.body.ip_bm_base).unwrap();
             // println!("Internal pool bitmap data object: {:#?}", &subobject);
-        } else if let Ok(APFSObject::Btree(body)) = object {
+        } else if let Ok(APFSObject::Btree(body)) = object.as_deref() {
             if body.header.subtype.r#type() == ObjectType::SpacemanFreeQueue {
                 let btree = apfs.load_btree::<SpacemanFreeQueueValue>(Oid(superblock.body.xp_data_base.0 as u64 + idx as u64), StorageType::Physical)
                     .expect("Bad b-tree load");
@@ -223,27 +207,26 @@ fn main() {
     if superblock.body.efi_jumpstart != Paddr(0) {
         println!("Dumping Bootloader");
         let object = apfs.load_object_addr(superblock.body.efi_jumpstart).unwrap();
-        let jumpstart = match object {
-            APFSObject::EfiJumpstart(x) => x,
+        let jumpstart = match *object {
+            APFSObject::EfiJumpstart(ref x) => x.clone(),
             _ => { panic!("Wrong object type!"); },
         };
         println!("EFI Jumpstart: {:#?}", &jumpstart);
         assert_eq!(jumpstart.body.magic, NX_EFI_JUMPSTART_MAGIC);
         assert_eq!(jumpstart.body.version, NX_EFI_JUMPSTART_VERSION);
-        let mut loader = vec![];
+        let mut loader_bytes = vec![];
         for range in jumpstart.body.rec_extents {
             for idx in 0..range.block_count {
-                loader.push(apfs.load_block(Paddr(range.start_paddr.0 + idx as i64)).expect("Failed to load jumpstart block"));
+                loader_bytes.extend_from_slice(&apfs.load_block(Paddr(range.start_paddr.0 + idx as i64)).expect("Failed to load jumpstart block"));
             }
         }
-        let mut loader_bytes = loader.into_iter().flatten().collect::<Vec<u8>>();
         loader_bytes.shrink_to(jumpstart.body.efi_file_len as usize);
         println!("Bootloader: {:?}", loader_bytes);
     }
     if superblock.body.keylocker.start_paddr.0 != 0 &&
        superblock.body.keylocker.block_count != 0 {
         println!("Found keylocker");
-        let mut encrypted_bag = apfs.load_block(superblock.body.keylocker.start_paddr).expect("failed to load keybag");
+        let mut encrypted_bag = apfs.load_block(superblock.body.keylocker.start_paddr).expect("failed to load keybag").into_owned();
         // println!("{:?}", encrypted_bag);
         let kek = superblock.body.uuid.as_bytes();
 
@@ -276,7 +259,7 @@ fn main() {
 
                 // let sector_size = 0x200;
                 let first_sector_index = block_range.start_paddr.0 * (superblock.body.block_size as i64 / sector_size);
-                let mut encrypted_bag = apfs.load_block(block_range.start_paddr).expect("failed to load volume keybag");
+                let mut encrypted_bag = apfs.load_block(block_range.start_paddr).expect("failed to load volume keybag").into_owned();
                 xts.decrypt_area(&mut encrypted_bag, sector_size as usize, first_sector_index as u128, get_tweak_default);
                 println!("Volume keybag decrypt: {:#x?}", &encrypted_bag);
                 let mut keybag_cursor = Cursor::new(&encrypted_bag);
@@ -428,64 +411,36 @@ fn main() {
         // }
         // println!("{:?}", apfs.load_object_addr(superblock.body.keylocker.start_paddr));
     }
-    let object = apfs.load_object_oid(superblock.body.omap_oid, StorageType::Physical).unwrap();
-    let omap = match object {
-        APFSObject::ObjectMap(x) => x,
-        _ => { panic!("Wrong object type!"); },
-    };
-    let btree_result = apfs.load_btree::<OmapVal>(omap.body.tree_oid, StorageType::Physical);
-    if btree_result.is_err() {
-        println!("Error: {:?}", btree_result.as_ref().err());
-    }
-    assert!(btree_result.is_ok(), "Bad b-tree load");
-    let btree = btree_result.unwrap();
+    let mount = APFSMount::mount(apfs).expect("Failed to mount container");
+    let btree = mount.object_map_btree();
     println!("Superblock Object Map B-Tree: {:#?}", btree);
-    let records: &Vec<OmapRecord> = match &btree.root.records {
-        AnyRecords::Leaf(ref x) => x,
-        _ => { panic!("Wrong b-tree record type!"); },
-    };
-    for record in records {
-        let object = apfs.load_object_addr(record.value.paddr).unwrap();
-        let volume = match object {
-            APFSObject::ApfsSuperblock(x) => x,
-            _ => { panic!("Wrong object type!"); },
+    let apfs = mount.apfs();
+    for idx in 0..mount.volume_oids().len() {
+        let volume = match mount.volume(idx) {
+            Ok(volume) => volume,
+            Err(ApfsError::ObjectEncrypted { .. }) => {
+                println!("Encrypted volume found, skipping...");
+                continue;
+            },
+            Err(err) => { panic!("Failed to load volume {}: {}", idx, err); },
         };
-        println!("Volume Superblock: {:#?}", volume);
-        let object = apfs.load_object_oid(volume.body.omap_oid, StorageType::Physical).unwrap();
-        let omap = match object {
-            APFSObject::ObjectMap(x) => x,
-            _ => { panic!("Wrong object type!"); },
-        };
-        let btree = apfs.load_btree::<OmapVal>(omap.body.tree_oid, StorageType::Physical)
-            .expect("Bad b-tree load");
-        println!("Volume Object Map B-Tree: {:#?}", &btree);
-        dump_omap_apfs_records(&btree, &mut apfs, &btree.root.records);
-        dump_btree("Volume Extent Reference", &mut apfs, volume.body.extentref_tree_oid);
-        dump_btree("Volume Snapshot Metadata", &mut apfs, volume.body.snap_meta_tree_oid);
-        if volume.body.snap_meta_ext_oid != Oid(0) {
-            let root_object = btree.get_record(&mut apfs, &OmapKey::new(volume.body.snap_meta_ext_oid.0, u64::MAX))
-                .expect("I/O error")
-                .expect("Failed to find address for Volume Snapshot extended data");
-            let object = apfs.load_object_oid(Oid(root_object.value.paddr.0 as u64), StorageType::Physical).unwrap();
-            let ext = match object {
-                APFSObject::SnapMetaExt(x) => x,
+        let superblock = volume.superblock();
+        println!("Volume Superblock: {:#?}", superblock);
+        let btree = volume.object_map();
+        println!("Volume Object Map B-Tree: {:#?}", btree);
+        dump_omap_apfs_records(btree, apfs, &btree.root.records);
+        dump_btree("Volume Extent Reference", apfs, superblock.body.extentref_tree_oid);
+        dump_btree("Volume Snapshot Metadata", apfs, superblock.body.snap_meta_tree_oid);
+        if superblock.body.snap_meta_ext_oid != Oid(0) {
+            let object = apfs.load_object_oid(superblock.body.snap_meta_ext_oid, StorageType::Virtual, Some((btree, volume.xid())))
+                .expect("Failed to load Volume Snapshot extended data");
+            let ext = match *object {
+                APFSObject::SnapMetaExt(ref x) => x.clone(),
                 _ => { panic!("Wrong object type!"); },
             };
             println!("Volume Snapshot extended data: {:#?}", ext);
         }
-
-        let root_object = btree.get_record(&mut apfs, &OmapKey::new(volume.body.root_tree_oid.0, u64::MAX))
-            .expect("I/O error")
-            .expect("Failed to find address for Volume root B-tree");
-        if root_object.value.flags.contains(OvFlags::ENCRYPTED) {
-            println!("Encrypted volume found, skipping...");
-            continue;
-        }
-        let root_btree = apfs.load_btree::<ApfsValue>(Oid(root_object.value.paddr.0 as u64), StorageType::Physical)
-            .expect("Failed to load volume root B-tree");
-        println!("Volume Root B-Tree: {:#?}", &root_btree);
-        dump_apfs_records(&root_btree, &mut apfs, &btree, &root_btree.root.records);
-
-        // let btree_result = apfs.load_btree(volume.body.root_tree_oid, StorageType::Physical);
+        println!("Volume Root B-Tree: {:#?}", volume.root_tree());
+        dump_apfs_records(&volume);
     }
 }
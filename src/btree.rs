@@ -5,47 +5,50 @@ use std::fs::File;
 use std::io::{self, prelude::*};
 use std::io::Cursor;
 use std::marker::PhantomData;
-use std::ops::RangeBounds;
+use std::ops::{Bound, RangeBounds};
 use std::fmt::Debug;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use byteorder::{LittleEndian, ReadBytesExt, BigEndian};
 use num_traits::FromPrimitive;
 
-use crate::{BtreeNodePhys, KVoff, ObjectType, JObjTypes, JDrecHashedKey, JInodeKey, JInodeVal, JDrecVal, JXattrVal, JXattrKey, JFileExtentKey, JFileExtentVal, JDstreamIdKey, JDstreamIdVal, JSiblingKey, JSiblingMapKey, JSiblingMapVal, XfBlob, XFieldDrec, DrecExtType, XFieldInode, InoExtType, JDstream, JDrecKey, JSiblingVal, SpacemanFreeQueueKey, SpacemanFreeQueueVal, BtFlags, BTOFF_INVALID, JDirStatsVal, JDirStatsKey, JPhysExtKey, JSnapMetadataVal, JSnapMetadataKey, JSnapNameKey, JSnapNameVal};
-use crate::internal::{KVloc, Nloc};
+use crate::{ApfsSuperblock, FextTreeKey, FextTreeVal, JObjectIdAndType, BtreeNodePhys, KVoff, ObjectType, JObjTypes, JDrecHashedKey, JInodeKey, JInodeVal, JDrecVal, JXattrVal, JXattrKey, JFileExtentKey, JFileExtentVal, JDstreamIdKey, JDstreamIdVal, JSiblingKey, JSiblingMapKey, JSiblingMapVal, XfBlob, XField, XFieldDrec, DrecExtType, XFieldInode, XF_BLOB_SIZE, XFIELD_SIZE, InoExtType, JDstream, JDrecKey, JSiblingVal, SpacemanFreeQueueKey, SpacemanFreeQueueVal, BtFlags, BTOFF_INVALID, JDirStatsVal, JDirStatsKey, JPhysExtKey, JSnapMetadataVal, JSnapMetadataKey, JSnapNameKey, JSnapNameVal};
+use crate::internal::{KVloc, Nloc, BtnIndexNodeVal, BTREE_NODE_HASH_SIZE_MAX, BTN_INDEX_NODE_VAL_SIZE};
 use crate::internal::Oid;
 use crate::internal::Xid;
 use crate::internal::{BtnFlags, OmapKey};
-use crate::internal::OmapVal;
+use crate::internal::{OmapVal, OmapSnapshot};
 use crate::internal::OvFlags;
 use crate::internal::BtreeInfo;
 use crate::internal::JKey;
 use crate::internal::JPhysExtVal;
 
-use crate::{APFS, APFSObject, BtreeNodeObject, Paddr, StorageType};
+use crate::{APFS, APFSObject, BlockSource, BtreeNodeObject, ObjPhys, Paddr, StorageType};
+use crate::error::{ApfsError, Result};
 
-pub trait Key : PartialOrd + Ord + PartialEq + Eq + Debug + Sized {
-    fn import(source: &mut dyn Read) -> io::Result<Self>;
+pub trait Key : PartialOrd + Ord + PartialEq + Eq + Debug + Clone + Sized {
+    fn import(source: &mut dyn Read) -> Result<Self>;
+
+    /* Compare a search key against a record key, where Equal means the
+       record satisfies the lookup. Exact matching unless overridden. */
+    fn r#match(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
 }
 
 pub trait Value : Debug + Sized {
 }
 
-pub trait LeafValue : Value {
+pub trait LeafValue : Value + Clone {
     type Key: Key;
 
-    fn import(source: &mut dyn Read, key: &Self::Key) -> io::Result<Self>;
+    fn import(source: &mut dyn Read, key: &Self::Key) -> Result<Self>;
 
-    fn ghost_value() -> Self {
-        unimplemented!("This B+ Tree value type doesn't support ghost entries");
+    fn ghost_value() -> Result<Self> {
+        Err(ApfsError::Corrupt("Ghost record in a B-tree whose values don't support them".to_string()))
     }
 }
 
-trait Match {
-    fn r#match(&self, other: &Self) -> Ordering;
-}
-
 impl Ord for OmapKey {
     fn cmp(&self, other: &Self) -> Ordering {
         let order = self.oid.cmp(&other.oid);
@@ -71,15 +74,13 @@ impl PartialEq for OmapKey {
 impl Eq for OmapKey {
 }
 
+/* Object map keys match on the object ID equality and
+   a transaction ID that is less than or equal */
 impl Key for OmapKey {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self::import(source)?)
     }
-}
 
-/* Object map keys match on the object ID equality and
-   a transaction ID that is less than or equal */
-impl Match for OmapKey {
     fn r#match(&self, other: &Self) -> Ordering {
         let order = self.oid.cmp(&other.oid);
         match order {
@@ -97,12 +98,12 @@ impl Value for OmapVal {}
 impl LeafValue for OmapVal {
     type Key = OmapKey;
 
-    fn import(source: &mut dyn Read, _: &Self::Key) -> io::Result<Self> {
+    fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
         Ok(Self::import(source)?)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ApfsSubKey {
     None,
     Name(String),
@@ -111,30 +112,53 @@ pub enum ApfsSubKey {
     SiblingLink(JSiblingKey),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ApfsKey {
     pub key: JKey,
     pub subkey: ApfsSubKey,
 }
 
-/* Object map keys match on the object ID equality and
-   a transaction ID that is less than or equal */
+impl ApfsKey {
+    pub fn new(oid: u64, r#type: JObjTypes, subkey: ApfsSubKey) -> Self {
+        ApfsKey { key: JKey { obj_id_and_type: JObjectIdAndType::new_by_field(r#type, oid) }, subkey }
+    }
+}
+
+impl ApfsSubKey {
+    /* Records of the same object and type collate on their subkey:
+       directory entries by name hash then name, extended attributes
+       and snapshots by name, file extents by logical offset and
+       sibling links by sibling ID */
+    fn collate(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (ApfsSubKey::None, ApfsSubKey::None) => Ordering::Equal,
+            (ApfsSubKey::Name(left), ApfsSubKey::Name(right)) => left.as_bytes().cmp(right.as_bytes()),
+            (ApfsSubKey::DrecHashed(left), ApfsSubKey::DrecHashed(right)) =>
+                left.hash().cmp(&right.hash()).then_with(|| left.name.cmp(&right.name)),
+            (ApfsSubKey::FileExtent(left), ApfsSubKey::FileExtent(right)) => left.logical_addr.cmp(&right.logical_addr),
+            (ApfsSubKey::SiblingLink(left), ApfsSubKey::SiblingLink(right)) => left.sibling_id.cmp(&right.sibling_id),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            ApfsSubKey::None => 0,
+            ApfsSubKey::Name(_) => 1,
+            ApfsSubKey::DrecHashed(_) => 2,
+            ApfsSubKey::FileExtent(_) => 3,
+            ApfsSubKey::SiblingLink(_) => 4,
+        }
+    }
+}
+
+/* File-system keys sort on object ID, then record type and
+   finally the subkey for that record type */
 impl Ord for ApfsKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        let order = self.key.obj_id_and_type.id().cmp(&other.key.obj_id_and_type.id());
-        match order {
-            Ordering::Equal => {
-                let order = (self.key.obj_id_and_type.r#type() as u64).cmp(&(other.key.obj_id_and_type.r#type() as u64));
-                match order {
-                    Ordering::Equal => match (&self.subkey, &other.subkey) {
-                        (ApfsSubKey::Name(ref left), ApfsSubKey::Name(ref right)) => left.cmp(right),
-                        _ => Ordering::Equal,
-                    },
-                    _ => order,
-                }
-            },
-            _ => order,
-        }
+        self.key.obj_id_and_type.id().cmp(&other.key.obj_id_and_type.id())
+            .then_with(|| (self.key.obj_id_and_type.r#type() as u64).cmp(&(other.key.obj_id_and_type.r#type() as u64)))
+            .then_with(|| self.subkey.collate(&other.subkey))
     }
 }
 
@@ -146,22 +170,46 @@ impl PartialOrd for ApfsKey {
 
 impl PartialEq for ApfsKey {
     fn eq(&self, other: &Self) -> bool {
-        // self.cmp(other) == Ordering::Equal
-        true
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for ApfsKey {
 }
 
+/* File extents match the extent starting at or before the logical
+   address and physical extents the one starting at or before the
+   block, leaving the caller to check the length covers it */
 impl Key for ApfsKey {
-    fn import(key_cursor: &mut dyn Read) -> io::Result<Self> {
+    fn r#match(&self, other: &Self) -> Ordering {
+        let key_type = self.key.obj_id_and_type.r#type();
+        if key_type == JObjTypes::Extent && other.key.obj_id_and_type.r#type() == JObjTypes::Extent {
+            return match self.key.obj_id_and_type.id().cmp(&other.key.obj_id_and_type.id()) {
+                Ordering::Less => Ordering::Less,
+                _ => Ordering::Equal,
+            };
+        }
+        match (&self.subkey, &other.subkey) {
+            (ApfsSubKey::FileExtent(left), ApfsSubKey::FileExtent(right)) => {
+                let order = self.key.obj_id_and_type.id().cmp(&other.key.obj_id_and_type.id());
+                match order {
+                    Ordering::Equal => match left.logical_addr.cmp(&right.logical_addr) {
+                        Ordering::Less => Ordering::Less,
+                        _ => Ordering::Equal,
+                    },
+                    _ => order,
+                }
+            },
+            _ => self.cmp(other),
+        }
+    }
+
+    fn import(key_cursor: &mut dyn Read) -> Result<Self> {
         let key = JKey::import(key_cursor)?;
         let key_type = key.obj_id_and_type.r#type();
-        println!("Key type: {:?}", key);
         match key_type {
             JObjTypes::Inode => {
-                println!("Inode key: {:?}", JInodeKey::import(key_cursor)?);
+                JInodeKey::import(key_cursor)?;
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -169,14 +217,13 @@ impl Key for ApfsKey {
             },
             JObjTypes::DirRec => {
                 let subkey = JDrecHashedKey::import(key_cursor)?;
-                println!("DirRec key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::DrecHashed(subkey),
                 });
             },
             JObjTypes::DirStats => {
-                println!("DirStats key: {:?}", JDirStatsKey::import(key_cursor)?);
+                JDirStatsKey::import(key_cursor)?;
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -184,7 +231,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::Xattr => {
                 let subkey = JXattrKey::import(key_cursor)?;
-                println!("Xattr key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::Name(subkey.name),
@@ -192,7 +238,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::FileExtent => {
                 let subkey = JFileExtentKey::import(key_cursor)?;
-                println!("FileExtent key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::FileExtent(subkey),
@@ -200,7 +245,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::DstreamId => {
                 let subkey = JDstreamIdKey::import(key_cursor)?;
-                println!("DstreamId key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -208,7 +252,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::SiblingLink => {
                 let subkey = JSiblingKey::import(key_cursor)?;
-                println!("SiblingLink key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::SiblingLink(subkey),
@@ -216,7 +259,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::SiblingMap => {
                 let subkey = JSiblingMapKey::import(key_cursor)?;
-                println!("SiblingMap key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -224,7 +266,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::Extent => {
                 let subkey = JPhysExtKey::import(key_cursor)?;
-                println!("Extent key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -232,7 +273,6 @@ impl Key for ApfsKey {
             },
             JObjTypes::SnapMetadata => {
                 let subkey = JSnapMetadataKey::import(key_cursor)?;
-                println!("SnapMetadata key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::None,
@@ -240,25 +280,22 @@ impl Key for ApfsKey {
             },
             JObjTypes::SnapName => {
                 let subkey = JSnapNameKey::import(key_cursor)?;
-                println!("SnapName key: {:?}", &subkey);
                 return Ok(ApfsKey {
                     key: key,
                     subkey: ApfsSubKey::Name(subkey.name),
                 });
             },
-            _ => {
-                println!("Unsupported key type: {:?}!", key_type);
-            },
+            _ => {},
         }
-        return Err(io::Error::new(io::ErrorKind::Unsupported, "Unrecognized APFS key type"));
+        Err(ApfsError::UnsupportedRecordType(key_type))
     }
 }
 
 impl Ord for SpacemanFreeQueueKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        let order = self.paddr.0.cmp(&other.paddr.0);
+        let order = self.xid.0.cmp(&other.xid.0);
         match order {
-            Ordering::Equal => self.xid.0.cmp(&other.xid.0),
+            Ordering::Equal => self.paddr.0.cmp(&other.paddr.0),
             _ => order,
         }
     }
@@ -280,11 +317,81 @@ impl Eq for SpacemanFreeQueueKey {
 }
 
 impl Key for SpacemanFreeQueueKey {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self::import(source)?)
     }
 }
 
+impl Ord for FextTreeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let order = self.private_id.cmp(&other.private_id);
+        match order {
+            Ordering::Equal => self.logical_addr.cmp(&other.logical_addr),
+            _ => order,
+        }
+    }
+}
+
+impl PartialOrd for FextTreeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FextTreeKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FextTreeKey {
+}
+
+/* As with file extent records in the file-system tree, a lookup finds
+   the extent starting at or before the offset searched for */
+impl Key for FextTreeKey {
+    fn import(source: &mut dyn Read) -> Result<Self> {
+        Self::import(source)
+    }
+
+    fn r#match(&self, other: &Self) -> Ordering {
+        match self.private_id.cmp(&other.private_id) {
+            Ordering::Equal => match self.logical_addr.cmp(&other.logical_addr) {
+                Ordering::Less => Ordering::Less,
+                _ => Ordering::Equal,
+            },
+            order => order,
+        }
+    }
+}
+
+impl Value for FextTreeVal {}
+
+impl LeafValue for FextTreeVal {
+    type Key = FextTreeKey;
+
+    fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
+        Self::import(source)
+    }
+}
+
+/* Object map snapshots are keyed by the snapshot's transaction */
+impl Key for Xid {
+    fn import(source: &mut dyn Read) -> Result<Self> {
+        Self::import(source)
+    }
+}
+
+impl Value for OmapSnapshot {}
+
+impl LeafValue for OmapSnapshot {
+    type Key = Xid;
+
+    fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
+        Self::import(source)
+    }
+}
+
 pub type SpacemanFreeQueueValue = Option<SpacemanFreeQueueVal>;
 
 impl Value for SpacemanFreeQueueValue {}
@@ -292,12 +399,12 @@ impl Value for SpacemanFreeQueueValue {}
 impl LeafValue for SpacemanFreeQueueValue {
     type Key = SpacemanFreeQueueKey;
 
-    fn import(source: &mut dyn Read, _: &Self::Key) -> io::Result<Self> {
+    fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
         Ok(Some(SpacemanFreeQueueVal::import(source)?))
     }
 
-    fn ghost_value() -> Self {
-        None
+    fn ghost_value() -> Result<Self> {
+        Ok(None)
     }
 }
 
@@ -327,7 +434,7 @@ impl LeafValue for SpacemanFreeQueueValue {
 //}
 //
 //impl Key for JPhysExtKey {
-//    fn import(source: &mut dyn Read) -> io::Result<Self> {
+//    fn import(source: &mut dyn Read) -> Result<Self> {
 //        Ok(Self::import(source)?)
 //    }
 //}
@@ -337,7 +444,7 @@ impl LeafValue for SpacemanFreeQueueValue {
 // impl LeafValue for JPhysExtVal {
 //     type Key = ApfsKey;
 
-//     fn import(source: &mut dyn Read, _: &Self::Key) -> io::Result<Self> {
+//     fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
 //         Ok(JPhysExtVal::import(source)?)
 //     }
 // }
@@ -347,7 +454,7 @@ impl LeafValue for SpacemanFreeQueueValue {
 // impl LeafValue for JSnapMetadataVal {
 //     type Key = ApfsKey;
 
-//     fn import(source: &mut dyn Read, _: &Self::Key) -> io::Result<Self> {
+//     fn import(source: &mut dyn Read, _: &Self::Key) -> Result<Self> {
 //         Ok(JSnapMetadataVal::import(source)?)
 //     }
 // }
@@ -385,7 +492,7 @@ impl<V> Record for LeafRecord<V> where
 
 pub type OmapRecord = LeafRecord<OmapVal>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum InodeXdata {
     SnapXid(Xid),
     DeltaTreeOid(Oid),
@@ -396,24 +503,24 @@ pub enum InodeXdata {
     Dstream(JDstream),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InodeValue {
-    value: JInodeVal,
+    pub value: JInodeVal,
     pub xdata: HashMap<InoExtType, InodeXdata>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DrecXdata {
     SiblingId(u64),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DrecValue {
-    value: JDrecVal,
+    pub value: JDrecVal,
     pub xdata: HashMap<DrecExtType, DrecXdata>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ApfsValue {
     SnapMetadata(JSnapMetadataVal),
     Extent(JPhysExtVal),
@@ -435,127 +542,43 @@ impl Value for ApfsValue {}
 impl LeafValue for ApfsValue {
     type Key = ApfsKey;
 
-    fn import(value_cursor: &mut dyn Read, key: &Self::Key) -> io::Result<Self> {
+    fn import(value_cursor: &mut dyn Read, key: &Self::Key) -> Result<Self> {
         let key_type = key.key.obj_id_and_type.r#type();
-        println!("Key type: {:?}", key);
         Ok(match key_type {
             JObjTypes::Inode => {
                 let value = JInodeVal::import(value_cursor)?;
-                println!("Inode: {:?}", &value);
-                let mut xdata_map = HashMap::new();
-                if value.xfields.len() > 0 {
-                    let mut xfields_cursor = Cursor::new(&value.xfields);
-                    let blob = XfBlob::import(&mut xfields_cursor)?;
-                    let mut xdata_cursor = Cursor::new(blob.data);
-                    let fields = (0..blob.num_exts).map(|_| XFieldInode::import(&mut xdata_cursor).unwrap()).collect::<Vec<XFieldInode>>();
-                    for field in &fields {
-                        let aligned_size = (field.size + 7) & 0xfff8;
-                        assert!(aligned_size & 0x07 == 0, "Unaligned field!");
-                        let mut xdata = vec![0u8; aligned_size as usize];
-                        xdata_cursor.read_exact(&mut xdata);
-                        match field.r#type {
-                            InoExtType::SnapXid => {
-                                assert_eq!(field.size, 8);
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let xvalue = Xid::import(&mut xvalue_cursor).unwrap();
-                                println!("Snapshot Txid: {:?}", xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::SnapXid(xvalue));
-                            },
-                            InoExtType::DeltaTreeOid => {
-                                assert_eq!(field.size, 8);
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let xvalue = Oid::import(&mut xvalue_cursor).unwrap();
-                                println!("Delta Tree OID: {:?}", xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::DeltaTreeOid(xvalue));
-                            },
-                            InoExtType::DocumentId => {
-                                assert_eq!(field.size, 4);
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let xvalue = xvalue_cursor.read_u32::<LittleEndian>().unwrap();
-                                println!("Document ID: {}", xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::DocumentId(xvalue));
-                            },
-                            InoExtType::Name => {
-                                // assert_eq!(field.size, 4);
-                                let xvalue = std::str::from_utf8(&xdata[0..field.size as usize]).unwrap();
-                                // let mut xvalue_cursor = Cursor::new(xdata);
-                                // let xvalue = xvalue_cursor.read_u32::<LittleEndian>().unwrap();
-                                println!("File name: {}", xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::Name(xvalue.to_owned()));
-                            },
-                            InoExtType::PrevFsize => {
-                                assert_eq!(field.size, 8);
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let xvalue = xvalue_cursor.read_u64::<LittleEndian>().unwrap();
-                                println!("Previous file size: {}", xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::PrevFsize(xvalue));
-                            },
-                            InoExtType::FinderInfo => {
-                                assert_eq!(field.size, 32);
-                                // let mut xvalue_cursor = Cursor::new(xdata);
-                                // let xvalue = Oid::import(&mut xvalue_cursor).unwrap();
-                                println!("FinderInfo: {:?}", xdata);
-                                xdata_map.insert(field.r#type, InodeXdata::FinderInfo(xdata[0..32].try_into().unwrap()));
-                            },
-                            InoExtType::Dstream => {
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let xvalue = JDstream::import(&mut xvalue_cursor).unwrap();
-                                println!("Dstream: {:?}", &xvalue);
-                                xdata_map.insert(field.r#type, InodeXdata::Dstream(xvalue));
-                            },
-                            _ => {},
-                        }
-                    }
-                    println!("Fields: {:?}", &fields);
-                }
+                let xdata = if value.xfields.len() > 0 {
+                    import_inode_xfields(&value.xfields)?
+                } else {
+                    HashMap::new()
+                };
                 ApfsValue::Inode(InodeValue {
                     value,
-                    xdata: xdata_map,
+                    xdata,
                 })
             },
             JObjTypes::DirRec => {
                 let value = JDrecVal::import(value_cursor)?;
-                println!("DirRec: {:?}", &value);
-                let mut xdata_map = HashMap::new();
-                if value.xfields.len() > 0 {
-                    let mut xfields_cursor = Cursor::new(&value.xfields);
-                    let blob = XfBlob::import(&mut xfields_cursor)?;
-                    let mut xdata_cursor = Cursor::new(blob.data);
-                    let fields = (0..blob.num_exts).map(|_| XFieldDrec::import(&mut xdata_cursor).unwrap()).collect::<Vec<XFieldDrec>>();
-                    for field in &fields {
-                        assert!(field.size & 0x07 == 0, "Unaligned field!");
-                        let mut xdata = vec![0u8; field.size as usize];
-                        xdata_cursor.read_exact(&mut xdata);
-                        match field.r#type {
-                            DrecExtType::DrecExtTypeSiblingId => {
-                                assert_eq!(field.size, 8);
-                                let mut xvalue_cursor = Cursor::new(xdata);
-                                let sibling_id = xvalue_cursor.read_u64::<LittleEndian>().unwrap();
-                                println!("Sibling ID: {}", sibling_id);
-                                xdata_map.insert(field.r#type, DrecXdata::SiblingId(sibling_id));
-                            }
-                        }
-                    }
-                    println!("Fields: {:?}", &fields);
-                }
+                let xdata = if value.xfields.len() > 0 {
+                    import_drec_xfields(&value.xfields)?
+                } else {
+                    HashMap::new()
+                };
                 ApfsValue::DirRec(DrecValue {
                     value,
-                    xdata: xdata_map,
+                    xdata,
                 })
             },
             JObjTypes::DirStats => {
-                let value = JDirStatsVal::import(value_cursor).unwrap();
-                println!("DirStats: {:?}", &value);
+                let value = JDirStatsVal::import(value_cursor)?;
                 ApfsValue::DirStats(value)
             },
             JObjTypes::Xattr => {
-                let value = JXattrVal::import(value_cursor).unwrap();
-                println!("Xattr: {:?}", &value);
+                let value = JXattrVal::import(value_cursor)?;
                 ApfsValue::Xattr(value)
             },
             JObjTypes::FileExtent => {
                 let value = JFileExtentVal::import(value_cursor)?;
-                println!("FileExtent: {:?}", &value);
                 // let length = sizes[&key.obj_id_and_type.id()] as usize;
                 // // let length = 12;
                 // println!("Reading block: {} ({} bytes)", value.phys_block_num, length);
@@ -566,49 +589,142 @@ impl LeafValue for ApfsValue {
             },
             JObjTypes::DstreamId => {
                 let value = JDstreamIdVal::import(value_cursor)?;
-                println!("DstreamId: {:?}", &value);
                 ApfsValue::DstreamId(value)
             },
             JObjTypes::SiblingLink => {
                 let value = JSiblingVal::import(value_cursor)?;
-                println!("SiblingLink: {:?}", &value);
                 ApfsValue::SiblingLink(value)
             },
             JObjTypes::SiblingMap => {
                 let value = JSiblingMapVal::import(value_cursor)?;
-                println!("SiblingMap: {:?}", &value);
                 ApfsValue::SiblingMap(value)
             },
             JObjTypes::Extent => {
                 let value = JPhysExtVal::import(value_cursor)?;
-                println!("Extent: {:?}", &value);
                 ApfsValue::Extent(value)
             },
             JObjTypes::SnapMetadata => {
                 let value = JSnapMetadataVal::import(value_cursor)?;
-                println!("SnapMetadata: {:?}", &value);
                 ApfsValue::SnapMetadata(value)
             },
             JObjTypes::SnapName => {
                 let value = JSnapNameVal::import(value_cursor)?;
-                println!("SnapName: {:?}", &value);
                 ApfsValue::SnapName(value)
             },
             _ => {
-                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unrecognized APFS value type: {:?}", key_type)));
+                return Err(ApfsError::UnsupportedRecordType(key_type));
             },
         })
     }
 }
 
+/* Extended fields are a table of (type, flags, size) headers
+   followed by the data of each field padded to eight bytes */
+fn import_xfields<T: FromPrimitive>(xfields: &[u8]) -> Result<Vec<(XField<T>, &[u8])>> {
+    let mut cursor = Cursor::new(xfields);
+    let blob = XfBlob::import(&mut cursor)?;
+    let data = &xfields[XF_BLOB_SIZE..];
+    let mut headers = data;
+    let fields = (0..blob.num_exts).map(|_| XField::<T>::import(&mut headers)).collect::<Result<Vec<_>>>()?;
+    let mut offset = XFIELD_SIZE * fields.len();
+    let mut values = vec![];
+    for field in fields {
+        let size = field.size as usize;
+        let xdata = data.get(offset..offset + size).ok_or(ApfsError::Truncated)?;
+        offset += (size + 7) & !7;
+        values.push((field, xdata));
+    }
+    Ok(values)
+}
+
+fn check_xfield_size(name: &str, xdata: &[u8], size: usize) -> Result<()> {
+    if xdata.len() != size {
+        return Err(ApfsError::Corrupt(format!("Extended field {} has size {}, expected {}", name, xdata.len(), size)));
+    }
+    Ok(())
+}
+
+pub fn import_inode_xfields(xfields: &[u8]) -> Result<HashMap<InoExtType, InodeXdata>> {
+    let mut xdata_map = HashMap::new();
+    for (field, xdata) in import_xfields::<InoExtType>(xfields)? {
+        let mut xvalue_cursor = Cursor::new(xdata);
+        match field.r#type {
+            InoExtType::SnapXid => {
+                check_xfield_size("snapshot transaction", xdata, 8)?;
+                let xvalue = Xid::import(&mut xvalue_cursor)?;
+                xdata_map.insert(field.r#type, InodeXdata::SnapXid(xvalue));
+            },
+            InoExtType::DeltaTreeOid => {
+                check_xfield_size("delta tree", xdata, 8)?;
+                let xvalue = Oid::import(&mut xvalue_cursor)?;
+                xdata_map.insert(field.r#type, InodeXdata::DeltaTreeOid(xvalue));
+            },
+            InoExtType::DocumentId => {
+                check_xfield_size("document ID", xdata, 4)?;
+                let xvalue = xvalue_cursor.read_u32::<LittleEndian>()?;
+                xdata_map.insert(field.r#type, InodeXdata::DocumentId(xvalue));
+            },
+            InoExtType::Name => {
+                let xvalue = std::str::from_utf8(xdata).map_err(|_| ApfsError::InvalidString)?;
+                xdata_map.insert(field.r#type, InodeXdata::Name(xvalue.to_owned()));
+            },
+            InoExtType::PrevFsize => {
+                check_xfield_size("previous file size", xdata, 8)?;
+                let xvalue = xvalue_cursor.read_u64::<LittleEndian>()?;
+                xdata_map.insert(field.r#type, InodeXdata::PrevFsize(xvalue));
+            },
+            InoExtType::FinderInfo => {
+                check_xfield_size("Finder info", xdata, 32)?;
+                let mut xvalue = [0u8; 32];
+                xvalue.copy_from_slice(xdata);
+                xdata_map.insert(field.r#type, InodeXdata::FinderInfo(xvalue));
+            },
+            InoExtType::Dstream => {
+                let xvalue = JDstream::import(&mut xvalue_cursor)?;
+                xdata_map.insert(field.r#type, InodeXdata::Dstream(xvalue));
+            },
+            _ => {},
+        }
+    }
+    Ok(xdata_map)
+}
+
+pub fn import_drec_xfields(xfields: &[u8]) -> Result<HashMap<DrecExtType, DrecXdata>> {
+    let mut xdata_map = HashMap::new();
+    for (field, xdata) in import_xfields::<DrecExtType>(xfields)? {
+        match field.r#type {
+            DrecExtType::DrecExtTypeSiblingId => {
+                check_xfield_size("sibling ID", xdata, 8)?;
+                let sibling_id = Cursor::new(xdata).read_u64::<LittleEndian>()?;
+                xdata_map.insert(field.r#type, DrecXdata::SiblingId(sibling_id));
+            },
+        }
+    }
+    Ok(xdata_map)
+}
+
 #[derive(Debug, Clone)]
 pub struct OidValue {
     pub oid: Oid,
+    /* Hash of the child node, only present in hashed trees */
+    pub hash: Option<[u8; BTREE_NODE_HASH_SIZE_MAX]>,
 }
 
 impl OidValue {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
-        Ok(OidValue { oid: Oid::import(source)? })
+    fn size(hashed: bool) -> usize {
+        if hashed { BTN_INDEX_NODE_VAL_SIZE } else { 8 }
+    }
+
+    fn parse(value: &[u8], hashed: bool) -> Result<Self> {
+        if value.len() != Self::size(hashed) {
+            return Err(ApfsError::Corrupt(format!("B-tree index value of {} bytes, expected {}", value.len(), Self::size(hashed))));
+        }
+        Ok(if hashed {
+            let value = BtnIndexNodeVal::import(&mut &value[..])?;
+            OidValue { oid: value.child_oid, hash: Some(value.child_hash) }
+        } else {
+            OidValue { oid: Oid::import(&mut &value[..])?, hash: None }
+        })
     }
 }
 
@@ -620,6 +736,11 @@ pub struct NonLeafRecord<K: Key> {
     pub value: OidValue,
 }
 
+mod validate;
+mod view;
+pub use validate::{BtreeIssue, NodeArea};
+pub use view::NodeView;
+
 #[cfg(test)]
 mod test;
 
@@ -654,92 +775,160 @@ pub enum AnyRecords<V: LeafValue> {
 #[derive(Debug)]
 pub struct Btree<V: LeafValue> {
     info: BtreeInfo,
-    pub root: Rc<BtreeNode<V>>,
+    pub root: Arc<BtreeNode<V>>,
+    object_map: Option<(Arc<Btree<OmapVal>>, Xid)>,
     _v: PhantomData<V>,
 }
 
 #[derive(Debug)]
 pub struct BtreeNode<V: LeafValue> {
-    node: BtreeNodeObject,
+    header: ObjPhys,
+    layout: NodeLayout,
     pub records: AnyRecords<V>,
     _v: PhantomData<V>,
 }
 
-impl BtreeNode<OmapVal> {
-    fn get_record<'a>(&'a self, key: &<OmapVal as LeafValue>::Key) -> Option<AnyRecord<OmapVal>> {
+/* Where the areas of a node lie, kept so the node can be checked
+   after its records are decoded without holding on to its block */
+#[derive(Debug)]
+struct NodeLayout {
+    flags: BtnFlags,
+    level: u16,
+    table_space: Nloc,
+    free_space: Nloc,
+    /* End of the value area, which stops short of the info of a root */
+    data_len: usize,
+    toc: Vec<KVloc>,
+}
+
+impl<V: LeafValue> BtreeNode<V> {
+    /* Records are sorted, so the candidate is the last one whose key
+       does not match above the one searched for */
+    fn get_record(&self, key: &V::Key) -> Option<AnyRecord<V>> {
         match self.records {
             AnyRecords::Leaf(ref x) => {
-                x.into_iter().rev().filter(|y| key.r#match(&y.key) == Ordering::Equal).nth(0).map(|y| AnyRecord::Leaf(y.clone()))
+                let idx = x.partition_point(|y| key.r#match(&y.key) != Ordering::Less);
+                x[..idx].last().filter(|y| key.r#match(&y.key) == Ordering::Equal).map(|y| AnyRecord::Leaf(y.clone()))
             },
             AnyRecords::NonLeaf(ref x, _) => {
-                x.into_iter().rev().filter(|y| key.r#match(&y.key) != Ordering::Less).nth(0).map(|y| AnyRecord::NonLeaf(y.clone(), PhantomData))
+                let idx = x.partition_point(|y| key.r#match(&y.key) != Ordering::Less);
+                x[..idx].last().map(|y| AnyRecord::NonLeaf(y.clone(), PhantomData))
             },
         }
     }
 }
 
-enum BtreeRawObject {
-    BtreeRoot(BtreeNodeObject, BtreeInfo),
-    BtreeNonRoot(BtreeNodeObject),
+const BTREE_INFO_SIZE: usize = 40;
+
+fn node_slice(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    start.checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| ApfsError::Corrupt(format!("B-tree node range {}+{} exceeds node size {}", start, len, data.len())))
 }
 
-impl BtreeRawObject {
-    fn load_btree_object<S: Read + Seek>(apfs: &mut APFS<S>, oid: Oid, r#type: StorageType) -> io::Result<BtreeRawObject> {
-        let object = apfs.load_object_oid(oid, r#type)?;
-        let body = match object {
-            APFSObject::Btree(mut body) => {
-                let info = BtreeInfo::import(&mut Cursor::new(&body.body.data[body.body.data.len()-40..]))?;
-                body.body.data.truncate(body.body.data.len()-40);
-                BtreeRawObject::BtreeRoot(body, info)
+/* Key and value locations of every record, expanding the short form
+   used by fixed-size nodes */
+fn read_toc(body: &BtreeNodePhys, info: &BtreeInfo) -> Result<Vec<KVloc>> {
+    let toc = node_slice(&body.data, body.table_space.off as usize, body.table_space.len as usize)?;
+    let mut cursor = Cursor::new(toc);
+    let mut items = vec![];
+    for _ in 0..body.nkeys {
+        let kvloc = if body.flags.contains(BtnFlags::FIXED_KV_SIZE) {
+            let kvoff = KVoff::import(&mut cursor)?;
+            KVloc {
+                k: Nloc {
+                    off: kvoff.k,
+                    len: info.fixed.key_size as u16,
+                },
+                v: Nloc {
+                    off: kvoff.v,
+                    len: if body.flags.contains(BtnFlags::LEAF) {
+                        info.fixed.val_size as u16
+                    } else {
+                        OidValue::size(info.fixed.flags.contains(BtFlags::HASHED)) as u16
+                    },
+                },
+            }
+        } else {
+            KVloc::import(&mut cursor)?
+        };
+        items.push(kvloc);
+    }
+    Ok(items)
+}
+
+/* A node shared with the object cache, decoded from in place */
+enum BtreeRawObject<'a> {
+    BtreeRoot(Arc<APFSObject<'a>>, BtreeInfo),
+    BtreeNonRoot(Arc<APFSObject<'a>>),
+}
+
+impl<'a> BtreeRawObject<'a> {
+    fn load_btree_object<S: BlockSource>(apfs: &'a APFS<S>, oid: Oid, r#type: StorageType) -> Result<BtreeRawObject<'a>> {
+        Self::from_object(apfs.load_object_oid(oid, r#type, None)?, oid)
+    }
+
+    fn from_object(object: Arc<APFSObject<'a>>, oid: Oid) -> Result<BtreeRawObject<'a>> {
+        let body = match *object {
+            APFSObject::Btree(ref body) => {
+                let info_offset = body.body.data.len().checked_sub(BTREE_INFO_SIZE)
+                    .ok_or_else(|| ApfsError::Corrupt(format!("B-tree root {} too small for its info", oid.0)))?;
+                let info = BtreeInfo::import(&mut Cursor::new(&body.body.data[info_offset..]))?;
+                BtreeRawObject::BtreeRoot(Arc::clone(&object), info)
+            },
+            APFSObject::BtreeNode(_) => BtreeRawObject::BtreeNonRoot(Arc::clone(&object)),
+            ref other => {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Btree, found: other.object_type() });
             },
-            APFSObject::BtreeNode(body) => BtreeRawObject::BtreeNonRoot(body),
-            _ => { panic!("Invalid type"); },
         };
         Ok(body)
     }
+
+    fn node(&self) -> &BtreeNodeObject<'a> {
+        let object = match *self {
+            BtreeRawObject::BtreeRoot(ref object, _) | BtreeRawObject::BtreeNonRoot(ref object) => object,
+        };
+        match **object {
+            APFSObject::Btree(ref body) | APFSObject::BtreeNode(ref body) => body,
+            _ => unreachable!(),
+        }
+    }
+
+    /* Node data up to the end of the value area */
+    fn data(&self) -> &[u8] {
+        let data = &self.node().body.data;
+        match *self {
+            BtreeRawObject::BtreeRoot(..) => &data[..data.len() - BTREE_INFO_SIZE],
+            BtreeRawObject::BtreeNonRoot(_) => data,
+        }
+    }
 }
 
 impl<V> Btree<V> where
     V: LeafValue {
-    fn decode_btree_node(body: BtreeNodeObject, info: &BtreeInfo) -> io::Result<BtreeNode<V>> {
+    fn decode_btree_node(object: &BtreeRawObject, info: &BtreeInfo) -> Result<BtreeNode<V>> {
+        let body = object.node();
         if body.header.subtype.r#type() != ObjectType::Omap &&
            body.header.subtype.r#type() != ObjectType::Fstree &&
            body.header.subtype.r#type() != ObjectType::SpacemanFreeQueue &&
            body.header.subtype.r#type() != ObjectType::Blockreftree &&
-           body.header.subtype.r#type() != ObjectType::Snapmetatree {
-            return Err(io::Error::new(io::ErrorKind::Unsupported, "Unsupported B-tree type"));
+           body.header.subtype.r#type() != ObjectType::Snapmetatree &&
+           body.header.subtype.r#type() != ObjectType::FextTree &&
+           body.header.subtype.r#type() != ObjectType::OmapSnapshot {
+            return Err(ApfsError::UnsupportedBtreeType(body.header.subtype.r#type()));
         }
-        let toc = &body.body.data[body.body.table_space.off as usize..(body.body.table_space.off+body.body.table_space.len) as usize];
-        let mut cursor = Cursor::new(toc);
-        let mut items = vec![];
+        let data = object.data();
+        let key_area = body.body.table_space.off as usize + body.body.table_space.len as usize;
+        let toc = read_toc(&body.body, info)?;
         let mut records = vec![];
         let mut nrecords = vec![];
-        for _ in 0..body.body.nkeys {
-            let kvloc = if(body.body.flags.contains(BtnFlags::FIXED_KV_SIZE)) {
-                let kvoff = KVoff::import(&mut cursor)?;
-                KVloc {
-                    k: Nloc {
-                        off: kvoff.k,
-                        len: info.fixed.key_size as u16,
-                    },
-                    v: Nloc {
-                        off: kvoff.v,
-                        len: if body.body.flags.contains(BtnFlags::LEAF) {
-                            // info.fixed.key_size as u16
-                            info.fixed.val_size as u16
-                        } else {
-                            8  // Length of OidValue
-                        },
-                    },
-                }
-            } else {
-                KVloc::import(&mut cursor)?
-            };
-            items.push(kvloc);
-            let key_data = &body.body.data[(body.body.table_space.off+body.body.table_space.len+kvloc.k.off) as usize..(body.body.table_space.off+body.body.table_space.len+kvloc.k.off + kvloc.k.len) as usize];
+        for kvloc in toc.iter() {
+            let key_data = node_slice(data, key_area + kvloc.k.off as usize, kvloc.k.len as usize)?;
             let val_data = if !info.fixed.flags.contains(BtFlags::ALLOW_GHOSTS) ||
                     kvloc.v.off != BTOFF_INVALID {
-                &body.body.data[(body.body.data.len() as u16 - kvloc.v.off) as usize..(body.body.data.len() as u16 -  kvloc.v.off + kvloc.v.len) as usize]
+                let start = data.len().checked_sub(kvloc.v.off as usize)
+                    .ok_or_else(|| ApfsError::Corrupt(format!("B-tree value offset {} exceeds node size {}", kvloc.v.off, data.len())))?;
+                node_slice(data, start, kvloc.v.len as usize)?
             } else {
                 &[]
             };
@@ -748,7 +937,7 @@ impl<V> Btree<V> where
             let key = V::Key::import(&mut key_cursor)?;
             if body.body.flags.contains(BtnFlags::LEAF) {
                 let value = if val_data.len() == 0 {
-                    V::ghost_value()
+                    V::ghost_value()?
                 } else {
                     V::import(&mut value_cursor, &key)?
                 };
@@ -760,57 +949,249 @@ impl<V> Btree<V> where
             } else {
                 nrecords.push(NonLeafRecord {
                     key,
-                    value: OidValue::import(&mut value_cursor)?,
+                    value: OidValue::parse(val_data, info.fixed.flags.contains(BtFlags::HASHED))?,
                 });
             }
         }
+        let layout = NodeLayout {
+            flags: body.body.flags,
+            level: body.body.level,
+            table_space: body.body.table_space,
+            free_space: body.body.free_space,
+            data_len: data.len(),
+            toc,
+        };
         let node = if body.body.flags.contains(BtnFlags::LEAF) {
             BtreeNode {
-                node: body, records: AnyRecords::Leaf(records), _v: PhantomData
+                header: body.header.clone(), layout, records: AnyRecords::Leaf(records), _v: PhantomData
             }
         } else {
             BtreeNode {
-                node: body, records: AnyRecords::NonLeaf(nrecords, PhantomData), _v: PhantomData
+                header: body.header.clone(), layout, records: AnyRecords::NonLeaf(nrecords, PhantomData), _v: PhantomData
             }
         };
         Ok(node)
     }
 
-    pub fn load_btree_node<S: Read + Seek>(&self, apfs: &mut APFS<S>, oid: Oid, r#type: StorageType) -> io::Result<BtreeNode<V>> {
-        let body = match BtreeRawObject::load_btree_object(apfs, oid, r#type)? {
-            BtreeRawObject::BtreeNonRoot(body) => body,
-            _ => { return Err(io::Error::new(io::ErrorKind::InvalidData, "Root node as a descendent in tree")); },
-        };
-        let node = Self::decode_btree_node(body, &self.info)?;
+    pub fn load_btree_node<S: BlockSource>(&self, apfs: &APFS<S>, oid: Oid, r#type: StorageType) -> Result<BtreeNode<V>> {
+        self.decode_child_node(BtreeRawObject::load_btree_object(apfs, oid, r#type)?, oid)
+    }
+
+    fn decode_child_node(&self, object: BtreeRawObject, oid: Oid) -> Result<BtreeNode<V>> {
+        if let BtreeRawObject::BtreeRoot(..) = object {
+            return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::BtreeNode, found: object.node().header.r#type.r#type() });
+        }
+        let node = Self::decode_btree_node(&object, &self.info)?;
         Ok(node)
     }
 
-    pub fn load_btree<S: Read + Seek>(apfs: &mut APFS<S>, oid: Oid, r#type: StorageType) -> io::Result<Btree<V>> {
-        let (body, info) = match BtreeRawObject::load_btree_object(apfs, oid, r#type)? {
-            BtreeRawObject::BtreeRoot(body, info) => (body, info),
-            _ => { return Err(io::Error::new(io::ErrorKind::InvalidData, "Non-root node at top of tree")); },
+    pub fn load_btree<S: BlockSource>(apfs: &APFS<S>, oid: Oid, r#type: StorageType) -> Result<Btree<V>> {
+        let object = BtreeRawObject::load_btree_object(apfs, oid, r#type)?;
+        let info = match object {
+            BtreeRawObject::BtreeRoot(_, ref info) => info.clone(),
+            BtreeRawObject::BtreeNonRoot(_) => {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Btree, found: object.node().header.r#type.r#type() });
+            },
+        };
+        let root = Self::decode_btree_node(&object, &info)?;
+        Ok(Btree { info, root: Arc::new(root), object_map: None, _v: PhantomData })
+    }
+
+    /* Load a tree whose root is a virtual object, such as a volume's
+       file-system tree, and resolve virtual child nodes through the
+       same object map as of the given transaction */
+    pub fn load_btree_virtual<S: BlockSource>(apfs: &APFS<S>, omap: Arc<Btree<OmapVal>>, oid: Oid, xid: Xid) -> Result<Btree<V>> {
+        let object = apfs.load_object_virtual(&omap, oid, xid)?;
+        let object = BtreeRawObject::from_object(object, oid)?;
+        let info = match object {
+            BtreeRawObject::BtreeRoot(_, ref info) => info.clone(),
+            BtreeRawObject::BtreeNonRoot(_) => {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Btree, found: object.node().header.r#type.r#type() });
+            },
         };
-        let root = Self::decode_btree_node(body, &info)?;
-        Ok(Btree { info, root: Rc::new(root), _v: PhantomData })
+        let root = Self::decode_btree_node(&object, &info)?;
+        Ok(Btree { info, root: Arc::new(root), object_map: Some((omap, xid)), _v: PhantomData })
+    }
+
+    pub fn info(&self) -> &BtreeInfo {
+        &self.info
+    }
+
+    pub fn object_map(&self) -> Option<(&Btree<OmapVal>, Xid)> {
+        self.object_map.as_ref().map(|(omap, xid)| (&**omap, *xid))
     }
 }
 
-impl Btree<OmapVal> {
-    pub fn get_record_node<S: Read + Seek>(&self, apfs: &mut APFS<S>, node: &BtreeNode<OmapVal>, key: &OmapKey) -> io::Result<Option<OmapRecord>> {
-        Ok(match node.get_record(key) {
-            Some(any) => match any {
-                AnyRecord::Leaf(body) => Some(body),
-                AnyRecord::NonLeaf(body, _) => {
-                    let subnode = self.load_btree_node(apfs, body.value.oid, StorageType::Physical)?;
-                    self.get_record_node(apfs, &subnode, key)?
-                },
+impl<V: LeafValue> Btree<V> {
+    /* Child OIDs are physical unless the tree is bound to an object
+       map and its info does not carry the physical flag */
+    pub fn load_child_node<S: BlockSource>(&self, apfs: &APFS<S>, oid: Oid) -> Result<BtreeNode<V>> {
+        self.decode_child_node(self.load_child_object(apfs, oid)?, oid)
+    }
+
+    fn load_child_object<'a, S: BlockSource>(&self, apfs: &'a APFS<S>, oid: Oid) -> Result<BtreeRawObject<'a>> {
+        match self.object_map {
+            Some((ref omap, xid)) if !self.info.fixed.flags.contains(BtFlags::PHYSICAL) => {
+                BtreeRawObject::from_object(apfs.load_object_virtual(omap, oid, xid)?, oid)
             },
-            None => None,
-        })
+            _ => {
+                let paddr = Paddr(oid.0 as i64);
+                let object = apfs.load_object_addr(paddr)?;
+                let found = object.header().oid;
+                if found != oid {
+                    return Err(ApfsError::OidMismatch { paddr, expected: oid, found });
+                }
+                BtreeRawObject::from_object(object, oid)
+            },
+        }
+    }
+
+    pub fn iter<'a, S: BlockSource>(&'a self, apfs: &'a APFS<S>) -> BtreeIter<'a, S, V> {
+        self.range(apfs, ..)
+    }
+
+    /* Leaf records in key order whose keys fall within the range,
+       loading child nodes only once the walk reaches them */
+    pub fn range<'a, S: BlockSource, R: RangeBounds<V::Key>>(&'a self, apfs: &'a APFS<S>, range: R) -> BtreeIter<'a, S, V> {
+        BtreeIter {
+            btree: self,
+            apfs,
+            stack: vec![],
+            start: Some(range.start_bound().cloned()),
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+pub struct BtreeIter<'a, S: BlockSource, V: LeafValue> {
+    btree: &'a Btree<V>,
+    apfs: &'a APFS<S>,
+    stack: Vec<(Arc<BtreeNode<V>>, usize)>,
+    start: Option<Bound<V::Key>>,
+    end: Bound<V::Key>,
+}
+
+impl<'a, S: BlockSource, V: LeafValue> BtreeIter<'a, S, V> {
+    fn before_end(&self, key: &V::Key) -> bool {
+        match self.end {
+            Bound::Included(ref end) => key <= end,
+            Bound::Excluded(ref end) => key < end,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /* Descend from the root to the first leaf record not below the
+       start bound, keeping the path so the walk can continue */
+    fn seek(&mut self, start: Bound<V::Key>) -> Result<()> {
+        let mut node = self.btree.root.clone();
+        loop {
+            let child = match node.records {
+                AnyRecords::NonLeaf(ref records, _) => {
+                    let idx = match start {
+                        Bound::Included(ref key) => records.iter().rposition(|record| record.key < *key),
+                        Bound::Excluded(ref key) => records.iter().rposition(|record| record.key <= *key),
+                        Bound::Unbounded => None,
+                    }.unwrap_or(0);
+                    records.get(idx).map(|record| (idx, record.value.oid))
+                },
+                AnyRecords::Leaf(ref records) => {
+                    let idx = match start {
+                        Bound::Included(ref key) => records.iter().position(|record| record.key >= *key),
+                        Bound::Excluded(ref key) => records.iter().position(|record| record.key > *key),
+                        Bound::Unbounded => Some(0),
+                    };
+                    self.stack.push((node.clone(), idx.unwrap_or(records.len())));
+                    return Ok(());
+                },
+            };
+            match child {
+                Some((idx, oid)) => {
+                    let child = self.load_child(&node, oid)?;
+                    self.stack.push((node, idx));
+                    node = child;
+                },
+                None => {
+                    self.stack.push((node, 0));
+                    return Ok(());
+                },
+            }
+        }
+    }
+
+    fn descend_first(&mut self, mut node: Arc<BtreeNode<V>>) -> Result<()> {
+        loop {
+            let oid = match node.records {
+                AnyRecords::NonLeaf(ref records, _) => records.first().map(|record| record.value.oid),
+                AnyRecords::Leaf(_) => None,
+            };
+            let child = match oid {
+                Some(oid) => self.load_child(&node, oid)?,
+                None => {
+                    self.stack.push((node, 0));
+                    return Ok(());
+                },
+            };
+            self.stack.push((node, 0));
+            node = child;
+        }
+    }
+
+    /* Each child must sit exactly one level below its parent, so a
+       node that points back up the tree cannot make the walk loop */
+    fn load_child(&self, parent: &BtreeNode<V>, oid: Oid) -> Result<Arc<BtreeNode<V>>> {
+        let child = self.btree.load_child_node(self.apfs, oid)?;
+        if parent.layout.level.checked_sub(1) != Some(child.layout.level) {
+            return Err(ApfsError::Corrupt(format!("B-tree node {} at level {} below level {}", oid.0, child.layout.level, parent.layout.level)));
+        }
+        Ok(Arc::new(child))
+    }
+
+    fn advance(&mut self) -> Result<Option<LeafRecord<V>>> {
+        if let Some(start) = self.start.take() {
+            self.seek(start)?;
+        }
+        while let Some((node, idx)) = self.stack.last_mut() {
+            let node = node.clone();
+            match node.records {
+                AnyRecords::Leaf(ref records) => {
+                    if let Some(record) = records.get(*idx) {
+                        *idx += 1;
+                        if !self.before_end(&record.key) {
+                            self.stack.clear();
+                            return Ok(None);
+                        }
+                        return Ok(Some(record.clone()));
+                    }
+                    self.stack.pop();
+                },
+                AnyRecords::NonLeaf(ref records, _) => {
+                    *idx += 1;
+                    match records.get(*idx) {
+                        Some(record) if self.before_end(&record.key) => {
+                            let child = self.load_child(&node, record.value.oid)?;
+                            self.descend_first(child)?;
+                        },
+                        Some(_) => self.stack.clear(),
+                        None => { self.stack.pop(); },
+                    }
+                },
+            }
+        }
+        Ok(None)
     }
+}
 
-    pub fn get_record<S: Read + Seek>(&self, apfs: &mut APFS<S>, key: &OmapKey) -> io::Result<Option<OmapRecord>> {
-        self.get_record_node(apfs, &self.root, key)
+impl<'a, S: BlockSource, V: LeafValue> Iterator for BtreeIter<'a, S, V> {
+    type Item = Result<LeafRecord<V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(record) => record.map(Ok),
+            Err(err) => {
+                self.stack.clear();
+                Some(Err(err))
+            },
+        }
     }
 }
 
@@ -823,19 +1204,16 @@ pub enum BtreeTypes {
     ExtentRef(Btree<ApfsValue>),
     // SnapMetadata(Btree<JSnapMetadataVal>),
     SnapMetadata(Btree<ApfsValue>),
+    Fext(Btree<FextTreeVal>),
+    OmapSnapshot(Btree<OmapSnapshot>),
 }
 
-pub fn load_btree_generic<S: Read + Seek>(apfs: &mut APFS<S>, oid: Oid, r#type: StorageType) -> io::Result<BtreeTypes> {
-    let object = apfs.load_object_oid(oid, r#type)?;
-    let body = match object {
-        APFSObject::Btree(mut body) => body,
-        APFSObject::BtreeNode(mut body) => {
-            return Err(io::Error::new(io::ErrorKind::InvalidData,
-                 "Non-root node at top of tree"));
-        },
-        _ => {
-            return Err(io::Error::new(io::ErrorKind::InvalidData,
-                 "Object is not a B-Tree"));
+pub fn load_btree_generic<S: BlockSource>(apfs: &APFS<S>, oid: Oid, r#type: StorageType) -> Result<BtreeTypes> {
+    let object = apfs.load_object_oid(oid, r#type, None)?;
+    let body = match *object {
+        APFSObject::Btree(ref body) => body,
+        ref other => {
+            return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Btree, found: other.object_type() });
         },
     };
     Ok(match body.header.subtype.r#type() {
@@ -844,9 +1222,72 @@ pub fn load_btree_generic<S: Read + Seek>(apfs: &mut APFS<S>, oid: Oid, r#type:
         ObjectType::SpacemanFreeQueue => BtreeTypes::SpacemanFreeQueue(Btree::load_btree(apfs, oid, r#type)?),
         ObjectType::Blockreftree => BtreeTypes::ExtentRef(Btree::load_btree(apfs, oid, r#type)?),
         ObjectType::Snapmetatree => BtreeTypes::SnapMetadata(Btree::load_btree(apfs, oid, r#type)?),
-        _ => {
-            return Err(io::Error::new(io::ErrorKind::Unsupported,
-                 format!("B-Tree type not supported: {:?}", body.header.subtype.r#type())));
+        ObjectType::FextTree => BtreeTypes::Fext(Btree::load_btree(apfs, oid, r#type)?),
+        ObjectType::OmapSnapshot => BtreeTypes::OmapSnapshot(Btree::load_btree(apfs, oid, r#type)?),
+        subtype => {
+            return Err(ApfsError::UnsupportedBtreeType(subtype));
         },
     })
 }
+
+/* A contiguous run of a file's data on disk */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extent {
+    pub logical_addr: u64,
+    pub length: u64,
+    pub phys_block_num: u64,
+    pub crypto_id: u64,
+}
+
+/* The tree holding a volume's file extents */
+#[derive(Debug)]
+pub enum ExtentTree {
+    FsTree(Arc<Btree<ApfsValue>>),
+    Fext(Btree<FextTreeVal>),
+}
+
+impl ExtentTree {
+    /* Sealed volumes use their fext tree, any other volume keeps file
+       extent records in its file-system tree */
+    pub fn for_volume<S: BlockSource>(apfs: &APFS<S>, volume: &ApfsSuperblock, fs_tree: Arc<Btree<ApfsValue>>) -> Result<Self> {
+        if !volume.is_sealed() {
+            return Ok(ExtentTree::FsTree(fs_tree));
+        }
+        let storage = volume.fext_tree_type.storage();
+        let fext = match fs_tree.object_map {
+            Some((ref omap, xid)) if storage == StorageType::Virtual => {
+                Btree::load_btree_virtual(apfs, omap.clone(), volume.fext_tree_oid, xid)?
+            },
+            _ => Btree::load_btree(apfs, volume.fext_tree_oid, storage)?,
+        };
+        Ok(ExtentTree::Fext(fext))
+    }
+
+    /* The extent of a data stream covering the given offset, if any */
+    pub fn extent_at<S: BlockSource>(&self, apfs: &APFS<S>, private_id: u64, offset: u64) -> Result<Option<Extent>> {
+        let extent = match self {
+            ExtentTree::FsTree(btree) => {
+                let key = ApfsKey::new(private_id, JObjTypes::FileExtent, ApfsSubKey::FileExtent(JFileExtentKey { logical_addr: offset }));
+                btree.lookup(apfs, &key)?.and_then(|record| match (record.key.subkey, record.value) {
+                    (ApfsSubKey::FileExtent(key), ApfsValue::FileExtent(value)) => Some(Extent {
+                        logical_addr: key.logical_addr,
+                        length: value.length(),
+                        phys_block_num: value.phys_block_num,
+                        crypto_id: value.crypto_id,
+                    }),
+                    _ => None,
+                })
+            },
+            ExtentTree::Fext(btree) => {
+                let key = FextTreeKey { private_id, logical_addr: offset };
+                btree.lookup(apfs, &key)?.map(|record| Extent {
+                    logical_addr: record.key.logical_addr,
+                    length: record.value.length(),
+                    phys_block_num: record.value.phys_block_num,
+                    crypto_id: 0,
+                })
+            },
+        };
+        Ok(extent.filter(|extent| offset - extent.logical_addr < extent.length))
+    }
+}
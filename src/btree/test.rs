@@ -218,25 +218,84 @@ mod apfs_key {
         assert_eq!(key1.cmp(&key_object_id_greater_type_less), Ordering::Less);
         assert_eq!(key1.cmp(&key_object_id_greater_type_greater), Ordering::Less);
     }
+
+    fn import_key(oid: u64, r#type: JObjTypes, subkey: &[u8]) -> ApfsKey {
+        ApfsKey::import(&mut Cursor::new(crate::tests::fs_key(oid, r#type, subkey))).unwrap()
+    }
+
+    fn drec_key(oid: u64, hash: u32, name: &str) -> ApfsKey {
+        let mut subkey = ((hash << 10) | (name.len() as u32 + 1)).to_le_bytes().to_vec();
+        subkey.extend_from_slice(name.as_bytes());
+        subkey.push(0);
+        import_key(oid, JObjTypes::DirRec, &subkey)
+    }
+
+    fn xattr_key(oid: u64, name: &str) -> ApfsKey {
+        let mut subkey = (name.len() as u16 + 1).to_le_bytes().to_vec();
+        subkey.extend_from_slice(name.as_bytes());
+        subkey.push(0);
+        import_key(oid, JObjTypes::Xattr, &subkey)
+    }
+
+    #[test]
+    fn directory_records_collate_on_hash_before_name() {
+        assert_eq!(drec_key(500, 7, "zulu").cmp(&drec_key(500, 9, "alpha")), Ordering::Less);
+        assert_eq!(drec_key(500, 9, "alpha").cmp(&drec_key(500, 9, "zulu")), Ordering::Less);
+        assert_eq!(drec_key(500, 9, "alp").cmp(&drec_key(500, 9, "alpha")), Ordering::Less);
+        assert_eq!(drec_key(500, 9, "alpha"), drec_key(500, 9, "alpha"));
+        assert_ne!(drec_key(500, 9, "alpha"), drec_key(500, 9, "zulu"));
+        assert_ne!(drec_key(500, 9, "alpha"), drec_key(501, 9, "alpha"));
+    }
+
+    #[test]
+    fn extended_attributes_collate_on_name() {
+        assert_eq!(xattr_key(500, "com.apple.a").cmp(&xattr_key(500, "com.apple.b")), Ordering::Less);
+        assert_eq!(xattr_key(500, "com.apple.b").cmp(&xattr_key(500, "com.apple.a")), Ordering::Greater);
+        assert_eq!(xattr_key(500, "com.apple.a"), xattr_key(500, "com.apple.a"));
+    }
+
+    #[test]
+    fn file_extents_and_siblings_collate_on_numeric_subkey() {
+        let extent = |offset: u64| import_key(500, JObjTypes::FileExtent, &offset.to_le_bytes());
+        assert_eq!(extent(0x1000).cmp(&extent(0x2000)), Ordering::Less);
+        assert_eq!(extent(0x10000).cmp(&extent(0x2000)), Ordering::Greater);
+        assert_eq!(extent(0x2000), extent(0x2000));
+        assert_ne!(extent(0x1000), extent(0x2000));
+        let sibling = |id: u64| import_key(500, JObjTypes::SiblingLink, &id.to_le_bytes());
+        assert_eq!(sibling(3).cmp(&sibling(12)), Ordering::Less);
+        assert_ne!(sibling(3), sibling(12));
+    }
+
+    #[test]
+    fn records_collate_by_type_before_subkey() {
+        let inode = import_key(500, JObjTypes::Inode, &[]);
+        let xattr = xattr_key(500, "a");
+        let extent = import_key(500, JObjTypes::FileExtent, &0u64.to_le_bytes());
+        let drec = drec_key(500, 0, "a");
+        assert!(inode < xattr);
+        assert!(xattr < extent);
+        assert!(extent < drec);
+        assert!(drec < import_key(501, JObjTypes::Inode, &[]));
+    }
 }
 
-use crate::{tests::{test_dir, load_test_apfs_superblock, TEST_APFS_FILE, TEST_16KB_APFS_FILE}, JObjectIdAndType, ObjectMapObject, NxSuperblockObject, BtreeInfoFixed, BtFlags, ObjPhys, ObjectTypeAndFlags, ObjTypeFlags};
+use crate::{ImageSource, tests::{test_dir, load_test_apfs_superblock, TEST_APFS_FILE, TEST_16KB_APFS_FILE}, JObjectIdAndType, ObjectMapObject, NxSuperblockObject, BtreeInfoFixed, BtFlags, ObjPhys, ObjectTypeAndFlags, ObjTypeFlags};
 
-fn load_test_apfs_object_map(file: &str) -> (APFS<File>, NxSuperblockObject, ObjectMapObject) {
+fn load_test_apfs_object_map(file: &str) -> (APFS<ImageSource>, NxSuperblockObject, ObjectMapObject) {
     let (mut apfs, superblock) = load_test_apfs_superblock(file);
-    let object_result = apfs.load_object_oid(superblock.body.omap_oid, StorageType::Physical);
+    let object_result = apfs.load_object_oid(superblock.body.omap_oid, StorageType::Physical, None);
     assert!(object_result.is_ok(), "Bad object map load");
     let object = object_result.unwrap();
-    let omap = match object {
-        APFSObject::ObjectMap(x) => x,
+    let omap = match *object {
+        APFSObject::ObjectMap(ref x) => x.clone(),
         _ => { panic!("Wrong object type!"); },
     };
     (apfs, superblock, omap)
 }
 
-fn load_test_apfs_object_map_btree(file: &str) -> (APFS<File>, NxSuperblockObject, ObjectMapObject, Btree<OmapVal>) {
+fn load_test_apfs_object_map_btree(file: &str) -> (APFS<ImageSource>, NxSuperblockObject, ObjectMapObject, Btree<OmapVal>) {
     let (mut apfs, superblock, omap) = load_test_apfs_object_map(file);
-    let btree_result = Btree::<OmapVal>::load_btree(&mut apfs, omap.body.tree_oid, StorageType::Physical);
+    let btree_result = Btree::<OmapVal>::load_btree(&apfs, omap.body.tree_oid, StorageType::Physical);
     assert!(btree_result.is_ok(), "Bad b-tree load");
     let btree = btree_result.unwrap();
     (apfs, superblock, omap, btree)
@@ -265,8 +324,8 @@ fn test_load_object_map_btree() {
 #[test]
 fn test_load_object_map_btree_dummy() {
     let mut source = File::open(test_dir().join("btree.blob")).expect("Unable to load blob");
-    let mut apfs = APFS { source, block_size: 4096 };
-    let btree_result = Btree::<OmapVal>::load_btree(&mut apfs, Oid(0), StorageType::Physical);
+    let mut apfs = APFS::new(source, 4096);
+    let btree_result = Btree::<OmapVal>::load_btree(&apfs, Oid(0), StorageType::Physical);
     assert!(btree_result.is_ok(), "Bad b-tree load");
     let btree = btree_result.unwrap();
     let records = match &btree.root.records {
@@ -317,8 +376,8 @@ mod object_map {
 
     fn load_root_object_map() -> Btree<OmapVal> {
         let mut source = File::open(test_dir().join(OBJECT_MAP_ROOT_FILE)).expect("Unable to load blob");
-        let mut apfs = APFS { source, block_size: 4096 };
-        let btree_result = Btree::<OmapVal>::load_btree(&mut apfs, Oid(0), StorageType::Physical);
+        let mut apfs = APFS::new(source, 4096);
+        let btree_result = Btree::<OmapVal>::load_btree(&apfs, Oid(0), StorageType::Physical);
         assert!(btree_result.is_ok(), "Bad b-tree load");
         let btree = btree_result.unwrap();
         btree
@@ -327,8 +386,8 @@ mod object_map {
     fn load_nonroot_object_map(file: &str) -> BtreeNode<OmapVal> {
         let btree = load_root_object_map();
         let mut source = File::open(test_dir().join(file)).expect("Unable to load blob");
-        let mut apfs = APFS { source, block_size: 4096 };
-        let node_result = btree.load_btree_node(&mut apfs, Oid(0), StorageType::Physical);
+        let mut apfs = APFS::new(source, 4096);
+        let node_result = btree.load_btree_node(&apfs, Oid(0), StorageType::Physical);
         if node_result.is_err() {
             println!("Error: {:?}", node_result.as_ref().err());
         }
@@ -505,15 +564,14 @@ mod object_map {
         let mut leaf_blob = vec![0u8; BLOCK_SIZE];
         blob_source.read_exact(&mut leaf_blob).unwrap();
         let mut source = DummySource {
-            position: 0,
             block_size: BLOCK_SIZE as u64,
             blocks: HashMap::new(),
         };
         source.blocks.insert(ROOT_BLOCK, root_blob);
         source.blocks.insert(NONLEAF_BLOCK, nonleaf_blob);
         source.blocks.insert(LEAF_BLOCK, leaf_blob);
-        let mut apfs = APFS { source, block_size: BLOCK_SIZE };
-        let btree_result = Btree::<OmapVal>::load_btree(&mut apfs, Oid(ROOT_BLOCK), StorageType::Physical);
+        let mut apfs = APFS::new(source, BLOCK_SIZE);
+        let btree_result = Btree::<OmapVal>::load_btree(&apfs, Oid(ROOT_BLOCK), StorageType::Physical);
         (apfs, btree_result.expect("Bad b-tree load"))
     }
 
@@ -525,39 +583,82 @@ mod object_map {
     #[test]
     fn can_get_exact_matching_record_from_btree() {
         let (mut apfs, btree) = load_object_map_from_dummy_source();
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 9829294, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 9829474, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x408, 54, Oid(0x408), Xid(54), 4096, Paddr(1454548));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 9829294, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 9829474, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x408, 54, Oid(0x408), Xid(54), 4096, Paddr(1454548));
     }
 
     #[test]
     fn no_record_returned_on_bad_exact_match_from_btree() {
         let (mut apfs, btree) = load_object_map_from_dummy_source();
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x403, 9829294);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x405, 9829474);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x407, 54);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x409, 54);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x403, 9829294);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x405, 9829474);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x407, 54);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x409, 54);
     }
 
     #[test]
     fn can_get_inexact_matching_record_from_btree() {
         let (mut apfs, btree) = load_object_map_from_dummy_source();
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 9829295, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 9829473, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 9829475, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, 19829474, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x404, u64::MAX, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x408, 55, Oid(0x408), Xid(54), 4096, Paddr(1454548));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 0x408, u64::MAX, Oid(0x408), Xid(54), 4096, Paddr(1454548));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 9829295, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 9829473, Oid(0x404), Xid(9829294), 4096, Paddr(1284313));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 9829475, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, 19829474, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x404, u64::MAX, Oid(0x404), Xid(9829474), 4096, Paddr(1077411));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x408, 55, Oid(0x408), Xid(54), 4096, Paddr(1454548));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 0x408, u64::MAX, Oid(0x408), Xid(54), 4096, Paddr(1454548));
     }
 
     #[test]
     fn no_record_returned_on_bad_inexact_match_from_btree() {
         let (mut apfs, btree) = load_object_map_from_dummy_source();
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x404, 0);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x404, 9820354);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x408, 0);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 0x408, 53);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x404, 0);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x404, 9820354);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x408, 0);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 0x408, 53);
+    }
+
+    #[test]
+    fn can_resolve_virtual_oid() {
+        let (mut apfs, btree) = load_object_map_from_dummy_source();
+        let value = apfs.resolve_virtual_oid(&btree, Oid(0x404), Xid(9829473)).expect("Failed to resolve virtual object");
+        assert_eq!(value.paddr, Paddr(1284313));
+        let value = apfs.resolve_virtual_oid(&btree, Oid(0x404), Xid(u64::MAX)).expect("Failed to resolve virtual object");
+        assert_eq!(value.paddr, Paddr(1077411));
+    }
+
+    #[test]
+    fn missing_virtual_oid_is_not_found() {
+        let (mut apfs, btree) = load_object_map_from_dummy_source();
+        match apfs.resolve_virtual_oid(&btree, Oid(0x403), Xid(u64::MAX)) {
+            Err(ApfsError::ObjectNotFound { oid, xid }) => {
+                assert_eq!(oid, Oid(0x403));
+                assert_eq!(xid, Xid(u64::MAX));
+            },
+            other => { panic!("Unexpected result: {:?}", other); },
+        }
+        match apfs.resolve_virtual_oid(&btree, Oid(0x404), Xid(0)) {
+            Err(ApfsError::ObjectNotFound { .. }) => {},
+            other => { panic!("Unexpected result: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn virtual_object_load_checks_object_id() {
+        let (mut apfs, btree) = load_object_map_from_dummy_source();
+        match apfs.load_object_virtual(&btree, Oid(0x404), Xid(9829473)) {
+            Err(ApfsError::BadChecksum { paddr, .. }) => assert_eq!(paddr, Paddr(1284313)),
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+        let leaf_blob = apfs.source.blocks[&1080572].clone();
+        apfs.source.blocks.insert(1077411, leaf_blob);
+        match apfs.load_object_virtual(&btree, Oid(0x404), Xid(u64::MAX)) {
+            Err(ApfsError::OidMismatch { paddr, expected, .. }) => {
+                assert_eq!(paddr, Paddr(1077411));
+                assert_eq!(expected, Oid(0x404));
+            },
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
     }
 }
 
@@ -578,8 +679,8 @@ fn test_load_volume_superblock() {
     }
     assert!(found >= 0);
     let object = apfs.load_object_addr(records[found as usize].value.paddr).unwrap();
-    let volume = match object {
-        APFSObject::ApfsSuperblock(x) => x,
+    let volume = match *object {
+        APFSObject::ApfsSuperblock(ref x) => x.clone(),
         _ => { panic!("Wrong object type!"); },
     };
     assert_eq!(volume.body.volname[0..7], *b"MYAPFS\0");
@@ -616,8 +717,8 @@ fn check_omap_leaf_record_lookup_node(node: &BtreeNode<OmapVal>, key_oid: u64, k
    check_omap_leaf_record_lookup(node.get_record(&OmapKey::new(key_oid, key_xid)), oid, xid, size, paddr)
 }
 
-fn check_omap_leaf_record_lookup_btree<S: Read + Seek>(btree: &Btree<OmapVal>, apfs: &mut APFS<S>, key_oid: u64, key_xid: u64, oid: Oid, xid: Xid, size: u32, paddr: Paddr) {
-    let record = btree.get_record(apfs, &OmapKey::new(key_oid, key_xid)).expect("error looking up record");
+fn check_omap_leaf_record_lookup_btree<S: BlockSource>(btree: &Btree<OmapVal>, apfs: &APFS<S>, key_oid: u64, key_xid: u64, oid: Oid, xid: Xid, size: u32, paddr: Paddr) {
+    let record = btree.lookup(apfs, &OmapKey::new(key_oid, key_xid)).expect("error looking up record");
     check_omap_leaf_record_lookup(record.map(|x| AnyRecord::Leaf(x)), oid, xid, size, paddr);
 }
 
@@ -629,8 +730,8 @@ fn check_omap_record_lookup_missing_node(node: &BtreeNode<OmapVal>, key_oid: u64
     check_omap_record_lookup_missing(node.get_record(&OmapKey::new(key_oid, key_xid)))
 }
 
-fn check_omap_record_lookup_missing_btree<S: Read + Seek>(btree: &Btree<OmapVal>, apfs: &mut APFS<S>, key_oid: u64, key_xid: u64) {
-    let record = btree.get_record(apfs, &OmapKey::new(key_oid, key_xid)).expect("error looking up record");
+fn check_omap_record_lookup_missing_btree<S: BlockSource>(btree: &Btree<OmapVal>, apfs: &APFS<S>, key_oid: u64, key_xid: u64) {
+    let record = btree.lookup(apfs, &OmapKey::new(key_oid, key_xid)).expect("error looking up record");
     check_omap_record_lookup_missing(record.map(|x| AnyRecord::Leaf(x)))
 }
 
@@ -665,26 +766,26 @@ mod block_4k {
     #[test]
     fn can_get_exact_matching_record_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_APFS_FILE);
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1026, 4, Oid(1026), Xid(4), 4096, Paddr(102));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1026, 4, Oid(1026), Xid(4), 4096, Paddr(102));
     }
 
     #[test]
     fn no_record_returned_on_bad_exact_match_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_APFS_FILE);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 500, 999);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 2012, 1);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 500, 999);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 2012, 1);
     }
 
     #[test]
     fn can_get_inexact_matching_record_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_APFS_FILE);
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1026, 100, Oid(1026), Xid(4), 4096, Paddr(102));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1026, 100, Oid(1026), Xid(4), 4096, Paddr(102));
     }
 
     #[test]
     fn no_record_returned_on_bad_inexact_match_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_APFS_FILE);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1026, 1);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1026, 1);
     }
 }
 
@@ -740,88 +841,75 @@ mod block_16k {
     #[cfg_attr(not(feature = "expensive_tests"), ignore)]
     fn can_get_exact_matching_record_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_16KB_APFS_FILE);
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1026, 2, Oid(1026), Xid(2), 16384, Paddr(978));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1030, 3, Oid(1030), Xid(3), 16384, Paddr(986));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1032, 4, Oid(1032), Xid(4), 16384, Paddr(998));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1026, 2, Oid(1026), Xid(2), 16384, Paddr(978));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1030, 3, Oid(1030), Xid(3), 16384, Paddr(986));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1032, 4, Oid(1032), Xid(4), 16384, Paddr(998));
     }
 
     #[test]
     #[cfg_attr(not(feature = "expensive_tests"), ignore)]
     fn no_record_returned_on_bad_exact_match_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_16KB_APFS_FILE);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1025, 2);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1027, 2);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1029, 3);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1031, 3);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1031, 4);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1033, 4);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1025, 2);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1027, 2);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1029, 3);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1031, 3);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1031, 4);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1033, 4);
     }
 
     #[test]
     #[cfg_attr(not(feature = "expensive_tests"), ignore)]
     fn can_get_inexact_matching_record_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_16KB_APFS_FILE);
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1026, 3, Oid(1026), Xid(2), 16384, Paddr(978));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1026, 4, Oid(1026), Xid(2), 16384, Paddr(978));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1030, 4, Oid(1030), Xid(3), 16384, Paddr(986));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1030, 9, Oid(1030), Xid(3), 16384, Paddr(986));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1032, 5, Oid(1032), Xid(4), 16384, Paddr(998));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 1032, 30, Oid(1032), Xid(4), 16384, Paddr(998));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1026, 3, Oid(1026), Xid(2), 16384, Paddr(978));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1026, 4, Oid(1026), Xid(2), 16384, Paddr(978));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1030, 4, Oid(1030), Xid(3), 16384, Paddr(986));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1030, 9, Oid(1030), Xid(3), 16384, Paddr(986));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1032, 5, Oid(1032), Xid(4), 16384, Paddr(998));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 1032, 30, Oid(1032), Xid(4), 16384, Paddr(998));
     }
 
     #[test]
     #[cfg_attr(not(feature = "expensive_tests"), ignore)]
     fn no_record_returned_on_bad_inexact_match_from_btree() {
         let (mut apfs, _, _, btree) = load_test_apfs_object_map_btree(TEST_16KB_APFS_FILE);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1026, 0);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1026, 1);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1030, 1);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1030, 2);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1032, 2);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 1032, 3);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1026, 0);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1026, 1);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1030, 1);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1030, 2);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1032, 2);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 1032, 3);
     }
 }
 
 mod dummy_node {
     use super::*;
 
-    fn create_dummy_single_node() -> (APFS<Cursor<Vec<u8>>>, Btree<OmapVal>) {
-        (APFS {
-            source: Cursor::new(Vec::<u8>::new()),
-            block_size: 4096,
-        },
+    fn create_dummy_single_node() -> (APFS<Vec<u8>>, Btree<OmapVal>) {
+        (APFS::new(Vec::<u8>::new(), 4096),
         Btree {
-            root: Rc::new(BtreeNode {
-                node: BtreeNodeObject {
-                    header: ObjPhys {
-                        cksum: 0,
-                        oid: Oid(0),
-                        xid: Xid(0),
-                        r#type: ObjectTypeAndFlags::new_by_field(ObjectType::Btree, StorageType::Physical, ObjTypeFlags::empty()),
-                        subtype: ObjectTypeAndFlags::new_by_field(ObjectType::Omap, StorageType::Virtual, ObjTypeFlags::empty()),
+            root: Arc::new(BtreeNode {
+                header: ObjPhys {
+                    cksum: 0,
+                    oid: Oid(0),
+                    xid: Xid(0),
+                    r#type: ObjectTypeAndFlags::new_by_field(ObjectType::Btree, StorageType::Physical, ObjTypeFlags::empty()),
+                    subtype: ObjectTypeAndFlags::new_by_field(ObjectType::Omap, StorageType::Virtual, ObjTypeFlags::empty()),
+                },
+                layout: NodeLayout {
+                    flags: BtnFlags::ROOT | BtnFlags::LEAF | BtnFlags::FIXED_KV_SIZE,
+                    level: 0,
+                    table_space: Nloc {
+                        off: 0,
+                        len: 0,
                     },
-                    body: BtreeNodePhys {
-                        flags: BtnFlags::ROOT | BtnFlags::LEAF | BtnFlags::FIXED_KV_SIZE,
-                        level: 0,
-                        nkeys: 8,
-                        table_space: Nloc {
-                            off: 0,
-                            len: 0,
-                        },
-                        free_space: Nloc {
-                            off: 0,
-                            len: 0,
-                        },
-                        key_free_list: Nloc {
-                            off: 0,
-                            len: 0,
-                        },
-                        val_free_list: Nloc {
-                            off: 0,
-                            len: 0,
-                        },
-                        data: vec![],
+                    free_space: Nloc {
+                        off: 0,
+                        len: 0,
                     },
+                    data_len: 0,
+                    toc: vec![],
                 },
                 records: AnyRecords::Leaf(vec![
                     LeafRecord {
@@ -871,6 +959,7 @@ mod dummy_node {
                 key_count: 8,
                 node_count: 1,
             },
+            object_map: None,
             _v: PhantomData,
         })
     }
@@ -930,60 +1019,60 @@ mod dummy_node {
     #[test]
     fn can_get_exact_matching_record_from_btree() {
         let (mut apfs, btree) = create_dummy_single_node();
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 110, 1000, Oid(110), Xid(1000), 4096, Paddr(30));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 100, Oid(120), Xid(100), 4096, Paddr(50));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 200, Oid(120), Xid(200), 4096, Paddr(40));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 300, Oid(120), Xid(300), 4096, Paddr(60));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 130, 50, Oid(130), Xid(50), 4096, Paddr(100));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 130, 51, Oid(130), Xid(51), 4096, Paddr(101));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 131, 10, Oid(131), Xid(10), 4096, Paddr(90));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 135, 50, Oid(135), Xid(50), 4096, Paddr(95));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 110, 1000, Oid(110), Xid(1000), 4096, Paddr(30));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 100, Oid(120), Xid(100), 4096, Paddr(50));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 200, Oid(120), Xid(200), 4096, Paddr(40));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 300, Oid(120), Xid(300), 4096, Paddr(60));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 130, 50, Oid(130), Xid(50), 4096, Paddr(100));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 130, 51, Oid(130), Xid(51), 4096, Paddr(101));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 131, 10, Oid(131), Xid(10), 4096, Paddr(90));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 135, 50, Oid(135), Xid(50), 4096, Paddr(95));
     }
 
     #[test]
     fn no_record_returned_on_bad_exact_match_from_btree() {
         let (mut apfs, btree) = create_dummy_single_node();
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 109, 1000);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 111, 1000);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 119, 100);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 121, 200);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 129, 50);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 134, 10);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 136, 50);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 109, 1000);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 111, 1000);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 119, 100);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 121, 200);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 129, 50);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 134, 10);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 136, 50);
     }
 
     #[test]
     fn can_get_inexact_matching_record_from_btree() {
         let (mut apfs, btree) = create_dummy_single_node();
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 110, u64::MAX, Oid(110), Xid(1000), 4096, Paddr(30));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 199, Oid(120), Xid(100), 4096, Paddr(50));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 201, Oid(120), Xid(200), 4096, Paddr(40));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 299, Oid(120), Xid(200), 4096, Paddr(40));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 120, 30000, Oid(120), Xid(300), 4096, Paddr(60));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 130, 51, Oid(130), Xid(51), 4096, Paddr(101));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 130, u64::MAX, Oid(130), Xid(51), 4096, Paddr(101));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 131, 49, Oid(131), Xid(10), 4096, Paddr(90));
-        check_omap_leaf_record_lookup_btree(&btree, &mut apfs, 135, 65, Oid(135), Xid(50), 4096, Paddr(95));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 110, u64::MAX, Oid(110), Xid(1000), 4096, Paddr(30));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 199, Oid(120), Xid(100), 4096, Paddr(50));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 201, Oid(120), Xid(200), 4096, Paddr(40));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 299, Oid(120), Xid(200), 4096, Paddr(40));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 120, 30000, Oid(120), Xid(300), 4096, Paddr(60));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 130, 51, Oid(130), Xid(51), 4096, Paddr(101));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 130, u64::MAX, Oid(130), Xid(51), 4096, Paddr(101));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 131, 49, Oid(131), Xid(10), 4096, Paddr(90));
+        check_omap_leaf_record_lookup_btree(&btree, &apfs, 135, 65, Oid(135), Xid(50), 4096, Paddr(95));
     }
 
     #[test]
     fn no_record_returned_on_bad_inexact_match_from_btree() {
         let (mut apfs, btree) = create_dummy_single_node();
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 110, 999);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 120, 0);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 120, 50);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 120, 99);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 130, 49);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 130, 1);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 131, 9);
-        check_omap_record_lookup_missing_btree(&btree, &mut apfs, 135, 49);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 110, 999);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 120, 0);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 120, 50);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 120, 99);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 130, 49);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 130, 1);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 131, 9);
+        check_omap_record_lookup_missing_btree(&btree, &apfs, 135, 49);
     }
 }
 
 mod spaceman_free_queue {
     use super::*;
 
-    fn load_test_apfs_sfq_btree(file: &str) -> (APFS<File>, NxSuperblockObject, Btree<SpacemanFreeQueueValue>) {
+    fn load_test_apfs_sfq_btree(file: &str) -> (APFS<ImageSource>, NxSuperblockObject, Btree<SpacemanFreeQueueValue>) {
         let (mut apfs, superblock) = load_test_apfs_superblock(file);
         let btree = apfs.load_btree::<SpacemanFreeQueueValue>(Oid(superblock.body.xp_data_base.0 as u64 + 12), StorageType::Physical)
             .expect("Bad b-tree load");
@@ -1016,7 +1105,7 @@ mod spaceman_free_queue {
 mod spaceman_free_queue_16k {
     use super::*;
 
-    fn load_test_apfs_sfq_btree(file: &str) -> (APFS<File>, NxSuperblockObject, Btree<SpacemanFreeQueueValue>) {
+    fn load_test_apfs_sfq_btree(file: &str) -> (APFS<ImageSource>, NxSuperblockObject, Btree<SpacemanFreeQueueValue>) {
         let (mut apfs, superblock) = load_test_apfs_superblock(file);
         let btree = apfs.load_btree::<SpacemanFreeQueueValue>(Oid(superblock.body.xp_data_base.0 as u64 + 13), StorageType::Physical)
             .expect("Bad b-tree load");
@@ -1046,3 +1135,850 @@ mod spaceman_free_queue_16k {
         // assert_eq!(records[3].value.0, 3);
     }
 }
+
+mod record_lookup {
+    use crate::tests::{DummySource, build_btree_node, fs_key};
+    use crate::{BtnFlags, JObjTypes, SpacemanFreeQueueKey};
+
+    use super::*;
+
+    pub fn extent_key(oid: u64, logical_addr: u64) -> Vec<u8> {
+        fs_key(oid, JObjTypes::FileExtent, &logical_addr.to_le_bytes())
+    }
+
+    pub fn extent_val(length: u64, paddr: u64) -> Option<Vec<u8>> {
+        let mut value = length.to_le_bytes().to_vec();
+        value.extend_from_slice(&paddr.to_le_bytes());
+        value.extend_from_slice(&0u64.to_le_bytes());
+        Some(value)
+    }
+
+    pub fn child(oid: u64) -> Option<Vec<u8>> {
+        Some(oid.to_le_bytes().to_vec())
+    }
+
+    pub fn load_extent_tree() -> (APFS<DummySource>, Btree<ApfsValue>) {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT, 1, BtFlags::PHYSICAL, &[
+            (extent_key(0x10, 0), child(2)),
+            (extent_key(0x10, 0x3000), child(3)),
+        ]));
+        source.blocks.insert(2, build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+            (extent_key(0x10, 0x1000), extent_val(0x2000, 200)),
+        ]));
+        source.blocks.insert(3, build_btree_node(3, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0x3000), extent_val(0x1000, 300)),
+            (extent_key(0x11, 0), extent_val(0x1000, 400)),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<ApfsValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        (apfs, btree)
+    }
+
+    /* Key of the record a point lookup should find, being the last one
+       not above the searched key if it matches */
+    pub fn scan_lookup<V: LeafValue>(apfs: &APFS<DummySource>, btree: &Btree<V>, key: &V::Key) -> Option<V::Key> {
+        btree.iter(apfs)
+            .map(|record| record.expect("Failed iteration").key)
+            .take_while(|found| key.r#match(found) != Ordering::Less)
+            .last()
+            .filter(|found| key.r#match(found) == Ordering::Equal)
+    }
+
+    fn lookup_extent(apfs: &APFS<DummySource>, btree: &Btree<ApfsValue>, oid: u64, offset: u64) -> Option<(u64, u64)> {
+        let key = ApfsKey::import(&mut Cursor::new(extent_key(oid, offset))).unwrap();
+        let record = btree.lookup(apfs, &key).expect("Failed lookup")?;
+        let logical_addr = match record.key.subkey {
+            ApfsSubKey::FileExtent(ref subkey) => subkey.logical_addr,
+            ref other => { panic!("Wrong subkey: {:?}", other); },
+        };
+        match record.value {
+            ApfsValue::FileExtent(value) => Some((logical_addr, value.phys_block_num)),
+            other => { panic!("Wrong value: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn can_find_file_extent_covering_offset() {
+        let (apfs, btree) = load_extent_tree();
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0), Some((0, 100)));
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0x1800), Some((0x1000, 200)));
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0x2fff), Some((0x1000, 200)));
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0x5000), Some((0x3000, 300)));
+        assert_eq!(lookup_extent(&apfs, &btree, 0x11, 0x10), Some((0, 400)));
+    }
+
+    #[test]
+    fn no_file_extent_for_other_inodes() {
+        let (apfs, btree) = load_extent_tree();
+        assert_eq!(lookup_extent(&apfs, &btree, 0x0f, 0x1000), None);
+        assert_eq!(lookup_extent(&apfs, &btree, 0x12, 0), None);
+    }
+
+    #[test]
+    fn lookups_load_nodes_through_the_cache() {
+        let (apfs, btree) = load_extent_tree();
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0x1800), Some((0x1000, 200)));
+        let misses = apfs.cache_stats().misses;
+        assert_eq!(lookup_extent(&apfs, &btree, 0x10, 0), Some((0, 100)));
+        assert_eq!(apfs.cache_stats().misses, misses);
+        assert!(apfs.cache_stats().hits > 0);
+    }
+
+    pub fn sfq_key(xid: u64, paddr: u64) -> Vec<u8> {
+        let mut key = xid.to_le_bytes().to_vec();
+        key.extend_from_slice(&paddr.to_le_bytes());
+        key
+    }
+
+    #[test]
+    fn can_find_exact_free_queue_entry() {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::SpacemanFreeQueue, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::ALLOW_GHOSTS, &[
+            (sfq_key(2, 83), Some(2u64.to_le_bytes().to_vec())),
+            (sfq_key(3, 85), None),
+            (sfq_key(3, 89), Some(4u64.to_le_bytes().to_vec())),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<SpacemanFreeQueueValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        let lookup = |xid, paddr| btree.lookup(&apfs, &SpacemanFreeQueueKey { xid: Xid(xid), paddr: Paddr(paddr) }).expect("Failed lookup");
+        assert_eq!(lookup(3, 89).expect("Missing record").value.expect("Unexpected ghost").0, 4);
+        assert!(lookup(3, 85).expect("Missing record").value.is_none());
+        assert!(lookup(3, 86).is_none());
+        assert!(lookup(2, 85).is_none());
+    }
+}
+
+mod record_iteration {
+    use crate::tests::{DummySource, build_btree_node};
+    use crate::{BtnFlags, SpacemanFreeQueueKey};
+
+    use super::*;
+    use super::record_lookup::*;
+
+    fn sfq_val(length: u64) -> Option<Vec<u8>> {
+        Some(length.to_le_bytes().to_vec())
+    }
+
+    fn load_sfq_tree() -> (APFS<DummySource>, Btree<SpacemanFreeQueueValue>) {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::SpacemanFreeQueue, BtnFlags::ROOT, 1, BtFlags::ALLOW_GHOSTS, &[
+            (sfq_key(2, 83), child(2)),
+            (sfq_key(4, 96), child(3)),
+        ]));
+        source.blocks.insert(2, build_btree_node(2, 1, ObjectType::SpacemanFreeQueue, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (sfq_key(2, 83), sfq_val(2)),
+            (sfq_key(3, 85), None),
+            (sfq_key(3, 89), sfq_val(4)),
+        ]));
+        source.blocks.insert(3, build_btree_node(3, 1, ObjectType::SpacemanFreeQueue, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (sfq_key(4, 96), sfq_val(3)),
+            (sfq_key(5, 12), sfq_val(1)),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<SpacemanFreeQueueValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        (apfs, btree)
+    }
+
+    fn key(xid: u64, paddr: i64) -> SpacemanFreeQueueKey {
+        SpacemanFreeQueueKey { xid: Xid(xid), paddr: Paddr(paddr) }
+    }
+
+    fn keys<I: Iterator<Item = Result<LeafRecord<SpacemanFreeQueueValue>>>>(records: I) -> Vec<(u64, i64)> {
+        records.map(|record| {
+            let record = record.expect("Failed iteration");
+            (record.key.xid.0, record.key.paddr.0)
+        }).collect()
+    }
+
+    #[test]
+    fn can_iterate_all_records_in_order() {
+        let (apfs, btree) = load_sfq_tree();
+        assert_eq!(keys(btree.iter(&apfs)), vec![(2, 83), (3, 85), (3, 89), (4, 96), (5, 12)]);
+    }
+
+    #[test]
+    fn can_iterate_bounded_range_across_leaves() {
+        let (apfs, btree) = load_sfq_tree();
+        assert_eq!(keys(btree.range(&apfs, key(3, 0)..key(5, 0))), vec![(3, 85), (3, 89), (4, 96)]);
+        assert_eq!(keys(btree.range(&apfs, key(3, 85)..=key(4, 96))), vec![(3, 85), (3, 89), (4, 96)]);
+        assert_eq!(keys(btree.range(&apfs, (Bound::Excluded(key(3, 89)), Bound::Unbounded))), vec![(4, 96), (5, 12)]);
+        assert_eq!(keys(btree.range(&apfs, key(6, 0)..)), vec![]);
+        assert_eq!(keys(btree.range(&apfs, ..key(2, 0))), vec![]);
+    }
+
+    #[test]
+    fn range_loads_children_on_demand() {
+        let (apfs, btree) = load_sfq_tree();
+        let mut records = btree.range(&apfs, ..key(4, 0));
+        assert_eq!(apfs.cache_stats().misses, 1);
+        assert_eq!(keys(records.by_ref().take(1)), vec![(2, 83)]);
+        assert_eq!(apfs.cache_stats().misses, 2);
+        assert_eq!(keys(records), vec![(3, 85), (3, 89)]);
+        assert_eq!(apfs.cache_stats().misses, 2);
+    }
+
+    #[test]
+    fn iteration_fails_on_index_node_pointing_to_itself() {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::SpacemanFreeQueue, BtnFlags::ROOT, 2, BtFlags::ALLOW_GHOSTS, &[
+            (sfq_key(2, 83), child(3)),
+            (sfq_key(4, 96), child(2)),
+        ]));
+        source.blocks.insert(2, build_btree_node(2, 1, ObjectType::SpacemanFreeQueue, BtnFlags::empty(), 1, BtFlags::empty(), &[
+            (sfq_key(4, 96), child(2)),
+        ]));
+        source.blocks.insert(3, build_btree_node(3, 1, ObjectType::SpacemanFreeQueue, BtnFlags::empty(), 1, BtFlags::empty(), &[
+            (sfq_key(2, 83), child(4)),
+        ]));
+        source.blocks.insert(4, build_btree_node(4, 1, ObjectType::SpacemanFreeQueue, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (sfq_key(2, 83), sfq_val(2)),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<SpacemanFreeQueueValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        let mut records = btree.iter(&apfs);
+        assert_eq!(records.next().expect("Missing record").expect("Failed iteration").key, SpacemanFreeQueueKey { xid: Xid(2), paddr: Paddr(83) });
+        assert!(matches!(records.next(), Some(Err(ApfsError::Corrupt(_)))));
+        assert!(records.next().is_none());
+        let mut records = btree.range(&apfs, key(4, 96)..);
+        assert!(matches!(records.next(), Some(Err(ApfsError::Corrupt(_)))));
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn can_iterate_records_of_one_inode() {
+        let (apfs, btree) = load_extent_tree();
+        let start = ApfsKey::import(&mut Cursor::new(extent_key(0x10, 0))).unwrap();
+        let end = ApfsKey::import(&mut Cursor::new(extent_key(0x10, u64::MAX))).unwrap();
+        let records = btree.range(&apfs, start..=end).collect::<Result<Vec<_>>>().expect("Failed iteration");
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.key.key.obj_id_and_type.id() == 0x10));
+    }
+}
+
+mod virtual_children {
+    use crate::tests::{DummySource, build_btree_node, build_omap_btree};
+    use crate::BtnFlags;
+
+    use super::*;
+    use super::record_lookup::*;
+
+    pub const OMAP_TREE_BLOCK: u64 = 31;
+
+    /* A file-system tree with virtual root 0x500 and children 0x501
+       and 0x502, where 0x502 was rewritten in transaction 5 */
+    pub fn load_virtual_source(tree_flags: BtFlags) -> APFS<DummySource> {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(OMAP_TREE_BLOCK, build_omap_btree(OMAP_TREE_BLOCK, 5, &[
+            (0x500, 1, 0, 40),
+            (0x501, 1, 0, 41),
+            (0x502, 1, 0, 42),
+            (0x502, 5, 0, 43),
+        ]));
+        source.blocks.insert(40, build_btree_node(0x500, 1, ObjectType::Fstree, BtnFlags::ROOT, 1, tree_flags, &[
+            (extent_key(0x10, 0), child(0x501)),
+            (extent_key(0x10, 0x3000), child(0x502)),
+        ]));
+        source.blocks.insert(41, build_btree_node(0x501, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x3000, 100)),
+        ]));
+        source.blocks.insert(42, build_btree_node(0x502, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0x3000), extent_val(0x1000, 200)),
+        ]));
+        source.blocks.insert(43, build_btree_node(0x502, 5, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0x3000), extent_val(0x1000, 300)),
+        ]));
+        APFS::new(source, 4096)
+    }
+
+    pub fn load_omap(apfs: &APFS<DummySource>) -> Arc<Btree<OmapVal>> {
+        Arc::new(apfs.load_btree(Oid(OMAP_TREE_BLOCK), StorageType::Physical).expect("Bad object map load"))
+    }
+
+    fn physical_blocks(apfs: &APFS<DummySource>, btree: &Btree<ApfsValue>) -> Vec<u64> {
+        btree.iter(apfs).map(|record| match record.expect("Failed iteration").value {
+            ApfsValue::FileExtent(value) => value.phys_block_num,
+            other => { panic!("Wrong value: {:?}", other); },
+        }).collect()
+    }
+
+    #[test]
+    fn can_iterate_tree_with_virtual_children() {
+        let apfs = load_virtual_source(BtFlags::empty());
+        let omap = load_omap(&apfs);
+        let btree = apfs.load_btree_virtual::<ApfsValue>(omap.clone(), Oid(0x500), Xid(1)).expect("Bad b-tree load");
+        assert_eq!(physical_blocks(&apfs, &btree), vec![100, 200]);
+        let btree = apfs.load_btree_virtual::<ApfsValue>(omap, Oid(0x500), Xid(5)).expect("Bad b-tree load");
+        assert_eq!(physical_blocks(&apfs, &btree), vec![100, 300]);
+        assert_eq!(btree.object_map().map(|(_, xid)| xid), Some(Xid(5)));
+    }
+
+    #[test]
+    fn can_look_up_through_virtual_children() {
+        let apfs = load_virtual_source(BtFlags::empty());
+        let btree = apfs.load_btree_virtual::<ApfsValue>(load_omap(&apfs), Oid(0x500), Xid(5)).expect("Bad b-tree load");
+        let key = ApfsKey::import(&mut Cursor::new(extent_key(0x10, 0x3800))).unwrap();
+        match btree.lookup(&apfs, &key).expect("Failed lookup").expect("Missing record").value {
+            ApfsValue::FileExtent(value) => assert_eq!(value.phys_block_num, 300),
+            other => { panic!("Wrong value: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn physical_flag_keeps_children_physical() {
+        let apfs = load_virtual_source(BtFlags::PHYSICAL);
+        let btree = apfs.load_btree_virtual::<ApfsValue>(load_omap(&apfs), Oid(0x500), Xid(5)).expect("Bad b-tree load");
+        match btree.iter(&apfs).next() {
+            Some(Err(ApfsError::BadChecksum { paddr, .. })) => assert_eq!(paddr, Paddr(0x501)),
+            other => { panic!("Unexpected result: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn missing_virtual_child_is_an_error() {
+        let apfs = load_virtual_source(BtFlags::empty());
+        let btree = apfs.load_btree_virtual::<ApfsValue>(load_omap(&apfs), Oid(0x500), Xid(5)).expect("Bad b-tree load");
+        assert!(apfs.load_btree_virtual::<ApfsValue>(load_omap(&apfs), Oid(0x500), Xid(0)).is_err());
+        match btree.load_child_node(&apfs, Oid(0x503)) {
+            Err(ApfsError::ObjectNotFound { oid, .. }) => assert_eq!(oid, Oid(0x503)),
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+    }
+}
+
+mod malformed {
+    use crate::tests::{DummySource, build_btree_node};
+    use crate::{BtnFlags, DrecExtType, InoExtType, fletcher64, import_drec_xfields, import_inode_xfields};
+
+    use super::*;
+    use super::record_lookup::*;
+
+    fn load_corrupted(corrupt: impl FnOnce(&mut Vec<u8>), tree_flags: BtFlags) -> Result<Btree<ApfsValue>> {
+        let mut block = build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT | BtnFlags::LEAF, 0, tree_flags, &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+        ]);
+        corrupt(&mut block);
+        let checksum = fletcher64(&block[8..]);
+        block[0..8].copy_from_slice(&checksum.to_le_bytes());
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, block);
+        let apfs = APFS::new(source, 4096);
+        Btree::<ApfsValue>::load_btree(&apfs, Oid(1), StorageType::Physical)
+    }
+
+    fn put_u16(block: &mut [u8], offset: usize, value: u16) {
+        block[offset..offset+2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn table_of_contents_out_of_range_is_an_error() {
+        assert!(load_corrupted(|_| {}, BtFlags::empty()).is_ok());
+        let result = load_corrupted(|block| put_u16(block, 40, 0xfff0), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+        let result = load_corrupted(|block| put_u16(block, 42, 0xffff), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn record_offsets_out_of_range_are_an_error() {
+        let result = load_corrupted(|block| put_u16(block, 56, 0xfff0), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+        let result = load_corrupted(|block| put_u16(block, 58, 0xffff), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+        let result = load_corrupted(|block| put_u16(block, 60, 0xfff0), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+        let result = load_corrupted(|block| put_u16(block, 62, 0xffff), BtFlags::empty());
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn ghost_in_tree_without_ghost_values_is_an_error() {
+        let result = load_corrupted(|block| put_u16(block, 60, BTOFF_INVALID), BtFlags::ALLOW_GHOSTS);
+        assert!(matches!(result, Err(ApfsError::Corrupt(_))), "{:?}", result.err());
+    }
+
+    fn xfields(fields: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut blob = (fields.len() as u16).to_le_bytes().to_vec();
+        blob.extend_from_slice(&0u16.to_le_bytes());
+        for (r#type, data) in fields {
+            blob.extend_from_slice(&[*r#type, 0]);
+            blob.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        }
+        for (_, data) in fields {
+            blob.extend_from_slice(data);
+            blob.extend(std::iter::repeat(0).take(((data.len() + 7) & !7) - data.len()));
+        }
+        blob
+    }
+
+    #[test]
+    fn can_import_inode_xfields() {
+        let xdata = import_inode_xfields(&xfields(&[
+            (InoExtType::DocumentId as u8, &7u32.to_le_bytes()),
+            (InoExtType::Name as u8, b"file\0"),
+        ])).expect("Bad xfields");
+        assert!(matches!(xdata.get(&InoExtType::DocumentId), Some(InodeXdata::DocumentId(7))));
+        assert!(matches!(xdata.get(&InoExtType::Name), Some(InodeXdata::Name(name)) if name == "file\0"));
+    }
+
+    #[test]
+    fn malformed_xfields_are_an_error() {
+        assert!(import_inode_xfields(&xfields(&[(InoExtType::DocumentId as u8, &7u64.to_le_bytes())])).is_err());
+        assert!(import_inode_xfields(&xfields(&[(InoExtType::Dstream as u8, &[0u8; 8])])).is_err());
+        assert!(import_inode_xfields(&xfields(&[(InoExtType::Name as u8, &[0xff, 0xfe])])).is_err());
+        assert!(import_inode_xfields(&xfields(&[(0xee, &[0u8; 8])])).is_err());
+        assert!(import_drec_xfields(&xfields(&[(DrecExtType::DrecExtTypeSiblingId as u8, &[0u8; 4])])).is_err());
+        let mut truncated = xfields(&[(InoExtType::PrevFsize as u8, &1u64.to_le_bytes())]);
+        truncated.truncate(10);
+        assert!(matches!(import_inode_xfields(&truncated), Err(ApfsError::Truncated)));
+        assert!(import_inode_xfields(&[1]).is_err());
+    }
+}
+
+mod validation {
+    use crate::tests::{DummySource, build_btree_node, put_u32, put_u64};
+    use crate::{BtnFlags, fletcher64};
+
+    use super::*;
+    use super::record_lookup::*;
+
+    /* Offset of the B-tree info at the end of a 4k root node */
+    const INFO: usize = 4096 - 40;
+
+    fn extent_tree_blocks() -> HashMap<u64, Vec<u8>> {
+        let mut blocks = HashMap::new();
+        let mut root = build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT, 1, BtFlags::PHYSICAL, &[
+            (extent_key(0x10, 0), child(2)),
+            (extent_key(0x10, 0x3000), child(3)),
+        ]);
+        patch(&mut root, |block| {
+            put_u32(block, INFO + 20, 24);
+            put_u64(block, INFO + 24, 4);
+            put_u64(block, INFO + 32, 3);
+        });
+        blocks.insert(1, root);
+        blocks.insert(2, build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+            (extent_key(0x10, 0x1000), extent_val(0x2000, 200)),
+        ]));
+        blocks.insert(3, build_btree_node(3, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0x3000), extent_val(0x1000, 300)),
+            (extent_key(0x11, 0), extent_val(0x1000, 400)),
+        ]));
+        blocks
+    }
+
+    fn patch(block: &mut Vec<u8>, change: impl FnOnce(&mut Vec<u8>)) {
+        change(block);
+        let checksum = fletcher64(&block[8..]);
+        block[0..8].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    fn validate(blocks: HashMap<u64, Vec<u8>>) -> Vec<BtreeIssue> {
+        let apfs = APFS::new(DummySource { block_size: 4096, blocks }, 4096);
+        let btree = Btree::<ApfsValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        btree.validate(&apfs)
+    }
+
+    #[test]
+    fn well_formed_tree_has_no_issues() {
+        let issues = validate(extent_tree_blocks());
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn totals_are_checked_against_info() {
+        let mut blocks = extent_tree_blocks();
+        patch(blocks.get_mut(&1).unwrap(), |block| {
+            put_u32(block, INFO + 16, 8);
+            put_u64(block, INFO + 24, 2);
+            put_u64(block, INFO + 32, 1);
+        });
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        assert!(matches!(issues[0], BtreeIssue::KeyCount { expected: 2, found: 4 }), "{:?}", issues);
+        assert!(matches!(issues[1], BtreeIssue::NodeCount { expected: 1, found: 3 }), "{:?}", issues);
+        assert!(matches!(issues[2], BtreeIssue::LongestKey { expected: 8, found: 16 }), "{:?}", issues);
+    }
+
+    #[test]
+    fn misordered_keys_are_reported_with_their_node() {
+        let mut blocks = extent_tree_blocks();
+        blocks.insert(3, build_btree_node(3, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x11, 0), extent_val(0x1000, 400)),
+            (extent_key(0x10, 0x2000), extent_val(0x1000, 300)),
+        ]));
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 2, "{:?}", issues);
+        assert!(matches!(issues[0], BtreeIssue::KeyOrder { node: Oid(3), index: 1 }), "{:?}", issues);
+        assert!(matches!(issues[1], BtreeIssue::KeyOutOfRange { node: Oid(3), index: 1 }), "{:?}", issues);
+    }
+
+    #[test]
+    fn child_levels_must_decrease_by_one() {
+        let mut blocks = extent_tree_blocks();
+        blocks.insert(2, build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::empty(), 1, BtFlags::empty(), &[
+            (extent_key(0x10, 0), child(2)),
+        ]));
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert!(matches!(issues[0], BtreeIssue::LevelMismatch { node: Oid(2), expected: 0, found: 1 }), "{:?}", issues);
+    }
+
+    #[test]
+    fn deep_trees_are_walked_without_recursing() {
+        const DEPTH: u64 = 4000;
+        let mut blocks = HashMap::new();
+        let mut root = build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT, DEPTH as u16, BtFlags::PHYSICAL, &[
+            (extent_key(0x10, 0), child(2)),
+        ]);
+        patch(&mut root, |block| {
+            put_u32(block, INFO + 20, 24);
+            put_u64(block, INFO + 24, 1);
+            put_u64(block, INFO + 32, DEPTH + 1);
+        });
+        blocks.insert(1, root);
+        for oid in 2..=DEPTH {
+            blocks.insert(oid, build_btree_node(oid, 1, ObjectType::Fstree, BtnFlags::empty(), (DEPTH + 1 - oid) as u16, BtFlags::empty(), &[
+                (extent_key(0x10, 0), child(oid + 1)),
+            ]));
+        }
+        blocks.insert(DEPTH + 1, build_btree_node(DEPTH + 1, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+        ]));
+        let issues = validate(blocks);
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn unreadable_children_are_reported() {
+        let mut blocks = extent_tree_blocks();
+        blocks.remove(&3);
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert!(matches!(issues[0], BtreeIssue::Unreadable { node: Oid(3), error: ApfsError::BadChecksum { .. } }), "{:?}", issues);
+    }
+
+    #[test]
+    fn overlapping_areas_are_reported() {
+        let mut blocks = extent_tree_blocks();
+        patch(blocks.get_mut(&2).unwrap(), |block| {
+            block[44..46].copy_from_slice(&8u16.to_le_bytes());
+        });
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 2, "{:?}", issues);
+        assert!(matches!(issues[0], BtreeIssue::AreaOverlap { node: Oid(2), area: NodeArea::Key(0), other: NodeArea::FreeSpace }), "{:?}", issues);
+        assert!(matches!(issues[1], BtreeIssue::AreaOverlap { node: Oid(2), area: NodeArea::Key(1), other: NodeArea::FreeSpace }), "{:?}", issues);
+    }
+
+    #[test]
+    fn fixed_size_trees_must_use_short_entries() {
+        let mut blocks = extent_tree_blocks();
+        patch(blocks.get_mut(&1).unwrap(), |block| {
+            put_u32(block, INFO + 8, 16);
+            put_u32(block, INFO + 12, 24);
+        });
+        let issues = validate(blocks);
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        for (issue, oid) in issues.iter().zip(&[1, 2, 3]) {
+            assert!(matches!(issue, BtreeIssue::KvLayout { node, fixed: false } if node.0 == *oid), "{:?}", issues);
+        }
+    }
+}
+
+mod node_view {
+    use crate::tests::{DummySource, build_btree_node};
+    use crate::{BtnFlags, NodeView};
+
+    use super::*;
+    use super::record_lookup::*;
+    use super::virtual_children::*;
+
+    fn view_info(flags: BtFlags) -> BtreeInfo {
+        BtreeInfo {
+            fixed: BtreeInfoFixed { flags, node_size: 4096, key_size: 0, val_size: 0 },
+            longest_key: 0,
+            longest_val: 0,
+            key_count: 0,
+            node_count: 0,
+        }
+    }
+
+    fn extent(oid: u64, offset: u64) -> ApfsKey {
+        ApfsKey::import(&mut Cursor::new(extent_key(oid, offset))).unwrap()
+    }
+
+    #[test]
+    fn can_read_keys_and_values_in_place() {
+        let block = build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::ALLOW_GHOSTS, &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+            (extent_key(0x10, 0x1000), None),
+        ]);
+        let view = NodeView::parse(Paddr(2), &block, &view_info(BtFlags::ALLOW_GHOSTS)).expect("Bad node view");
+        assert_eq!(view.len(), 2);
+        assert!(view.is_leaf());
+        assert_eq!(view.key(0).unwrap(), &extent_key(0x10, 0)[..]);
+        assert_eq!(view.value(0).unwrap(), extent_val(0x1000, 100).as_deref());
+        assert_eq!(view.value(1).unwrap(), None);
+        assert!(view.key(2).is_err());
+        let record = view.decode_record::<ApfsValue>(0).expect("Bad record");
+        assert_eq!(record.key, extent(0x10, 0));
+    }
+
+    #[test]
+    fn root_view_excludes_tree_info() {
+        let block = build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+        ]);
+        let view = NodeView::parse(Paddr(1), &block, &view_info(BtFlags::empty())).expect("Bad node view");
+        assert_eq!(view.value(0).unwrap(), extent_val(0x1000, 100).as_deref());
+    }
+
+    #[test]
+    fn search_finds_last_entry_not_above_key() {
+        let block = build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT, 1, BtFlags::empty(), &[
+            (extent_key(0x10, 0), child(2)),
+            (extent_key(0x10, 0x3000), child(3)),
+            (extent_key(0x12, 0), child(4)),
+        ]);
+        let view = NodeView::parse(Paddr(1), &block, &view_info(BtFlags::empty())).expect("Bad node view");
+        assert_eq!(view.search(&extent(0x0f, 0)).unwrap(), None);
+        assert_eq!(view.search(&extent(0x10, 0x2fff)).unwrap(), Some(0));
+        assert_eq!(view.search(&extent(0x10, 0x3000)).unwrap(), Some(1));
+        assert_eq!(view.search(&extent(0x11, 0)).unwrap(), Some(1));
+        assert_eq!(view.search(&extent(0x13, 0)).unwrap(), Some(2));
+        assert_eq!(view.child(2).unwrap().oid, Oid(4));
+    }
+
+    #[test]
+    fn fixed_size_entries_are_expanded() {
+        let apfs = load_virtual_source(BtFlags::empty());
+        let omap = load_omap(&apfs);
+        let block = apfs.load_block(Paddr(OMAP_TREE_BLOCK as i64)).unwrap();
+        let view = NodeView::parse(Paddr(OMAP_TREE_BLOCK as i64), &block, omap.info()).expect("Bad node view");
+        assert_eq!(view.len(), 4);
+        let record = view.decode_record::<OmapVal>(3).expect("Bad record");
+        assert_eq!((record.key.oid, record.key.xid, record.value.paddr), (Oid(0x502), Xid(5), Paddr(43)));
+        assert_eq!(view.search(&OmapKey { oid: Oid(0x502), xid: Xid(4) }).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn lookup_matches_full_scan() {
+        let (apfs, btree) = load_extent_tree();
+        for (oid, offset) in &[(0x10, 0), (0x10, 0x1800), (0x10, 0x3000), (0x10, 0x5000), (0x11, 0), (0x0f, 0), (0x12, 0)] {
+            let key = extent(*oid, *offset);
+            let expected = scan_lookup(&apfs, &btree, &key);
+            let found = btree.lookup(&apfs, &key).expect("Failed lookup").map(|record| record.key);
+            assert_eq!(found, expected, "{:x}:{:x}", oid, offset);
+        }
+    }
+
+    #[test]
+    fn lookup_resolves_virtual_children() {
+        let apfs = load_virtual_source(BtFlags::empty());
+        let btree = apfs.load_btree_virtual::<ApfsValue>(load_omap(&apfs), Oid(0x500), Xid(5)).expect("Bad b-tree load");
+        match btree.lookup(&apfs, &extent(0x10, 0x3800)).expect("Failed lookup").expect("Missing record").value {
+            ApfsValue::FileExtent(value) => assert_eq!(value.phys_block_num, 300),
+            other => { panic!("Wrong value: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn lookup_rejects_child_at_same_level() {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT, 1, BtFlags::PHYSICAL, &[
+            (extent_key(0x10, 0), child(2)),
+        ]));
+        source.blocks.insert(2, build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::empty(), 1, BtFlags::empty(), &[
+            (extent_key(0x10, 0), child(2)),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<ApfsValue>::load_btree(&apfs, Oid(1), StorageType::Physical).expect("Bad b-tree load");
+        assert!(matches!(btree.lookup(&apfs, &extent(0x10, 0)), Err(ApfsError::Corrupt(_))));
+    }
+}
+
+mod hashed {
+    use crate::tests::{DummySource, build_btree_node};
+    use crate::{BtnFlags, BTREE_NODE_HASH_SIZE_MAX};
+
+    use super::*;
+    use super::record_lookup::*;
+
+    fn hashed_child(oid: u64, fill: u8) -> Option<Vec<u8>> {
+        let mut value = oid.to_le_bytes().to_vec();
+        value.extend_from_slice(&[fill; BTREE_NODE_HASH_SIZE_MAX]);
+        Some(value)
+    }
+
+    fn load_hashed_tree(children: &[(Vec<u8>, Option<Vec<u8>>)]) -> (APFS<DummySource>, Result<Btree<ApfsValue>>) {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(1, build_btree_node(1, 1, ObjectType::Fstree, BtnFlags::ROOT | BtnFlags::HASHED, 1, BtFlags::PHYSICAL | BtFlags::HASHED, children));
+        source.blocks.insert(2, build_btree_node(2, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x10, 0), extent_val(0x1000, 100)),
+        ]));
+        source.blocks.insert(3, build_btree_node(3, 1, ObjectType::Fstree, BtnFlags::LEAF, 0, BtFlags::empty(), &[
+            (extent_key(0x11, 0), extent_val(0x1000, 200)),
+        ]));
+        let apfs = APFS::new(source, 4096);
+        let btree = Btree::<ApfsValue>::load_btree(&apfs, Oid(1), StorageType::Physical);
+        (apfs, btree)
+    }
+
+    #[test]
+    fn index_values_carry_child_hashes() {
+        let (apfs, btree) = load_hashed_tree(&[
+            (extent_key(0x10, 0), hashed_child(2, 0xaa)),
+            (extent_key(0x11, 0), hashed_child(3, 0xbb)),
+        ]);
+        let btree = btree.expect("Bad b-tree load");
+        match btree.root.records {
+            AnyRecords::NonLeaf(ref records, _) => {
+                assert_eq!(records[0].value.oid, Oid(2));
+                assert_eq!(records[0].value.hash, Some([0xaa; BTREE_NODE_HASH_SIZE_MAX]));
+                assert_eq!(records[1].value.hash, Some([0xbb; BTREE_NODE_HASH_SIZE_MAX]));
+            },
+            ref other => { panic!("Wrong records: {:?}", other); },
+        }
+        assert_eq!(btree.iter(&apfs).count(), 2);
+        let key = ApfsKey::import(&mut Cursor::new(extent_key(0x11, 0x800))).unwrap();
+        let found = btree.lookup(&apfs, &key).expect("Failed lookup").map(|record| record.key);
+        assert_eq!(found, scan_lookup(&apfs, &btree, &key));
+        assert!(found.is_some());
+    }
+
+    #[test]
+    fn unhashed_index_value_in_hashed_tree_is_an_error() {
+        let (_, btree) = load_hashed_tree(&[
+            (extent_key(0x10, 0), child(2)),
+            (extent_key(0x11, 0), child(3)),
+        ]);
+        assert!(matches!(btree, Err(ApfsError::Corrupt(_))), "{:?}", btree.err());
+    }
+
+    #[test]
+    fn plain_index_values_have_no_hash() {
+        let (_, btree) = load_extent_tree();
+        match btree.root.records {
+            AnyRecords::NonLeaf(ref records, _) => assert!(records.iter().all(|record| record.value.hash.is_none())),
+            ref other => { panic!("Wrong records: {:?}", other); },
+        }
+    }
+}
+
+mod fext_tree {
+    use crate::tests::{DummySource, build_btree_node, put_u32, put_u64};
+    use crate::{ApfsSuperblock, BtnFlags, Extent, ExtentTree, VolumeIncompatFlags};
+
+    use super::*;
+    use super::record_lookup::*;
+
+    const FEXT_TREE_BLOCK: u64 = 50;
+
+    fn fext_key(private_id: u64, logical_addr: u64) -> Vec<u8> {
+        let mut key = private_id.to_le_bytes().to_vec();
+        key.extend_from_slice(&logical_addr.to_le_bytes());
+        key
+    }
+
+    fn fext_val(length: u64, paddr: u64) -> Option<Vec<u8>> {
+        let mut value = length.to_le_bytes().to_vec();
+        value.extend_from_slice(&paddr.to_le_bytes());
+        Some(value)
+    }
+
+    fn load_fext_source() -> APFS<DummySource> {
+        let mut source = DummySource {
+            block_size: 4096,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(FEXT_TREE_BLOCK, build_btree_node(FEXT_TREE_BLOCK, 1, ObjectType::FextTree, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::PHYSICAL, &[
+            (fext_key(0x10, 0), fext_val(0x2000, 100)),
+            (fext_key(0x10, 0x2000), fext_val(0x1000, 200)),
+            (fext_key(0x11, 0), fext_val(0x1000, 300)),
+        ]));
+        APFS::new(source, 4096)
+    }
+
+    fn volume(sealed: bool) -> ApfsSuperblock {
+        let mut body = vec![0u8; 4096 - 32];
+        let incompat = if sealed { VolumeIncompatFlags::SEALED_VOLUME } else { VolumeIncompatFlags::empty() };
+        put_u64(&mut body, 24, incompat.bits());
+        put_u64(&mut body, 0x408 - 32, FEXT_TREE_BLOCK);
+        put_u32(&mut body, 0x410 - 32, ObjectType::Btree as u32 | StorageType::Physical as u32);
+        ApfsSuperblock::import(&mut Cursor::new(&body[..])).expect("Bad volume superblock")
+    }
+
+    fn extent(logical_addr: u64, length: u64, phys_block_num: u64) -> Extent {
+        Extent { logical_addr, length, phys_block_num, crypto_id: 0 }
+    }
+
+    #[test]
+    fn can_load_fext_tree_generically() {
+        let apfs = load_fext_source();
+        match load_btree_generic(&apfs, Oid(FEXT_TREE_BLOCK), StorageType::Physical).expect("Bad b-tree load") {
+            BtreeTypes::Fext(btree) => assert_eq!(btree.iter(&apfs).count(), 3),
+            other => { panic!("Wrong tree type: {:?}", other); },
+        }
+    }
+
+    #[test]
+    fn fext_lookup_finds_covering_extent() {
+        let apfs = load_fext_source();
+        let btree = Btree::<FextTreeVal>::load_btree(&apfs, Oid(FEXT_TREE_BLOCK), StorageType::Physical).expect("Bad b-tree load");
+        let key = FextTreeKey { private_id: 0x10, logical_addr: 0x2800 };
+        let record = btree.lookup(&apfs, &key).expect("Failed lookup").expect("Missing record");
+        assert_eq!((record.key.logical_addr, record.value.phys_block_num), (0x2000, 200));
+        let key = FextTreeKey { private_id: 0x12, logical_addr: 0 };
+        assert!(btree.lookup(&apfs, &key).expect("Failed lookup").is_none());
+    }
+
+    #[test]
+    fn sealed_volumes_use_the_fext_tree() {
+        let apfs = load_fext_source();
+        let fs_tree = Arc::new(load_extent_tree().1);
+        assert!(volume(true).is_sealed());
+        let extents = ExtentTree::for_volume(&apfs, &volume(true), fs_tree).expect("Bad extent tree");
+        assert!(matches!(extents, ExtentTree::Fext(_)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0).unwrap(), Some(extent(0, 0x2000, 100)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0x2fff).unwrap(), Some(extent(0x2000, 0x1000, 200)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0x3000).unwrap(), None);
+        assert_eq!(extents.extent_at(&apfs, 0x0f, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn other_volumes_use_the_fs_tree() {
+        let (apfs, fs_tree) = load_extent_tree();
+        assert!(!volume(false).is_sealed());
+        let extents = ExtentTree::for_volume(&apfs, &volume(false), Arc::new(fs_tree)).expect("Bad extent tree");
+        assert!(matches!(extents, ExtentTree::FsTree(_)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0x1800).unwrap(), Some(extent(0x1000, 0x2000, 200)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0x3000).unwrap(), Some(extent(0x3000, 0x1000, 300)));
+        assert_eq!(extents.extent_at(&apfs, 0x10, 0x4000).unwrap(), None);
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+
+use super::*;
+
+/* Regions of a node's data area, relative to the end of the node header */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeArea {
+    TableSpace,
+    FreeSpace,
+    Key(usize),
+    Value(usize),
+}
+
+/* A single problem found while validating a tree. Nodes are named by
+   the OID their parent refers to them by, or the root's own OID */
+#[derive(Debug)]
+pub enum BtreeIssue {
+    Unreadable {
+        node: Oid,
+        error: ApfsError,
+    },
+    KeyOrder {
+        node: Oid,
+        index: usize,
+    },
+    KeyOutOfRange {
+        node: Oid,
+        index: usize,
+    },
+    LevelMismatch {
+        node: Oid,
+        expected: u16,
+        found: u16,
+    },
+    EmptyIndexNode {
+        node: Oid,
+    },
+    KvLayout {
+        node: Oid,
+        fixed: bool,
+    },
+    AreaOutOfBounds {
+        node: Oid,
+        area: NodeArea,
+    },
+    AreaOverlap {
+        node: Oid,
+        area: NodeArea,
+        other: NodeArea,
+    },
+    KeyCount {
+        expected: u64,
+        found: u64,
+    },
+    NodeCount {
+        expected: u64,
+        found: u64,
+    },
+    LongestKey {
+        expected: u32,
+        found: u32,
+    },
+    LongestValue {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for BtreeIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BtreeIssue::Unreadable { node, error } => write!(f, "Node {} could not be read: {}", node.0, error),
+            BtreeIssue::KeyOrder { node, index } => write!(f, "Node {}: key {} is not greater than the previous key", node.0, index),
+            BtreeIssue::KeyOutOfRange { node, index } => write!(f, "Node {}: key {} lies outside the range given by its parent", node.0, index),
+            BtreeIssue::LevelMismatch { node, expected, found } => write!(f, "Node {}: level {}, expected {}", node.0, found, expected),
+            BtreeIssue::EmptyIndexNode { node } => write!(f, "Node {}: index node without children", node.0),
+            BtreeIssue::KvLayout { node, fixed } => write!(f, "Node {}: {} entries in a tree with {} size records", node.0,
+                if *fixed { "fixed" } else { "variable" }, if *fixed { "variable" } else { "fixed" }),
+            BtreeIssue::AreaOutOfBounds { node, area } => write!(f, "Node {}: {:?} extends past the end of the node", node.0, area),
+            BtreeIssue::AreaOverlap { node, area, other } => write!(f, "Node {}: {:?} overlaps {:?}", node.0, area, other),
+            BtreeIssue::KeyCount { expected, found } => write!(f, "Tree info records {} keys, found {}", expected, found),
+            BtreeIssue::NodeCount { expected, found } => write!(f, "Tree info records {} nodes, found {}", expected, found),
+            BtreeIssue::LongestKey { expected, found } => write!(f, "Tree info records longest key {}, found {}", expected, found),
+            BtreeIssue::LongestValue { expected, found } => write!(f, "Tree info records longest value {}, found {}", expected, found),
+        }
+    }
+}
+
+/* A node still to be visited, with the level its parent expects it at
+   and the bounds on its keys */
+type PendingNode<K> = (Oid, u16, Option<K>, Option<K>);
+
+struct Validator<'a, S: BlockSource, V: LeafValue> {
+    btree: &'a Btree<V>,
+    apfs: &'a APFS<S>,
+    issues: Vec<BtreeIssue>,
+    /* Totals are only meaningful if every node could be visited */
+    complete: bool,
+    keys: u64,
+    nodes: u64,
+    longest_key: u32,
+    longest_val: u32,
+}
+
+impl<'a, S: BlockSource, V: LeafValue> Validator<'a, S, V> {
+    fn check_layout(&mut self, oid: Oid, node: &BtreeNode<V>) {
+        let info = &self.btree.info;
+        let layout = &node.layout;
+        let fixed = layout.flags.contains(BtnFlags::FIXED_KV_SIZE);
+        if fixed != (info.fixed.key_size != 0) {
+            self.issues.push(BtreeIssue::KvLayout { node: oid, fixed });
+        }
+        let data_len = layout.data_len;
+        let table_end = layout.table_space.off as usize + layout.table_space.len as usize;
+        let free_start = table_end + layout.free_space.off as usize;
+        let free_end = free_start + layout.free_space.len as usize;
+        if table_end > data_len {
+            self.issues.push(BtreeIssue::AreaOutOfBounds { node: oid, area: NodeArea::TableSpace });
+            return;
+        }
+        if free_end > data_len {
+            self.issues.push(BtreeIssue::AreaOutOfBounds { node: oid, area: NodeArea::FreeSpace });
+        }
+        let leaf = layout.flags.contains(BtnFlags::LEAF);
+        let ghosts = info.fixed.flags.contains(BtFlags::ALLOW_GHOSTS);
+        for (idx, kvloc) in layout.toc.iter().enumerate() {
+            let key_start = table_end + kvloc.k.off as usize;
+            let key_end = key_start + kvloc.k.len as usize;
+            if key_end > data_len {
+                self.issues.push(BtreeIssue::AreaOutOfBounds { node: oid, area: NodeArea::Key(idx) });
+            } else if key_start < free_end && key_end > free_start {
+                self.issues.push(BtreeIssue::AreaOverlap { node: oid, area: NodeArea::Key(idx), other: NodeArea::FreeSpace });
+            }
+            if kvloc.k.len as u32 > self.longest_key {
+                self.longest_key = kvloc.k.len as u32;
+            }
+            if ghosts && kvloc.v.off == BTOFF_INVALID {
+                continue;
+            }
+            if leaf && kvloc.v.len as u32 > self.longest_val {
+                self.longest_val = kvloc.v.len as u32;
+            }
+            if kvloc.v.off as usize > data_len || kvloc.v.len > kvloc.v.off {
+                self.issues.push(BtreeIssue::AreaOutOfBounds { node: oid, area: NodeArea::Value(idx) });
+                continue;
+            }
+            let value_start = data_len - kvloc.v.off as usize;
+            let value_end = value_start + kvloc.v.len as usize;
+            if value_start < table_end {
+                self.issues.push(BtreeIssue::AreaOverlap { node: oid, area: NodeArea::Value(idx), other: NodeArea::TableSpace });
+            } else if value_start < free_end && value_end > free_start {
+                self.issues.push(BtreeIssue::AreaOverlap { node: oid, area: NodeArea::Value(idx), other: NodeArea::FreeSpace });
+            }
+        }
+    }
+
+    fn check_keys<'k>(&mut self, oid: Oid, keys: impl Iterator<Item = &'k V::Key>, lower: Option<&V::Key>, upper: Option<&V::Key>) where V::Key: 'k {
+        let mut previous: Option<&V::Key> = None;
+        for (idx, key) in keys.enumerate() {
+            if previous.is_some_and(|previous| previous >= key) {
+                self.issues.push(BtreeIssue::KeyOrder { node: oid, index: idx });
+            }
+            if lower.is_some_and(|lower| key < lower) || upper.is_some_and(|upper| key >= upper) {
+                self.issues.push(BtreeIssue::KeyOutOfRange { node: oid, index: idx });
+            }
+            previous = Some(key);
+        }
+    }
+
+    /* The tree is walked depth first from a work stack rather than by
+       recursion, since the depth comes from the image. Each child must
+       sit one level below its parent and hold only keys from its own
+       index key up to the next one. A child at the wrong level is not
+       descended into, so a loop of nodes cannot be followed */
+    fn walk(&mut self) {
+        let root = Arc::clone(&self.btree.root);
+        let mut pending = vec![];
+        self.visit(root.header.oid, &root, None, None, &mut pending);
+        while let Some((oid, level, lower, upper)) = pending.pop() {
+            let node = match self.btree.load_child_node(self.apfs, oid) {
+                Ok(node) => node,
+                Err(error) => {
+                    self.issues.push(BtreeIssue::Unreadable { node: oid, error });
+                    self.complete = false;
+                    continue;
+                },
+            };
+            if node.layout.level != level {
+                self.issues.push(BtreeIssue::LevelMismatch { node: oid, expected: level, found: node.layout.level });
+                self.complete = false;
+                continue;
+            }
+            self.visit(oid, &node, lower.as_ref(), upper.as_ref(), &mut pending);
+        }
+    }
+
+    /* Check a node and queue its children, first child on top */
+    fn visit(&mut self, oid: Oid, node: &BtreeNode<V>, lower: Option<&V::Key>, upper: Option<&V::Key>, pending: &mut Vec<PendingNode<V::Key>>) {
+        self.nodes += 1;
+        self.check_layout(oid, node);
+        let level = node.layout.level;
+        match node.records {
+            AnyRecords::Leaf(ref records) => {
+                if level != 0 {
+                    self.issues.push(BtreeIssue::LevelMismatch { node: oid, expected: 0, found: level });
+                }
+                self.keys += records.len() as u64;
+                self.check_keys(oid, records.iter().map(|record| &record.key), lower, upper);
+            },
+            AnyRecords::NonLeaf(ref records, _) => {
+                self.check_keys(oid, records.iter().map(|record| &record.key), lower, upper);
+                if records.is_empty() {
+                    self.issues.push(BtreeIssue::EmptyIndexNode { node: oid });
+                }
+                if level == 0 {
+                    self.issues.push(BtreeIssue::LevelMismatch { node: oid, expected: 1, found: level });
+                    self.complete = false;
+                    return;
+                }
+                for (idx, record) in records.iter().enumerate().rev() {
+                    let next = records.get(idx + 1).map(|record| &record.key).or(upper);
+                    pending.push((record.value.oid, level - 1, Some(record.key.clone()), next.cloned()));
+                }
+            },
+        }
+    }
+
+    fn check_totals(&mut self) {
+        let info = &self.btree.info;
+        if self.complete {
+            if info.key_count != self.keys {
+                self.issues.push(BtreeIssue::KeyCount { expected: info.key_count, found: self.keys });
+            }
+            if info.node_count != self.nodes {
+                self.issues.push(BtreeIssue::NodeCount { expected: info.node_count, found: self.nodes });
+            }
+        }
+        if self.longest_key > info.longest_key {
+            self.issues.push(BtreeIssue::LongestKey { expected: info.longest_key, found: self.longest_key });
+        }
+        if self.longest_val > info.longest_val {
+            self.issues.push(BtreeIssue::LongestValue { expected: info.longest_val, found: self.longest_val });
+        }
+    }
+}
+
+impl<V: LeafValue> Btree<V> {
+    /* Walk the whole tree and check it against its info, returning
+       every problem found rather than stopping at the first one */
+    pub fn validate<S: BlockSource>(&self, apfs: &APFS<S>) -> Vec<BtreeIssue> {
+        let mut validator = Validator {
+            btree: self,
+            apfs,
+            issues: vec![],
+            complete: true,
+            keys: 0,
+            nodes: 0,
+            longest_key: 0,
+            longest_val: 0,
+        };
+        validator.walk();
+        validator.check_totals();
+        validator.issues
+    }
+}
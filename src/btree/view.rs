@@ -0,0 +1,198 @@
+use super::*;
+
+use crate::{ObjPhys, BtreeNodeObject, OBJ_PHYS_SIZE, BTREE_NODE_PHYS_SIZE};
+
+/* A B-tree node read in place from its block. The table of contents
+   is parsed one entry at a time and keys and values are handed out as
+   slices of the block, so only the records asked for are decoded */
+#[derive(Debug)]
+pub struct NodeView<'a> {
+    header: ObjPhys,
+    flags: BtnFlags,
+    level: u16,
+    nkeys: u32,
+    toc: &'a [u8],
+    keys: &'a [u8],
+    values: &'a [u8],
+    fixed: Option<(u16, u16)>,
+    ghosts: bool,
+    hashed: bool,
+}
+
+impl<'a> NodeView<'a> {
+    /* The block is not checksummed here, only split into its areas.
+       Root nodes keep the tree info at the end of the value area */
+    pub fn parse(addr: Paddr, block: &'a [u8], info: &BtreeInfo) -> Result<Self> {
+        let header = ObjPhys::parse(block)?;
+        match header.r#type.r#type() {
+            ObjectType::Btree | ObjectType::BtreeNode => (),
+            found => {
+                return Err(ApfsError::WrongObjectType { paddr: addr, expected: ObjectType::BtreeNode, found });
+            },
+        }
+        let body = &block[OBJ_PHYS_SIZE..];
+        let node = BtreeNodePhys::import(&mut body.get(..BTREE_NODE_PHYS_SIZE).ok_or(ApfsError::Truncated)?)?;
+        Self::new(header, &node, &body[BTREE_NODE_PHYS_SIZE..], info)
+    }
+
+    /* A view of a node already loaded as an object, reading its data
+       wherever the object keeps it */
+    pub fn from_object(object: &'a BtreeNodeObject<'_>, info: &BtreeInfo) -> Result<Self> {
+        Self::new(object.header.clone(), &object.body, &object.body.data, info)
+    }
+
+    fn new(header: ObjPhys, node: &BtreeNodePhys<'_>, data: &'a [u8], info: &BtreeInfo) -> Result<Self> {
+        let info_size = if header.r#type.r#type() == ObjectType::Btree { BTREE_INFO_SIZE } else { 0 };
+        let values = data.len().checked_sub(info_size)
+            .map(|end| &data[..end])
+            .ok_or_else(|| ApfsError::Corrupt(format!("B-tree root {} too small for its info", header.oid.0)))?;
+        let toc = node_slice(values, node.table_space.off as usize, node.table_space.len as usize)?;
+        let keys = &values[node.table_space.off as usize + node.table_space.len as usize..];
+        let fixed = if node.flags.contains(BtnFlags::FIXED_KV_SIZE) {
+            let val_size = if node.flags.contains(BtnFlags::LEAF) {
+                info.fixed.val_size as u16
+            } else {
+                OidValue::size(info.fixed.flags.contains(BtFlags::HASHED)) as u16
+            };
+            Some((info.fixed.key_size as u16, val_size))
+        } else {
+            None
+        };
+        Ok(NodeView {
+            header,
+            flags: node.flags,
+            level: node.level,
+            nkeys: node.nkeys,
+            toc,
+            keys,
+            values,
+            fixed,
+            ghosts: info.fixed.flags.contains(BtFlags::ALLOW_GHOSTS),
+            hashed: info.fixed.flags.contains(BtFlags::HASHED),
+        })
+    }
+
+    pub fn header(&self) -> &ObjPhys {
+        &self.header
+    }
+
+    pub fn flags(&self) -> BtnFlags {
+        self.flags
+    }
+
+    pub fn level(&self) -> u16 {
+        self.level
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.flags.contains(BtnFlags::LEAF)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nkeys as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nkeys == 0
+    }
+
+    pub fn entry(&self, idx: usize) -> Result<KVloc> {
+        if idx >= self.len() {
+            return Err(ApfsError::Corrupt(format!("B-tree entry {} beyond {} keys", idx, self.nkeys)));
+        }
+        Ok(match self.fixed {
+            Some((key_size, val_size)) => {
+                let kvoff = KVoff::import(&mut node_slice(self.toc, idx * 4, 4)?)?;
+                KVloc {
+                    k: Nloc { off: kvoff.k, len: key_size },
+                    v: Nloc { off: kvoff.v, len: val_size },
+                }
+            },
+            None => KVloc::import(&mut node_slice(self.toc, idx * 8, 8)?)?,
+        })
+    }
+
+    pub fn key(&self, idx: usize) -> Result<&'a [u8]> {
+        let kvloc = self.entry(idx)?;
+        node_slice(self.keys, kvloc.k.off as usize, kvloc.k.len as usize)
+    }
+
+    /* Ghost records have no value */
+    pub fn value(&self, idx: usize) -> Result<Option<&'a [u8]>> {
+        let kvloc = self.entry(idx)?;
+        if self.ghosts && kvloc.v.off == BTOFF_INVALID {
+            return Ok(None);
+        }
+        let start = self.values.len().checked_sub(kvloc.v.off as usize)
+            .ok_or_else(|| ApfsError::Corrupt(format!("B-tree value offset {} exceeds node size {}", kvloc.v.off, self.values.len())))?;
+        node_slice(self.values, start, kvloc.v.len as usize).map(Some)
+    }
+
+    pub fn decode_key<K: Key>(&self, idx: usize) -> Result<K> {
+        K::import(&mut self.key(idx)?)
+    }
+
+    pub fn decode_record<V: LeafValue>(&self, idx: usize) -> Result<LeafRecord<V>> {
+        let key = self.decode_key(idx)?;
+        let value = match self.value(idx)? {
+            Some(value) if !value.is_empty() => V::import(&mut &value[..], &key)?,
+            _ => V::ghost_value()?,
+        };
+        Ok(LeafRecord { key, value })
+    }
+
+    pub fn child(&self, idx: usize) -> Result<OidValue> {
+        let value = self.value(idx)?.ok_or_else(|| ApfsError::Corrupt(format!("B-tree index entry {} has no child", idx)))?;
+        OidValue::parse(value, self.hashed)
+    }
+
+    /* Binary search for the last entry not above the key, using the
+       same matching rules as lookups on decoded nodes */
+    pub fn search<K: Key>(&self, key: &K) -> Result<Option<usize>> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if key.r#match(&self.decode_key(mid)?) == Ordering::Less {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Ok(low.checked_sub(1))
+    }
+}
+
+impl<V: LeafValue> Btree<V> {
+    /* Point lookup that decodes only the keys the search visits and
+       the record found. Nodes below the root are loaded as objects, so
+       they come from the object cache, or are read in place when the
+       source lends out their blocks */
+    pub fn lookup<S: BlockSource>(&self, apfs: &APFS<S>, key: &V::Key) -> Result<Option<LeafRecord<V>>> {
+        let mut oid = match self.root.get_record(key) {
+            Some(AnyRecord::NonLeaf(record, _)) => record.value.oid,
+            Some(AnyRecord::Leaf(record)) => return Ok(Some(record)),
+            None => return Ok(None),
+        };
+        let mut level = self.root.layout.level;
+        loop {
+            let object = self.load_child_object(apfs, oid)?;
+            if let BtreeRawObject::BtreeRoot(..) = object {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::BtreeNode, found: ObjectType::Btree });
+            }
+            let view = NodeView::from_object(object.node(), &self.info)?;
+            if view.level() >= level {
+                return Err(ApfsError::Corrupt(format!("B-tree node {} at level {} below level {}", oid.0, view.level(), level)));
+            }
+            level = view.level();
+            let idx = match view.search(key)? {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+            if view.is_leaf() {
+                let record = view.decode_record::<V>(idx)?;
+                return Ok(if key.r#match(&record.key) == Ordering::Equal { Some(record) } else { None });
+            }
+            oid = view.child(idx)?.oid;
+        }
+    }
+}
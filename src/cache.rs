@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::{APFSObject, Paddr};
+
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+struct CacheEntry {
+    object: Arc<APFSObject<'static>>,
+    size: usize,
+    stamp: u64,
+}
+
+/* Least recently used cache of checksum verified objects, bounded by
+   the size in bytes of the blocks they were decoded from. Objects are
+   shared with their callers rather than copied out on every hit */
+pub struct ObjectCache {
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    entries: HashMap<Paddr, CacheEntry>,
+    lru: BTreeMap<u64, Paddr>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ObjectCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, paddr: Paddr) -> Option<Arc<APFSObject<'static>>> {
+        match self.entries.get_mut(&paddr) {
+            Some(entry) => {
+                self.clock += 1;
+                self.lru.remove(&entry.stamp);
+                entry.stamp = self.clock;
+                self.lru.insert(entry.stamp, paddr);
+                self.hits += 1;
+                Some(Arc::clone(&entry.object))
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, paddr: Paddr, object: Arc<APFSObject<'static>>, size: usize) {
+        if size > self.capacity {
+            return;
+        }
+        self.remove(paddr);
+        self.evict(self.capacity - size);
+        self.clock += 1;
+        self.lru.insert(self.clock, paddr);
+        self.entries.insert(paddr, CacheEntry { object, size, stamp: self.clock });
+        self.size += size;
+    }
+
+    pub fn remove(&mut self, paddr: Paddr) {
+        if let Some(entry) = self.entries.remove(&paddr) {
+            self.lru.remove(&entry.stamp);
+            self.size -= entry.size;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(capacity);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            size: self.size,
+            capacity: self.capacity,
+        }
+    }
+
+    fn evict(&mut self, limit: usize) {
+        while self.size > limit {
+            let (_, paddr) = match self.lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(entry) = self.entries.remove(&paddr) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{NxEfiJumpstart, NxEfiJumpstartObject, ObjPhys};
+
+    use std::io::Cursor;
+
+    fn test_object(oid: u8) -> Arc<APFSObject<'static>> {
+        let mut block = [0u8; 256];
+        block[8] = oid;
+        block[24] = 0x14;
+        let mut cursor = Cursor::new(&block[..]);
+        Arc::new(APFSObject::EfiJumpstart(NxEfiJumpstartObject {
+            header: ObjPhys::import(&mut cursor).unwrap(),
+            body: NxEfiJumpstart::import(&mut cursor).unwrap(),
+        }))
+    }
+
+    #[test]
+    fn cache_counts_hits_and_misses() {
+        let mut cache = ObjectCache::new(8192);
+        assert!(cache.get(Paddr(1)).is_none());
+        cache.insert(Paddr(1), test_object(1), 4096);
+        assert_eq!(cache.get(Paddr(1)).unwrap().header().oid.0, 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, size: 4096, capacity: 8192 });
+    }
+
+    #[test]
+    fn cache_hits_share_the_object() {
+        let mut cache = ObjectCache::new(8192);
+        let object = test_object(1);
+        cache.insert(Paddr(1), object.clone(), 4096);
+        assert!(Arc::ptr_eq(&cache.get(Paddr(1)).unwrap(), &object));
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = ObjectCache::new(8192);
+        cache.insert(Paddr(1), test_object(1), 4096);
+        cache.insert(Paddr(2), test_object(2), 4096);
+        assert!(cache.get(Paddr(1)).is_some());
+        cache.insert(Paddr(3), test_object(3), 4096);
+        assert!(cache.get(Paddr(2)).is_none());
+        assert!(cache.get(Paddr(1)).is_some());
+        assert!(cache.get(Paddr(3)).is_some());
+        assert_eq!(cache.stats().size, 8192);
+    }
+
+    #[test]
+    fn cache_shrinks_to_new_capacity() {
+        let mut cache = ObjectCache::new(8192);
+        cache.insert(Paddr(1), test_object(1), 4096);
+        cache.insert(Paddr(2), test_object(2), 4096);
+        cache.set_capacity(4096);
+        assert!(cache.get(Paddr(1)).is_none());
+        assert!(cache.get(Paddr(2)).is_some());
+        cache.set_capacity(0);
+        assert_eq!(cache.stats().size, 0);
+        cache.insert(Paddr(1), test_object(1), 4096);
+        assert!(cache.get(Paddr(1)).is_none());
+    }
+}
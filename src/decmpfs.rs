@@ -0,0 +1,195 @@
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+
+use crate::{DecmpfsHeader, DecmpfsType, DECMPFS_HEADER_SIZE, DECMPFS_MAGIC};
+use crate::error::{ApfsError, Result};
+use crate::{lzfse, lzvn};
+
+/* Each block of a resource fork decodes to this much of the file */
+const DECMPFS_BLOCK_SIZE: u64 = 0x10000;
+
+/* Where a resource fork is read from, either its own data stream or
+   the attribute value when it is small enough to be embedded */
+pub trait ForkSource: Read + Seek {}
+
+impl<T: Read + Seek> ForkSource for T {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Algorithm {
+    Uncompressed,
+    Zlib,
+    Lzvn,
+    Lzfse,
+}
+
+/* The contents of a file compressed by decmpfs. Data following the
+   header in the attribute is small and decoded up front, while data in
+   the resource fork is split into blocks decoded as they are read */
+pub struct Decmpfs<'a> {
+    size: u64,
+    algorithm: Algorithm,
+    contents: Contents<'a>,
+}
+
+enum Contents<'a> {
+    Inline(Vec<u8>),
+    Fork {
+        fork: Box<dyn ForkSource + 'a>,
+        /* Offset and length of each compressed block in the fork */
+        blocks: Vec<(u64, u64)>,
+        cached: Option<(usize, Vec<u8>)>,
+    },
+}
+
+impl<'a> Decmpfs<'a> {
+    /* Parse the header of a decmpfs attribute */
+    pub fn header(xattr: &[u8]) -> Result<DecmpfsHeader> {
+        let header = DecmpfsHeader::import(&mut &xattr[..])?;
+        if header.compression_magic != DECMPFS_MAGIC {
+            return Err(ApfsError::Corrupt(format!("Bad decmpfs magic 0x{:08x}", header.compression_magic)));
+        }
+        Ok(header)
+    }
+
+    /* The resource fork is only needed by the types that keep their
+       data there */
+    pub fn new(xattr: &[u8], fork: Option<Box<dyn ForkSource + 'a>>) -> Result<Self> {
+        let header = Self::header(xattr)?;
+        let size = header.uncompressed_size;
+        let algorithm = match header.compression_type {
+            DecmpfsType::UncompressedAttr => Algorithm::Uncompressed,
+            DecmpfsType::ZlibAttr | DecmpfsType::ZlibRsrc => Algorithm::Zlib,
+            DecmpfsType::LzvnAttr | DecmpfsType::LzvnRsrc => Algorithm::Lzvn,
+            DecmpfsType::LzfseAttr | DecmpfsType::LzfseRsrc => Algorithm::Lzfse,
+        };
+        let contents = if header.compression_type.in_resource_fork() {
+            let mut fork = fork.ok_or_else(|| ApfsError::Corrupt(format!("decmpfs type {:?} without a resource fork", header.compression_type)))?;
+            let count = size.div_ceil(DECMPFS_BLOCK_SIZE);
+            let blocks = if algorithm == Algorithm::Zlib {
+                zlib_block_table(&mut *fork, count)?
+            } else {
+                offset_block_table(&mut *fork, count)?
+            };
+            Contents::Fork { fork, blocks, cached: None }
+        } else {
+            let expected = usize::try_from(size)
+                .map_err(|_| ApfsError::Corrupt(format!("decmpfs attribute data of {} bytes", size)))?;
+            Contents::Inline(decode_block(algorithm, &xattr[DECMPFS_HEADER_SIZE..], expected)?)
+        };
+        Ok(Decmpfs { size, algorithm, contents })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /* Read from the decoded data, stopping at the end of a block */
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let (data, start) = match self.contents {
+            Contents::Inline(ref data) => (&data[..], offset as usize),
+            Contents::Fork { .. } => {
+                let index = (offset / DECMPFS_BLOCK_SIZE) as usize;
+                (self.block(index)?, (offset % DECMPFS_BLOCK_SIZE) as usize)
+            },
+        };
+        let count = min(buf.len(), data.len().saturating_sub(start));
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    /* The decoded block is kept for the reads that follow */
+    fn block(&mut self, index: usize) -> Result<&[u8]> {
+        let (fork, blocks, cached) = match self.contents {
+            Contents::Fork { ref mut fork, ref blocks, ref mut cached } => (fork, blocks, cached),
+            Contents::Inline(_) => unreachable!(),
+        };
+        if cached.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let (offset, length) = *blocks.get(index)
+                .ok_or_else(|| ApfsError::Corrupt(format!("decmpfs block {} beyond {} blocks", index, blocks.len())))?;
+            if length > 2 * DECMPFS_BLOCK_SIZE {
+                return Err(ApfsError::Corrupt(format!("decmpfs block {} of {} bytes", index, length)));
+            }
+            let mut data = vec![0; length as usize];
+            fork.seek(SeekFrom::Start(offset))?;
+            fork.read_exact(&mut data)?;
+            let expected = min(DECMPFS_BLOCK_SIZE, self.size - index as u64 * DECMPFS_BLOCK_SIZE) as usize;
+            *cached = Some((index, decode_block(self.algorithm, &data, expected)?));
+        }
+        Ok(&cached.as_ref().unwrap().1)
+    }
+}
+
+/* A zlib resource fork is a classic resource fork whose first resource
+   holds the block table, with offsets from the start of the table */
+fn zlib_block_table(fork: &mut dyn ForkSource, count: u64) -> Result<Vec<(u64, u64)>> {
+    fork.seek(SeekFrom::Start(0))?;
+    let data_offset = fork.read_u32::<BigEndian>()? as u64;
+    fork.seek(SeekFrom::Start(data_offset + 4))?;
+    let num_blocks = fork.read_u32::<LittleEndian>()? as u64;
+    if num_blocks < count {
+        return Err(ApfsError::Corrupt(format!("decmpfs resource fork has {} blocks for {}", num_blocks, count)));
+    }
+    let mut blocks = vec![];
+    for _ in 0..count {
+        let offset = fork.read_u32::<LittleEndian>()? as u64;
+        let length = fork.read_u32::<LittleEndian>()? as u64;
+        blocks.push((data_offset + 4 + offset, length));
+    }
+    Ok(blocks)
+}
+
+/* LZVN and LZFSE resource forks start with the offset of each block
+   and of the end of the last, the first offset being the table size */
+fn offset_block_table(fork: &mut dyn ForkSource, count: u64) -> Result<Vec<(u64, u64)>> {
+    fork.seek(SeekFrom::Start(0))?;
+    let table_size = fork.read_u32::<LittleEndian>()? as u64;
+    if table_size < (count + 1) * 4 {
+        return Err(ApfsError::Corrupt(format!("decmpfs resource fork table of {} bytes for {} blocks", table_size, count)));
+    }
+    let mut blocks = vec![];
+    let mut offset = table_size;
+    for _ in 0..count {
+        let end = fork.read_u32::<LittleEndian>()? as u64;
+        let length = end.checked_sub(offset)
+            .ok_or_else(|| ApfsError::Corrupt(format!("decmpfs block offsets {} and {} out of order", offset, end)))?;
+        blocks.push((offset, length));
+        offset = end;
+    }
+    Ok(blocks)
+}
+
+/* Blocks that would not shrink are stored as is after a marker byte:
+   one with all low bits set for zlib, which no zlib header has, and the
+   end of stream opcode for LZVN */
+fn decode_block(algorithm: Algorithm, data: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut decoded = match (algorithm, data.first()) {
+        (_, _) if expected == 0 => vec![],
+        (Algorithm::Uncompressed, _) => data.to_vec(),
+        (Algorithm::Zlib, Some(marker)) if marker & 0x0f == 0x0f => data[1..].to_vec(),
+        (Algorithm::Zlib, _) => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, expected)
+            .map_err(|err| ApfsError::Corrupt(format!("decmpfs zlib data: {}", err)))?,
+        (Algorithm::Lzvn, Some(0x06)) => data[1..].to_vec(),
+        (Algorithm::Lzvn, _) => {
+            let mut decoded = vec![];
+            lzvn::decode(data, &mut decoded, expected)?;
+            decoded
+        },
+        (Algorithm::Lzfse, _) => {
+            let mut decoded = vec![];
+            lzfse::decode(data, &mut decoded, expected)?;
+            decoded
+        },
+    };
+    if decoded.len() < expected {
+        return Err(ApfsError::Corrupt(format!("decmpfs data decoded to {} bytes instead of {}", decoded.len(), expected)));
+    }
+    decoded.truncate(expected);
+    Ok(decoded)
+}
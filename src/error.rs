@@ -0,0 +1,167 @@
+use std::fmt;
+use std::io;
+
+use crate::{JObjTypes, ObjectType, Oid, Paddr, StorageType, Xid};
+
+#[derive(Debug)]
+pub enum ApfsError {
+    Io(io::Error),
+    Truncated,
+    BadChecksum {
+        paddr: Paddr,
+        stored: u64,
+        computed: u64,
+    },
+    WrongObjectType {
+        paddr: Paddr,
+        expected: ObjectType,
+        found: ObjectType,
+    },
+    /* Objects loaded by a virtual or ephemeral oid have no block address
+       at hand, so those are reported by oid */
+    WrongObjectTypeForOid {
+        oid: Oid,
+        expected: ObjectType,
+        found: ObjectType,
+    },
+    UnsupportedObjectType {
+        paddr: Paddr,
+        found: ObjectType,
+    },
+    UnsupportedStorage {
+        oid: Oid,
+        storage: StorageType,
+    },
+    ObjectNotFound {
+        oid: Oid,
+        xid: Xid,
+    },
+    ObjectDeleted {
+        oid: Oid,
+        xid: Xid,
+    },
+    SnapshotNotFound {
+        xid: Xid,
+    },
+    SnapshotDeleted {
+        xid: Xid,
+    },
+    VolumeNotFound(String),
+    PathNotFound(String),
+    NotADirectory(String),
+    ObjectEncrypted {
+        oid: Oid,
+        paddr: Paddr,
+    },
+    ObjectNoHeader {
+        oid: Oid,
+        paddr: Paddr,
+    },
+    OidMismatch {
+        paddr: Paddr,
+        expected: Oid,
+        found: Oid,
+    },
+    UnsupportedBtreeType(ObjectType),
+    UnsupportedRecordType(JObjTypes),
+    UnknownObjectType(u32),
+    UnknownStorageType(u32),
+    UnknownFlags {
+        r#type: &'static str,
+        bits: u64,
+    },
+    UnknownValue {
+        r#type: &'static str,
+        value: u64,
+    },
+    InvalidString,
+    Corrupt(String),
+}
+
+pub type Result<T> = std::result::Result<T, ApfsError>;
+
+impl fmt::Display for ApfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApfsError::Io(err) => write!(f, "I/O error: {}", err),
+            ApfsError::Truncated => write!(f, "Structure truncated"),
+            ApfsError::BadChecksum { paddr, stored, computed } =>
+                write!(f, "Bad object checksum at block {}: stored 0x{:016x}, computed 0x{:016x}", paddr.0, stored, computed),
+            ApfsError::WrongObjectType { paddr, expected, found } =>
+                write!(f, "Wrong object type at block {}: expected {:?}, found {:?}", paddr.0, expected, found),
+            ApfsError::WrongObjectTypeForOid { oid, expected, found } =>
+                write!(f, "Wrong object type for object {}: expected {:?}, found {:?}", oid.0, expected, found),
+            ApfsError::UnsupportedObjectType { paddr, found } =>
+                write!(f, "Unsupported object type at block {}: {:?}", paddr.0, found),
+            ApfsError::UnsupportedStorage { oid, storage } =>
+                write!(f, "Unsupported storage type for object {}: {:?}", oid.0, storage),
+            ApfsError::ObjectNotFound { oid, xid } =>
+                write!(f, "Object {} not found in object map at transaction {}", oid.0, xid.0),
+            ApfsError::ObjectDeleted { oid, xid } =>
+                write!(f, "Object {} deleted from object map at transaction {}", oid.0, xid.0),
+            ApfsError::SnapshotNotFound { xid } =>
+                write!(f, "No object map snapshot at transaction {}", xid.0),
+            ApfsError::SnapshotDeleted { xid } =>
+                write!(f, "Object map snapshot at transaction {} has been deleted", xid.0),
+            ApfsError::VolumeNotFound(what) => write!(f, "No volume with {}", what),
+            ApfsError::PathNotFound(path) => write!(f, "No such file or directory: {}", path),
+            ApfsError::NotADirectory(path) => write!(f, "Not a directory: {}", path),
+            ApfsError::ObjectEncrypted { oid, paddr } =>
+                write!(f, "Object {} at block {} is encrypted", oid.0, paddr.0),
+            ApfsError::ObjectNoHeader { oid, paddr } =>
+                write!(f, "Object {} at block {} has no object header", oid.0, paddr.0),
+            ApfsError::OidMismatch { paddr, expected, found } =>
+                write!(f, "Wrong object ID at block {}: expected {}, found {}", paddr.0, expected.0, found.0),
+            ApfsError::UnsupportedBtreeType(subtype) => write!(f, "Unsupported B-tree type: {:?}", subtype),
+            ApfsError::UnsupportedRecordType(r#type) => write!(f, "Unsupported file-system record type: {:?}", r#type),
+            ApfsError::UnknownObjectType(value) => write!(f, "Unknown object type: 0x{:08x}", value),
+            ApfsError::UnknownStorageType(value) => write!(f, "Unknown storage type: 0x{:08x}", value),
+            ApfsError::UnknownFlags { r#type, bits } => write!(f, "Unknown {} bits: 0x{:x}", r#type, bits),
+            ApfsError::UnknownValue { r#type, value } => write!(f, "Unknown {} value: {}", r#type, value),
+            ApfsError::InvalidString => write!(f, "Invalid UTF-8 string"),
+            ApfsError::Corrupt(reason) => write!(f, "Corrupt structure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ApfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApfsError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/* Running out of bytes while decoding an in-memory structure means the
+   structure is truncated, anything else came from the underlying source */
+impl From<io::Error> for ApfsError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => ApfsError::Truncated,
+            _ => ApfsError::Io(err),
+        }
+    }
+}
+
+impl From<ApfsError> for io::Error {
+    fn from(err: ApfsError) -> Self {
+        match err {
+            ApfsError::Io(err) => err,
+            ApfsError::UnsupportedObjectType { .. } |
+            ApfsError::UnsupportedStorage { .. } |
+            ApfsError::ObjectEncrypted { .. } |
+            ApfsError::ObjectNoHeader { .. } |
+            ApfsError::UnsupportedBtreeType(_) |
+            ApfsError::UnsupportedRecordType(_) => io::Error::new(io::ErrorKind::Unsupported, err),
+            ApfsError::ObjectNotFound { .. } |
+            ApfsError::ObjectDeleted { .. } |
+            ApfsError::SnapshotNotFound { .. } |
+            ApfsError::SnapshotDeleted { .. } |
+            ApfsError::VolumeNotFound(_) |
+            ApfsError::PathNotFound(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            ApfsError::NotADirectory(_) => io::Error::new(io::ErrorKind::NotADirectory, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
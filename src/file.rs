@@ -0,0 +1,114 @@
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{APFS, BlockSource, ExtentTree, Oid, Paddr};
+use crate::error::ApfsError;
+use crate::decmpfs::Decmpfs;
+
+/* Reads the data stream of a file through its extents. Extents with
+   no physical block and ranges no extent covers are sparse and read as
+   zeros, and reads stop at the size recorded in the data stream.
+   Compressed files are read through their decoded contents instead */
+pub struct FileReader<'a, S: BlockSource> {
+    apfs: &'a APFS<S>,
+    contents: Contents<'a>,
+    size: u64,
+    position: u64,
+}
+
+enum Contents<'a> {
+    Stream {
+        extents: &'a ExtentTree,
+        private_id: u64,
+    },
+    Compressed(Decmpfs<'a>),
+}
+
+impl<'a, S: BlockSource> FileReader<'a, S> {
+    pub fn new(apfs: &'a APFS<S>, extents: &'a ExtentTree, private_id: u64, size: u64) -> Self {
+        FileReader { apfs, contents: Contents::Stream { extents, private_id }, size, position: 0 }
+    }
+
+    pub(crate) fn compressed(apfs: &'a APFS<S>, decmpfs: Decmpfs<'a>) -> Self {
+        FileReader { apfs, size: decmpfs.len(), contents: Contents::Compressed(decmpfs), position: 0 }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(self.contents, Contents::Compressed(_))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn read_extent(&self, extents: &ExtentTree, private_id: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.apfs.block_size as u64;
+        let wanted = min(buf.len() as u64, self.size - self.position);
+        let extent = match extents.extent_at(self.apfs, private_id, self.position)? {
+            Some(extent) => extent,
+            None => {
+                /* Without the next extent at hand only fill to the end
+                   of the block and look again from there */
+                let count = min(wanted, block_size - self.position % block_size) as usize;
+                buf[..count].fill(0);
+                return Ok(count);
+            },
+        };
+        let offset = self.position - extent.logical_addr;
+        let count = min(wanted, extent.length - offset) as usize;
+        if extent.phys_block_num == 0 {
+            buf[..count].fill(0);
+            return Ok(count);
+        }
+        /* Encrypted extents would only read back as ciphertext */
+        if extent.crypto_id != 0 {
+            return Err(ApfsError::ObjectEncrypted { oid: Oid(private_id), paddr: Paddr(extent.phys_block_num as i64) }.into());
+        }
+        let mut done = 0;
+        while done < count {
+            let offset = offset + done as u64;
+            let block = extent.phys_block_num.checked_add(offset / block_size)
+                .filter(|block| *block <= i64::MAX as u64)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Extent at block {} out of range", extent.phys_block_num)))?;
+            let data = self.apfs.load_block(Paddr(block as i64))?;
+            let start = (offset % block_size) as usize;
+            let length = min(count - done, data.len() - start);
+            buf[done..done + length].copy_from_slice(&data[start..start + length]);
+            done += length;
+        }
+        Ok(count)
+    }
+}
+
+impl<'a, S: BlockSource> Read for FileReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let count = match self.contents {
+            Contents::Stream { extents, private_id } => self.read_extent(extents, private_id, buf)?,
+            Contents::Compressed(ref mut decmpfs) => {
+                let wanted = min(buf.len() as u64, self.size - self.position) as usize;
+                decmpfs.read_at(self.position, &mut buf[..wanted])?
+            },
+        };
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a, S: BlockSource> Seek for FileReader<'a, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative or overflowing position"))?;
+        Ok(self.position)
+    }
+}
@@ -37,31 +37,52 @@ mod test {
         file.read_exact(&mut buffer).unwrap();
         assert_eq!(fletcher64(&buffer[8..]), 0x109452a1ced0d551);
     }
+
+    #[test]
+    fn test_fletcher64_odd_size() {
+        let buffer = &include_bytes!("../testdata/volume_superblock.1")[..];
+        assert_eq!(fletcher64(&buffer[8..11]), fletcher64(&[buffer[8], buffer[9], buffer[10], 0]));
+        assert_eq!(fletcher64(&[]), fletcher64(&[0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_fletcher64_large_object() {
+        let buffer = vec![0xffu8; 1 << 20];
+        let (mut lower, mut upper) = (0u64, 0u64);
+        for _ in 0..buffer.len() / 4 {
+            lower = (lower + 0xffffffff) % 0xffffffff;
+            upper = (upper + lower) % 0xffffffff;
+        }
+        let low = 0xffffffff - ((lower + upper) % 0xffffffff);
+        let high = 0xffffffff - ((lower + low) % 0xffffffff);
+        assert_eq!(fletcher64(&buffer), (high << 32) | low);
+    }
 }
 
+/* Any trailing bytes past a multiple of four are treated as if
+   padded with zeros. The sums are reduced once per 4 KiB, which keeps
+   them well clear of overflow for objects of any size */
 pub fn fletcher64(buffer: &[u8]) -> u64 {
     let initial_value = 0u64;
 
-    if buffer.len() % 4 != 0 {
-        panic!("Bad size");  // TODO turn into error?
-    }
     let mut lower_32bit = initial_value & 0xffffffff;
     let mut upper_32bit = (initial_value >> 32) & 0xffffffff;
 
-    for buffer_offset in (0..buffer.len()).step_by(4) {
-        let value_32bit = ((buffer[buffer_offset+0] as u64) <<  0) |
-                          ((buffer[buffer_offset+1] as u64) <<  8) |
-                          ((buffer[buffer_offset+2] as u64) << 16) |
-                          ((buffer[buffer_offset+3] as u64) << 24);
+    for block in buffer.chunks(4096) {
+        for chunk in block.chunks(4) {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let value_32bit = u32::from_le_bytes(bytes) as u64;
 
-        lower_32bit += value_32bit;
-        upper_32bit += lower_32bit;
+            lower_32bit += value_32bit;
+            upper_32bit += lower_32bit;
+        }
+        lower_32bit %= 0xffffffff;
+        upper_32bit %= 0xffffffff;
     }
-    lower_32bit %= 0xffffffff;
-    upper_32bit %= 0xffffffff;
 
     let value_32bit = 0xffffffff - ((lower_32bit + upper_32bit) % 0xffffffff);
     upper_32bit = 0xffffffff - ((lower_32bit + value_32bit) % 0xffffffff);
 
-    return (upper_32bit << 32) | value_32bit;
+    (upper_32bit << 32) | value_32bit
 }
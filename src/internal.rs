@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use std::borrow::Cow;
 use std::io::{self, prelude::*};
 
 use num_traits::FromPrimitive;
@@ -8,29 +9,47 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use bitflags;
 use uuid::{Bytes, Uuid};
 
+use crate::error::{ApfsError, Result};
+
 #[cfg(test)]
 mod test;
 
+macro_rules! import_flags {
+    ($flags:ident, $bits:expr) => {{
+        let bits = $bits;
+        $flags::from_bits(bits)
+            .ok_or(ApfsError::UnknownFlags { r#type: stringify!($flags), bits: bits as u64 })
+    }};
+}
+
+macro_rules! import_enum {
+    ($enum:ident, $from:ident, $value:expr) => {{
+        let value = $value;
+        $enum::$from(value)
+            .ok_or(ApfsError::UnknownValue { r#type: stringify!($enum), value: value as u64 })
+    }};
+}
+
 
 // General-Purpose Types
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Paddr(pub i64);
 
 impl Paddr {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self(source.read_i64::<LittleEndian>()?))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Prange {
     pub start_paddr: Paddr,
     pub block_count: u64,
 }
 
 impl Prange {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             start_paddr: Paddr::import(source)?,
             block_count: source.read_u64::<LittleEndian>()?,
@@ -38,7 +57,7 @@ impl Prange {
     }
 }
 
-fn import_uuid(source: &mut dyn Read) -> io::Result<Uuid> {
+fn import_uuid(source: &mut dyn Read) -> Result<Uuid> {
     let mut data: Bytes = [0; 16];
     source.read_exact(&mut data)?;
     Ok(Uuid::from_bytes(data))
@@ -51,7 +70,7 @@ fn import_uuid(source: &mut dyn Read) -> io::Result<Uuid> {
 pub struct Oid(pub u64);
 
 impl Oid {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self(source.read_u64::<LittleEndian>()?))
     }
 }
@@ -60,7 +79,7 @@ impl Oid {
 pub struct Xid(pub u64);
 
 impl Xid {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self(source.read_u64::<LittleEndian>()?))
     }
 }
@@ -68,7 +87,7 @@ impl Xid {
 
 const MAX_CKSUM_SIZE: usize = 8;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ObjPhys {
     pub cksum: u64,
     pub oid: Oid,
@@ -78,7 +97,7 @@ pub struct ObjPhys {
 }
 
 impl ObjPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             cksum: source.read_u64::<LittleEndian>()?,
             oid: Oid::import(source)?,
@@ -87,8 +106,14 @@ impl ObjPhys {
             subtype: ObjectTypeAndFlags::import(source)?,
         })
     }
+
+    pub fn parse(block: &[u8]) -> Result<Self> {
+        Self::import(&mut block.get(..OBJ_PHYS_SIZE).ok_or(ApfsError::Truncated)?)
+    }
 }
 
+pub const OBJ_PHYS_SIZE: usize = 32;
+
 
 const OID_NX_SUPERBLOCK                 : Oid = Oid(1);
 
@@ -107,7 +132,7 @@ const VOLUME_KEYBAG          : u32 = u32_code!(b"recs");
 const MEDIA_KEYBAG           : u32 = u32_code!(b"mkey");
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum ObjectType {
     NxSuperblock          = 0x00000001,
 
@@ -172,6 +197,7 @@ bitflags! {
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct ObjectTypeAndFlags(u32);
 
 impl ObjectTypeAndFlags {
@@ -184,15 +210,15 @@ impl ObjectTypeAndFlags {
         }
     }
 
-    pub fn new(value: u32) -> io::Result<ObjectTypeAndFlags> {
+    pub fn new(value: u32) -> Result<ObjectTypeAndFlags> {
         if Self::is_special_type(value) {
             return Ok(ObjectTypeAndFlags(value));
         }
         ObjectType::from_u32(value & OBJECT_TYPE_MASK)
-            .ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown object type: {}", value & OBJECT_TYPE_MASK)))?;
+            .ok_or(ApfsError::UnknownObjectType(value & OBJECT_TYPE_MASK))?;
         StorageType::from_u32(value & OBJ_STORAGETYPE_MASK)
-            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown storage type"))?;
-        ObjTypeFlags::from_bits(value & (OBJECT_TYPE_FLAGS_MASK & !OBJ_STORAGETYPE_MASK)).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown object flags"))?;
+            .ok_or(ApfsError::UnknownStorageType(value & OBJ_STORAGETYPE_MASK))?;
+        import_flags!(ObjTypeFlags, value & (OBJECT_TYPE_FLAGS_MASK & !OBJ_STORAGETYPE_MASK))?;
         Ok(ObjectTypeAndFlags(value))
     }
 
@@ -200,7 +226,7 @@ impl ObjectTypeAndFlags {
         ObjectTypeAndFlags(r#type as u32 | storage as u32 | flags.bits())
     }
 
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Self::new(source.read_u32::<LittleEndian>()?)
     }
 
@@ -242,7 +268,7 @@ impl std::fmt::Debug for ObjectTypeAndFlags {
 
 // EFI Jumpstart
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxEfiJumpstart {
     //nej_o: ObjPhys,
     pub magic: u32,
@@ -254,7 +280,7 @@ pub struct NxEfiJumpstart {
 }
 
 impl NxEfiJumpstart {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             magic: source.read_u32::<LittleEndian>()?,
             version: source.read_u32::<LittleEndian>()?,
@@ -329,7 +355,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxSuperblock {
         //nx_o: ObjPhys,
         pub magic: u32,
@@ -385,7 +411,7 @@ pub struct NxSuperblock {
 }
 
 impl NxSuperblock {
-    fn import_fs_oid(source: &mut dyn Read) -> io::Result<[Oid; NX_MAX_FILE_SYSTEMS]> {
+    fn import_fs_oid(source: &mut dyn Read) -> Result<[Oid; NX_MAX_FILE_SYSTEMS]> {
         let mut values = [Oid(0); NX_MAX_FILE_SYSTEMS];
         for entry in values.iter_mut() {
             *entry = Oid::import(source)?;
@@ -393,7 +419,7 @@ impl NxSuperblock {
         Ok(values)
     }
 
-    fn import_counters(source: &mut dyn Read) -> io::Result<[u64; CounterId::NumCounters as usize]> {
+    fn import_counters(source: &mut dyn Read) -> Result<[u64; CounterId::NumCounters as usize]> {
         let mut values = [0; CounterId::NumCounters as usize];
         for entry in values.iter_mut() {
             *entry = source.read_u64::<LittleEndian>()?;
@@ -401,7 +427,7 @@ impl NxSuperblock {
         Ok(values)
     }
 
-    fn import_ephemeral_info(source: &mut dyn Read) -> io::Result<[u64; NX_EPH_INFO_COUNT]> {
+    fn import_ephemeral_info(source: &mut dyn Read) -> Result<[u64; NX_EPH_INFO_COUNT]> {
         let mut values = [0; NX_EPH_INFO_COUNT];
         for entry in values.iter_mut() {
             *entry = source.read_u64::<LittleEndian>()?;
@@ -409,18 +435,15 @@ impl NxSuperblock {
         Ok(values)
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             magic: source.read_u32::<LittleEndian>()?,
             block_size: source.read_u32::<LittleEndian>()?,
             block_count: source.read_u64::<LittleEndian>()?,
 
-            features: SuperblockFeatureFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown feature flags"))?,
-            readonly_compatible_features: SuperblockRocompatFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown read-only flags"))?,
-            incompatible_features: SuperblockIncompatFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown incompatible flags"))?,
+            features: import_flags!(SuperblockFeatureFlags, source.read_u64::<LittleEndian>()?)?,
+            readonly_compatible_features: import_flags!(SuperblockRocompatFlags, source.read_u64::<LittleEndian>()?)?,
+            incompatible_features: import_flags!(SuperblockIncompatFlags, source.read_u64::<LittleEndian>()?)?,
 
             uuid: import_uuid(source)?,
 
@@ -449,8 +472,7 @@ impl NxSuperblock {
             counters: Self::import_counters(source)?,
             blocked_out_prange: Prange::import(source)?,
             evict_mapping_tree_oid: Oid::import(source)?,
-            flags: SuperblockFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(SuperblockFlags, source.read_u64::<LittleEndian>()?)?,
             efi_jumpstart: Paddr::import(source)?,
             fusion_uuid: import_uuid(source)?,
             keylocker: Prange::import(source)?,
@@ -471,24 +493,24 @@ impl NxSuperblock {
 
 pub const NX_MINIMUM_BLOCK_SIZE: usize = 4096;
 pub const NX_DEFAULT_BLOCK_SIZE: usize = 4096;
-const NX_MAXIMUM_BLOCK_SIZE: usize = 65536;
+pub const NX_MAXIMUM_BLOCK_SIZE: usize = 65536;
 
 const NX_MINIMUM_CONTAINER_SIZE: usize = 1048576;
 
 
-#[derive(Debug)]
-struct CheckpointMapping {
-    r#type:     ObjectTypeAndFlags,
-    subtype:    ObjectTypeAndFlags,
-    size:       u32,
-    pad:        u32,
-    fs_oid:     Oid,
-    oid:        Oid,
-    paddr:      Oid,
+#[derive(Debug, Clone)]
+pub struct CheckpointMapping {
+    pub r#type:     ObjectTypeAndFlags,
+    pub subtype:    ObjectTypeAndFlags,
+    pub size:       u32,
+    pad:            u32,
+    pub fs_oid:     Oid,
+    pub oid:        Oid,
+    pub paddr:      Paddr,
 }
 
 impl CheckpointMapping {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             r#type: ObjectTypeAndFlags::import(source)?,
             subtype: ObjectTypeAndFlags::import(source)?,
@@ -496,30 +518,29 @@ impl CheckpointMapping {
             pad: source.read_u32::<LittleEndian>()?,
             fs_oid: Oid::import(source)?,
             oid: Oid::import(source)?,
-            paddr: Oid::import(source)?,
+            paddr: Paddr::import(source)?,
         })
     }
 }
 
 bitflags! {
-    struct CpmFlags: u32 {
+    pub struct CpmFlags: u32 {
         const LAST = 0x00000001;
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CheckpointMapPhys {
       //cpm_o:        ObjPhys,
-      flags:    CpmFlags,
-      count:    u32,
-      map:      Vec<CheckpointMapping>,
+      pub flags:    CpmFlags,
+      pub count:    u32,
+      pub map:      Vec<CheckpointMapping>,
 }
 
 impl CheckpointMapPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
-            flags: CpmFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(CpmFlags, source.read_u32::<LittleEndian>()?)?,
             count: source.read_u32::<LittleEndian>()?,
             map: vec![],
         };
@@ -536,7 +557,7 @@ struct EvictMappingVal {
 }
 
 impl EvictMappingVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             dst_paddr: Paddr::import(source)?,
             len: source.read_u64::<LittleEndian>()?,
@@ -559,25 +580,24 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OmapPhys {
         //om_o: ObjPhys,
         flags: OmFlags,
-        snap_count: u32,
+        pub snap_count: u32,
         tree_type: ObjectTypeAndFlags,
-        snapshot_tree_type: ObjectTypeAndFlags,
+        pub snapshot_tree_type: ObjectTypeAndFlags,
         pub tree_oid: Oid,
-        snapshot_tree_oid: Oid,
-        most_recent_snap: Xid,
+        pub snapshot_tree_oid: Oid,
+        pub most_recent_snap: Xid,
         pending_revert_min: Xid,
         pending_revert_max: Xid,
 }
 
 impl OmapPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
-            flags: OmFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(OmFlags, source.read_u32::<LittleEndian>()?)?,
             snap_count: source.read_u32::<LittleEndian>()?,
             tree_type: ObjectTypeAndFlags::import(source)?,
             snapshot_tree_type: ObjectTypeAndFlags::import(source)?,
@@ -604,7 +624,7 @@ impl OmapKey {
         }
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             oid: Oid::import(source)?,
             xid: Xid::import(source)?,
@@ -630,10 +650,9 @@ pub struct OmapVal {
 }
 
 impl OmapVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
-            flags: OvFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(OvFlags, source.read_u32::<LittleEndian>()?)?,
             size: source.read_u32::<LittleEndian>()?,
             paddr: Paddr::import(source)?,
         })
@@ -641,23 +660,23 @@ impl OmapVal {
 }
 
 bitflags! {
-    struct OmsFlags: u32 {
+    pub struct OmsFlags: u32 {
         const DELETED = 0x00000001;
         const REVERTED = 0x00000002;
     }
 }
 
-struct OmapSnapshot {
-    flags: OmsFlags,
+#[derive(Debug, Clone)]
+pub struct OmapSnapshot {
+    pub flags: OmsFlags,
     pad: u32,
-    oid: Oid,
+    pub oid: Oid,
 }
 
 impl OmapSnapshot {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
-            flags: OmsFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(OmsFlags, source.read_u32::<LittleEndian>()?)?,
             pad: source.read_u32::<LittleEndian>()?,
             oid: Oid::import(source)?,
         })
@@ -667,7 +686,7 @@ impl OmapSnapshot {
 const OMAP_MAX_SNAP_COUNT: u32 = u32::MAX;
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, PartialEq, FromPrimitive)]
 enum OmapReapPhase {
     MapTree = 1,
     SnapshotTree = 2,
@@ -686,7 +705,7 @@ struct ApfsModifiedBy {
 }
 
 impl ApfsModifiedBy {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut id = [0; APFS_MODIFIED_NAMELEN];
         source.read_exact(&mut id[..])?;
         Ok(Self {
@@ -728,7 +747,7 @@ bitflags! {
 const APFS_VOLUME_ENUM_SHIFT: usize = 6;
 
 bitflags! {
-    struct VolumeRoles: u16 {
+    pub struct VolumeRoles: u16 {
         const ROLE_NONE = 0x0000;
 
         const ROLE_SYSTEM = 0x0001;
@@ -753,7 +772,7 @@ bitflags! {
 }
 
 bitflags! {
-    struct VolumeFeatureFlags: u64 {
+    pub struct VolumeFeatureFlags: u64 {
         const DEFRAG_PRERELEASE = 0x00000001;
         const HARDLINK_MAP_RECORDS = 0x00000002;
         const DEFRAG = 0x00000004;
@@ -769,13 +788,13 @@ bitflags! {
 }
 
 bitflags! {
-    struct VolumeRocompatFlags: u64 {
+    pub struct VolumeRocompatFlags: u64 {
         const SUPPORTED_MASK = 0;
     }
 }
 
 bitflags! {
-    struct VolumeIncompatFlags: u64 {
+    pub struct VolumeIncompatFlags: u64 {
         const CASE_INSENSITIVE = 0x00000001;
         const DATALESS_SNAPS = 0x00000002;
         const ENC_ROLLED = 0x00000004;
@@ -800,16 +819,16 @@ pub const APFS_MAGIC: u32   = u32_code!(b"BSPA");
 const APFS_MAX_HIST: usize = 8;
 const APFS_VOLNAME_LEN: usize = 256;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ApfsSuperblock {
     //apfs_o: ObjPhys,
 
     magic: u32,
     fs_index: u32,
 
-    features: VolumeFeatureFlags,
-    readonly_compatible_features: VolumeRocompatFlags,
-    incompatible_features: VolumeIncompatFlags,
+    pub features: VolumeFeatureFlags,
+    pub readonly_compatible_features: VolumeRocompatFlags,
+    pub incompatible_features: VolumeIncompatFlags,
 
     unmount_time: u64,
 
@@ -819,8 +838,8 @@ pub struct ApfsSuperblock {
 
     meta_crypto: WrappedMetaCryptoState,
 
-    root_tree_type: ObjectTypeAndFlags,
-    extentref_tree_type: ObjectTypeAndFlags,
+    pub root_tree_type: ObjectTypeAndFlags,
+    pub extentref_tree_type: ObjectTypeAndFlags,
     snap_meta_tree_type: ObjectTypeAndFlags,
 
     pub omap_oid: Oid,
@@ -842,10 +861,10 @@ pub struct ApfsSuperblock {
     total_blocks_alloced: u64,
     total_blocks_freed: u64,
 
-    vol_uuid: Uuid,
+    pub vol_uuid: Uuid,
     last_mod_time: u64,
 
-    fs_flags: VolumeFlags,
+    pub fs_flags: VolumeFlags,
 
     formatted_by: ApfsModifiedBy,
     modified_by: [ApfsModifiedBy; APFS_MAX_HIST],
@@ -853,7 +872,7 @@ pub struct ApfsSuperblock {
     pub volname: [u8; APFS_VOLNAME_LEN],
     next_doc_id: u32,
 
-    role: VolumeRoles,
+    pub role: VolumeRoles,
     reserved: u16,
 
     root_to_xid: Xid,
@@ -868,15 +887,27 @@ pub struct ApfsSuperblock {
 
     integrity_meta_oid: Oid,
 
-    fext_tree_oid: Oid,
-    fext_tree_type: ObjectTypeAndFlags,
+    pub fext_tree_oid: Oid,
+    pub fext_tree_type: ObjectTypeAndFlags,
 
     reserved_type: u32,
     reserved_oid: Oid,
 }
 
 impl ApfsSuperblock {
-    fn import_modified_by(source: &mut dyn Read) -> io::Result<[ApfsModifiedBy; APFS_MAX_HIST]> {
+    /* Sealed volumes keep file extents in the fext tree rather than
+       in the file-system tree */
+    pub fn is_sealed(&self) -> bool {
+        self.incompatible_features.contains(VolumeIncompatFlags::SEALED_VOLUME)
+    }
+
+    /* The volume name is stored NUL terminated */
+    pub fn name(&self) -> Result<&str> {
+        let len = self.volname.iter().position(|&c| c == 0).unwrap_or(APFS_VOLNAME_LEN);
+        std::str::from_utf8(&self.volname[..len]).map_err(|_| ApfsError::InvalidString)
+    }
+
+    fn import_modified_by(source: &mut dyn Read) -> Result<[ApfsModifiedBy; APFS_MAX_HIST]> {
         let mut values = [ApfsModifiedBy::default(); APFS_MAX_HIST];
         for entry in values.iter_mut() {
             *entry = ApfsModifiedBy::import(source)?;
@@ -884,23 +915,20 @@ impl ApfsSuperblock {
         Ok(values)
     }
 
-    fn import_volname(source: &mut dyn Read) -> io::Result<[u8; APFS_VOLNAME_LEN]> {
+    fn import_volname(source: &mut dyn Read) -> Result<[u8; APFS_VOLNAME_LEN]> {
         let mut values = [0; APFS_VOLNAME_LEN];
         source.read_exact(&mut values[..])?;
         Ok(values)
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             magic: source.read_u32::<LittleEndian>()?,
             fs_index: source.read_u32::<LittleEndian>()?,
 
-            features: VolumeFeatureFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
-            readonly_compatible_features: VolumeRocompatFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
-            incompatible_features: VolumeIncompatFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            features: import_flags!(VolumeFeatureFlags, source.read_u64::<LittleEndian>()?)?,
+            readonly_compatible_features: import_flags!(VolumeRocompatFlags, source.read_u64::<LittleEndian>()?)?,
+            incompatible_features: import_flags!(VolumeIncompatFlags, source.read_u64::<LittleEndian>()?)?,
 
             unmount_time: source.read_u64::<LittleEndian>()?,
 
@@ -936,8 +964,7 @@ impl ApfsSuperblock {
             vol_uuid: import_uuid(source)?,
             last_mod_time: source.read_u64::<LittleEndian>()?,
 
-            fs_flags: VolumeFlags::from_bits(source.read_u64::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            fs_flags: import_flags!(VolumeFlags, source.read_u64::<LittleEndian>()?)?,
 
             formatted_by: ApfsModifiedBy::import(source)?,
             modified_by: Self::import_modified_by(source)?,
@@ -945,8 +972,7 @@ impl ApfsSuperblock {
             volname: Self::import_volname(source)?,
             next_doc_id: source.read_u32::<LittleEndian>()?,
 
-            role: VolumeRoles::from_bits(source.read_u16::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            role: import_flags!(VolumeRoles, source.read_u16::<LittleEndian>()?)?,
             reserved: source.read_u16::<LittleEndian>()?,
 
             root_to_xid: Xid::import(source)?,
@@ -988,7 +1014,7 @@ pub struct Nloc {
 pub const BTOFF_INVALID: u16 = 0xffff;
 
 impl Nloc {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             off: source.read_u16::<LittleEndian>()?,
             len: source.read_u16::<LittleEndian>()?,
@@ -1003,7 +1029,7 @@ pub struct KVloc {
 }
 
 impl KVloc {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             k: Nloc::import(source)?,
             v: Nloc::import(source)?,
@@ -1011,14 +1037,14 @@ impl KVloc {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KVoff {
     pub k: u16,
     pub v: u16,
 }
 
 impl KVoff {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             k: source.read_u16::<LittleEndian>()?,
             v: source.read_u16::<LittleEndian>()?,
@@ -1040,7 +1066,7 @@ bitflags! {
 }
 
 #[derive(Debug)]
-pub struct BtreeNodePhys {
+pub struct BtreeNodePhys<'a> {
         //btn_o: ObjPhys,
         pub flags: BtnFlags,
         pub level: u16,
@@ -1049,27 +1075,50 @@ pub struct BtreeNodePhys {
         pub free_space: Nloc,
         pub key_free_list: Nloc,
         pub val_free_list: Nloc,
-        pub data: Vec<u8>,
+        pub data: Cow<'a, [u8]>,
 }
 
-impl BtreeNodePhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+impl<'a> BtreeNodePhys<'a> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
-            flags: BtnFlags::from_bits(source.read_u16::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown btn flags"))?,
+            flags: import_flags!(BtnFlags, source.read_u16::<LittleEndian>()?)?,
             level: source.read_u16::<LittleEndian>()?,
             nkeys: source.read_u32::<LittleEndian>()?,
             table_space: Nloc::import(source)?,
             free_space: Nloc::import(source)?,
             key_free_list: Nloc::import(source)?,
             val_free_list: Nloc::import(source)?,
-            data: vec![],
+            data: Cow::Owned(vec![]),
         };
-        source.read_to_end(&mut value.data)?;
+        source.read_to_end(value.data.to_mut())?;
         Ok(value)
     }
+
+    /* Parse the node header and keep the rest of the node borrowed
+       from the block it was read from */
+    pub fn parse(body: &'a [u8]) -> Result<Self> {
+        let header = body.get(..BTREE_NODE_PHYS_SIZE).ok_or(ApfsError::Truncated)?;
+        let mut value = Self::import(&mut &header[..])?;
+        value.data = Cow::Borrowed(&body[BTREE_NODE_PHYS_SIZE..]);
+        Ok(value)
+    }
+
+    pub fn into_owned(self) -> BtreeNodePhys<'static> {
+        BtreeNodePhys {
+            flags: self.flags,
+            level: self.level,
+            nkeys: self.nkeys,
+            table_space: self.table_space,
+            free_space: self.free_space,
+            key_free_list: self.key_free_list,
+            val_free_list: self.val_free_list,
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
 }
 
+pub const BTREE_NODE_PHYS_SIZE: usize = 24;
+
 bitflags! {
     pub struct BtFlags: u32 {
         const UINT64_KEYS         = 0x00000001;
@@ -1084,7 +1133,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BtreeInfoFixed {
         //bt_o: ObjPhys,
         pub flags: BtFlags,
@@ -1094,11 +1143,10 @@ pub struct BtreeInfoFixed {
 }
 
 impl BtreeInfoFixed {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let flags = source.read_u32::<LittleEndian>()?;
         Ok(Self {
-            flags: BtFlags::from_bits(flags)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown bt flags: 0x{:08X}", flags)))?,
+            flags: import_flags!(BtFlags, flags)?,
             node_size: source.read_u32::<LittleEndian>()?,
             key_size: source.read_u32::<LittleEndian>()?,
             val_size: source.read_u32::<LittleEndian>()?,
@@ -1106,7 +1154,7 @@ impl BtreeInfoFixed {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BtreeInfo {
         pub fixed: BtreeInfoFixed,
         pub longest_key: u32,
@@ -1116,7 +1164,7 @@ pub struct BtreeInfo {
 }
 
 impl BtreeInfo {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             fixed: BtreeInfoFixed::import(source)?,
             longest_key: source.read_u32::<LittleEndian>()?,
@@ -1127,16 +1175,17 @@ impl BtreeInfo {
     }
 }
 
-const BTREE_NODE_HASH_SIZE_MAX: usize = 64;
+pub const BTREE_NODE_HASH_SIZE_MAX: usize = 64;
+pub const BTN_INDEX_NODE_VAL_SIZE: usize = 8 + BTREE_NODE_HASH_SIZE_MAX;
 
-#[derive(Debug)]
-struct BtnIndexNodeVal {
-    child_oid: Oid,
-    child_hash: [u8; BTREE_NODE_HASH_SIZE_MAX],
+#[derive(Debug, Clone)]
+pub struct BtnIndexNodeVal {
+    pub child_oid: Oid,
+    pub child_hash: [u8; BTREE_NODE_HASH_SIZE_MAX],
 }
 
 impl BtnIndexNodeVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             child_oid: Oid::import(source)?,
             child_hash: [0; BTREE_NODE_HASH_SIZE_MAX],
@@ -1155,7 +1204,7 @@ type CpKeyRevision = u16;
 const CP_EFFECTIVE_CLASSMASK: usize = 0x0000001f;
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, PartialEq, FromPrimitive)]
 enum CpKeyClass {  // ProtectionClass
     DirNone = 0,
     A = 1,
@@ -1172,7 +1221,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct WrappedCryptoState {
     major_version: u16,
     minor_version: u16,
@@ -1187,14 +1236,12 @@ struct WrappedCryptoState {
 const CP_MAX_WRAPPEDKEYSIZE: u16 = 128;
 
 impl WrappedCryptoState {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             major_version: source.read_u16::<LittleEndian>()?,
             minor_version: source.read_u16::<LittleEndian>()?,
-            cpflags: CryptoFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown crypto flags"))?,
-            persistent_class: CpKeyClass::from_u32(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown crypto protection class"))?,
+            cpflags: import_flags!(CryptoFlags, source.read_u32::<LittleEndian>()?)?,
+            persistent_class: import_enum!(CpKeyClass, from_u32, source.read_u32::<LittleEndian>()?)?,
             key_os_version: source.read_u32::<LittleEndian>()?,
             key_revision: source.read_u16::<LittleEndian>()?,
             key_len: source.read_u16::<LittleEndian>()?,
@@ -1207,7 +1254,7 @@ impl WrappedCryptoState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct WrappedMetaCryptoState {
     major_version: u16,
     minor_version: u16,
@@ -1219,14 +1266,12 @@ struct WrappedMetaCryptoState {
 }
 
 impl WrappedMetaCryptoState  {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             major_version: source.read_u16::<LittleEndian>()?,
             minor_version: source.read_u16::<LittleEndian>()?,
-            cpflags: CryptoFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown crypto flags"))?,
-            persistent_class: CpKeyClass::from_u32(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown crypto protection class"))?,
+            cpflags: import_flags!(CryptoFlags, source.read_u32::<LittleEndian>()?)?,
+            persistent_class: import_enum!(CpKeyClass, from_u32, source.read_u32::<LittleEndian>()?)?,
             key_os_version: source.read_u32::<LittleEndian>()?,
             key_revision: source.read_u16::<LittleEndian>()?,
             unused: source.read_u16::<LittleEndian>()?,
@@ -1234,26 +1279,26 @@ impl WrappedMetaCryptoState  {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct JCryptoKey {
     //hdr: JKey,
 }
 
 impl JCryptoKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct JCryptoVal {
     refcnt: u32,
     state: WrappedCryptoState,
 }
 
 impl JCryptoVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             refcnt: source.read_u32::<LittleEndian>()?,
             state: WrappedCryptoState::import(source)?,
@@ -1272,7 +1317,7 @@ const APFS_VOL_KEYBAG_ENTRY_MAX_SIZE: usize = 512;
 const APFS_FV_PERSONAL_RECOVERY_KEY_UUID: &str = "EBC6C064-0000-11AA-AA11-00306543ECAC";
 
 #[repr(u16)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, PartialEq, FromPrimitive)]
 pub enum KbTag {
     Unknown = 0,
     Reserved1 = 1,
@@ -1284,7 +1329,7 @@ pub enum KbTag {
     ReservedF8 = 0xF8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KeybagEntry {
     pub uuid: Uuid,
     pub tag: KbTag,
@@ -1294,7 +1339,7 @@ pub struct KeybagEntry {
 }
 
 impl KeybagEntry {
-    fn import_padding(source: &mut dyn Read) -> io::Result<[u8; 4]> {
+    fn import_padding(source: &mut dyn Read) -> Result<[u8; 4]> {
         let mut values = [0; 4];
         for entry in values.iter_mut() {
             *entry = source.read_u8()?;
@@ -1302,14 +1347,10 @@ impl KeybagEntry {
         Ok(values)
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             uuid: import_uuid(source)?,
-            tag: {
-                let data = source.read_u16::<LittleEndian>()?;
-                KbTag::from_u16(data)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown keybag tag: {}", data)))?
-            },
+            tag: import_enum!(KbTag, from_u16, source.read_u16::<LittleEndian>()?)?,
             keylen: source.read_u16::<LittleEndian>()?,
             padding: Self::import_padding(source)?,
             keydata: vec![],
@@ -1317,14 +1358,14 @@ impl KeybagEntry {
         for _ in 0..value.keylen {
             value.keydata.push(source.read_u8()?);
         }
-        for _ in 0..((24+value.keylen)%16) {
+        for _ in 0..((24 + value.keylen as usize) % 16) {
             source.read_u8()?;
         }
         Ok(value)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KbLocker {
     version: u16,
     nkeys: u16,
@@ -1334,7 +1375,7 @@ pub struct KbLocker {
 }
 
 impl KbLocker {
-    fn import_padding(source: &mut dyn Read) -> io::Result<[u8; 8]> {
+    fn import_padding(source: &mut dyn Read) -> Result<[u8; 8]> {
         let mut values = [0; 8];
         for entry in values.iter_mut() {
             *entry = source.read_u8()?;
@@ -1342,7 +1383,7 @@ impl KbLocker {
         Ok(values)
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             version: source.read_u16::<LittleEndian>()?,
             nkeys: source.read_u16::<LittleEndian>()?,
@@ -1357,14 +1398,14 @@ impl KbLocker {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MediaKeybag {
     //mk_obj: ObjPhys,
     pub locker: KbLocker,
 }
 
 impl MediaKeybag {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             locker: KbLocker::import(source)?,
         })
@@ -1374,7 +1415,7 @@ impl MediaKeybag {
 
 // Space Manager
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct ChunkInfo {
     xid: u64,
     addr: u64,
@@ -1384,7 +1425,7 @@ struct ChunkInfo {
 }
 
 impl ChunkInfo {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             xid: source.read_u64::<LittleEndian>()?,
             addr: source.read_u64::<LittleEndian>()?,
@@ -1395,7 +1436,7 @@ impl ChunkInfo {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChunkInfoBlock {
     //cib_o: ObjPhys,
     index: u32,
@@ -1404,7 +1445,7 @@ pub struct ChunkInfoBlock {
 }
 
 impl ChunkInfoBlock {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             index: source.read_u32::<LittleEndian>()?,
             chunk_info_count: source.read_u32::<LittleEndian>()?,
@@ -1417,7 +1458,7 @@ impl ChunkInfoBlock {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CibAddrBlock {
     //cab_o: ObjPhys,
     index: u32,
@@ -1426,7 +1467,7 @@ struct CibAddrBlock {
 }
 
 impl CibAddrBlock {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             index: source.read_u32::<LittleEndian>()?,
             cib_count: source.read_u32::<LittleEndian>()?,
@@ -1439,23 +1480,23 @@ impl CibAddrBlock {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpacemanFreeQueueVal(pub u64);
 
 impl SpacemanFreeQueueVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self(source.read_u64::<LittleEndian>()?))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpacemanFreeQueueKey {
     pub xid: Xid,
     pub paddr: Paddr,
 }
 
 impl SpacemanFreeQueueKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             xid: Xid::import(source)?,
             paddr: Paddr::import(source)?,
@@ -1463,14 +1504,14 @@ impl SpacemanFreeQueueKey {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct SpacemanFreeQueueEntry {
     key: SpacemanFreeQueueKey,
     count: SpacemanFreeQueueVal,
 }
 
 impl SpacemanFreeQueueEntry {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             key: SpacemanFreeQueueKey::import(source)?,
             count: SpacemanFreeQueueVal::import(source)?,
@@ -1490,7 +1531,7 @@ struct SpacemanFreeQueue {
 }
 
 impl SpacemanFreeQueue {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             count: source.read_u64::<LittleEndian>()?,
             tree_oid: Oid::import(source)?,
@@ -1516,7 +1557,7 @@ struct SpacemanDevice {
 }
 
 impl SpacemanDevice {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             block_count: source.read_u64::<LittleEndian>()?,
             chunk_count: source.read_u64::<LittleEndian>()?,
@@ -1537,7 +1578,7 @@ struct SpacemanAllocationZoneBoundaries {
 }
 
 impl SpacemanAllocationZoneBoundaries {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             zone_start: source.read_u64::<LittleEndian>()?,
             zone_end: source.read_u64::<LittleEndian>()?,
@@ -1558,7 +1599,7 @@ struct SpacemanAllocationZoneInfoPhys {
 }
 
 impl SpacemanAllocationZoneInfoPhys {
-    fn import_previous_boundaries(source: &mut dyn Read) -> io::Result<[SpacemanAllocationZoneBoundaries; SM_ALLOCZONE_NUM_PREVIOUS_BOUNDARIES]> {
+    fn import_previous_boundaries(source: &mut dyn Read) -> Result<[SpacemanAllocationZoneBoundaries; SM_ALLOCZONE_NUM_PREVIOUS_BOUNDARIES]> {
         let mut values = [SpacemanAllocationZoneBoundaries::default(); SM_ALLOCZONE_NUM_PREVIOUS_BOUNDARIES];
         for entry in values.iter_mut() {
             *entry = SpacemanAllocationZoneBoundaries::import(source)?;
@@ -1566,7 +1607,7 @@ impl SpacemanAllocationZoneInfoPhys {
         Ok(values)
     }
 
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             current_boundaries: SpacemanAllocationZoneBoundaries::import(source)?,
             previous_boundaries: Self::import_previous_boundaries(source)?,
@@ -1592,7 +1633,7 @@ struct SpacemanDatazoneInfoPhys {
 }
 
 impl SpacemanDatazoneInfoPhys {
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = SpacemanDatazoneInfoPhys::default();
         for outer in 0..SM_DATAZONE_ALLOCZONE_COUNT {
             for inner in 0..Smdev::Count as usize {
@@ -1616,7 +1657,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpacemanPhys {
     //sm_o: ObjPhys,
     block_size: u32,
@@ -1645,7 +1686,7 @@ pub struct SpacemanPhys {
 }
 
 impl SpacemanPhys {
-    fn import_dev(source: &mut dyn Read) -> io::Result<[SpacemanDevice; Smdev::Count as usize]> {
+    fn import_dev(source: &mut dyn Read) -> Result<[SpacemanDevice; Smdev::Count as usize]> {
         let mut values = [SpacemanDevice::default(); Smdev::Count as usize];
         for entry in values.iter_mut() {
             *entry = SpacemanDevice::import(source)?;
@@ -1653,7 +1694,7 @@ impl SpacemanPhys {
         Ok(values)
     }
 
-    fn import_fq(source: &mut dyn Read) -> io::Result<[SpacemanFreeQueue; Sfq::Count as usize]> {
+    fn import_fq(source: &mut dyn Read) -> Result<[SpacemanFreeQueue; Sfq::Count as usize]> {
         let mut values = [SpacemanFreeQueue::default(); Sfq::Count as usize];
         for entry in values.iter_mut() {
             *entry = SpacemanFreeQueue::import(source)?;
@@ -1661,15 +1702,14 @@ impl SpacemanPhys {
         Ok(values)
     }
 
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             block_size: source.read_u32::<LittleEndian>()?,
             blocks_per_chunk: source.read_u32::<LittleEndian>()?,
             chunks_per_cib: source.read_u32::<LittleEndian>()?,
             cibs_per_cab: source.read_u32::<LittleEndian>()?,
             dev: Self::import_dev(source)?,
-            flags: SpacemanFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            flags: import_flags!(SpacemanFlags, source.read_u32::<LittleEndian>()?)?,
             ip_bm_tx_multiplier: source.read_u32::<LittleEndian>()?,
             ip_block_count: source.read_u64::<LittleEndian>()?,
             ip_bm_size_in_blocks: source.read_u32::<LittleEndian>()?,
@@ -1703,7 +1743,7 @@ const SPACEMAN_IP_BM_BLOCK_COUNT_MAX: u16 = 0xfffe;
 // File-System Constants
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum JObjTypes {
     Any = 0,
 
@@ -1736,12 +1776,18 @@ const OBJ_TYPE_SHIFT                    : usize = 60;
 
 const SYSTEM_OBJ_ID_MARK                : u64 = 0x0fffffff00000000;
 
+pub const ROOT_DIR_PARENT               : u64 = 1;
+pub const ROOT_DIR_INO_NUM              : u64 = 2;
+pub const PRIV_DIR_INO_NUM              : u64 = 3;
+pub const SNAP_DIR_INO_NUM              : u64 = 6;
+pub const PURGEABLE_DIR_INO_NUM         : u64 = 7;
+
+#[derive(Copy, Clone)]
 pub struct JObjectIdAndType(u64);
 
 impl JObjectIdAndType {
-    pub fn new(value: u64) -> io::Result<Self> {
-        JObjTypes::from_u8(((value & OBJ_TYPE_MASK) >> OBJ_TYPE_SHIFT) as u8)
-            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown filesystem object type"))?;
+    pub fn new(value: u64) -> Result<Self> {
+        import_enum!(JObjTypes, from_u8, ((value & OBJ_TYPE_MASK) >> OBJ_TYPE_SHIFT) as u8)?;
         Ok(Self(value))
     }
 
@@ -1749,7 +1795,7 @@ impl JObjectIdAndType {
         Self((value & OBJ_ID_MASK) | ((r#type as u64) << OBJ_TYPE_SHIFT))
     }
 
-    fn import(source: &mut dyn Read) -> io::Result<Self> {
+    fn import(source: &mut dyn Read) -> Result<Self> {
         Self::new(source.read_u64::<LittleEndian>()?)
     }
 
@@ -1772,35 +1818,35 @@ impl std::fmt::Debug for JObjectIdAndType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JKey {
     pub obj_id_and_type: JObjectIdAndType,
 }
 
 impl JKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             obj_id_and_type: JObjectIdAndType::import(source)?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JInodeKey {
     //hdr: JKey,
 }
 
 impl JInodeKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-type Uid = u32;
-type Gid = u32;
+pub type Uid = u32;
+pub type Gid = u32;
 
-type Mode = u16;
+pub type Mode = u16;
 
 //#define S_IFMT 0170000
 //
@@ -1823,33 +1869,91 @@ type Mode = u16;
 //#define DT_SOCK 12
 //#define DT_WHT 14
 
-#[derive(Debug)]
+const DREC_TYPE_MASK: u16 = 0x000f;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, FromPrimitive)]
+pub enum FileType {
+    Unknown = 0,
+    Fifo = 1,
+    CharDevice = 2,
+    Directory = 4,
+    BlockDevice = 6,
+    Regular = 8,
+    Symlink = 10,
+    Socket = 12,
+    Whiteout = 14,
+}
+
+bitflags! {
+    pub struct InodeFlags: u64 {
+        const IS_APFS_PRIVATE = 0x00000001;
+        const MAINTAIN_DIR_STATS = 0x00000002;
+        const DIR_STATS_ORIGIN = 0x00000004;
+        const PROT_CLASS_EXPLICIT = 0x00000008;
+        const WAS_CLONED = 0x00000010;
+        const FLAG_UNUSED = 0x00000020;
+        const HAS_SECURITY_EA = 0x00000040;
+        const BEING_TRUNCATED = 0x00000080;
+        const HAS_FINDER_INFO = 0x00000100;
+        const IS_SPARSE = 0x00000200;
+        const WAS_EVER_CLONED = 0x00000400;
+        const ACTIVE_FILE_TRIMMED = 0x00000800;
+        const PINNED_TO_MAIN = 0x00001000;
+        const PINNED_TO_TIER2 = 0x00002000;
+        const HAS_RSRC_FORK = 0x00004000;
+        const NO_RSRC_FORK = 0x00008000;
+        const ALLOCATION_SPILLEDOVER = 0x00010000;
+        const FAST_PROMOTE = 0x00020000;
+        const HAS_UNCOMPRESSED_SIZE = 0x00040000;
+        const IS_PURGEABLE = 0x00080000;
+        const WANTS_TO_BE_PURGEABLE = 0x00100000;
+        const IS_SYNC_ROOT = 0x00200000;
+        const SNAPSHOT_COW_EXEMPTION = 0x00400000;
+
+        const INHERITED_INTERNAL_FLAGS = Self::MAINTAIN_DIR_STATS.bits
+            | Self::SNAPSHOT_COW_EXEMPTION.bits;
+
+        const CLONED_INTERNAL_FLAGS = Self::HAS_RSRC_FORK.bits
+            | Self::NO_RSRC_FORK.bits
+            | Self::HAS_FINDER_INFO.bits
+            | Self::SNAPSHOT_COW_EXEMPTION.bits;
+
+        const PINNED_MASK = Self::PINNED_TO_MAIN.bits
+            | Self::PINNED_TO_TIER2.bits;
+    }
+}
+
+const S_IFMT: Mode = 0o170000;
+const S_IFMT_SHIFT: usize = 12;
+
+#[derive(Debug, Clone)]
 pub struct JInodeVal {
-    parent_id: u64,
-    private_id: u64,
+    pub parent_id: u64,
+    pub private_id: u64,
 
-    create_time: u64,
-    mod_time: u64,
-    change_time: u64,
-    access_time: u64,
+    pub create_time: u64,
+    pub mod_time: u64,
+    pub change_time: u64,
+    pub access_time: u64,
 
-    internal_flags: u64,
+    pub internal_flags: InodeFlags,
 
-    nchildren_or_nlink: i32,
+    pub nchildren_or_nlink: i32,
 
     default_protection_class: CpKeyClass,
-    write_generation_counter: u32,
-    bsd_flags: u32,
-    owner: Uid,
-    group: Gid,
-    mode: Mode,
+    pub write_generation_counter: u32,
+    pub bsd_flags: u32,
+    pub owner: Uid,
+    pub group: Gid,
+    pub mode: Mode,
     pad1: u16,
     pub uncompressed_size: u64,
     pub xfields: Vec<u8>,
 }
 
 impl JInodeVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             parent_id: source.read_u64::<LittleEndian>()?,
             private_id: source.read_u64::<LittleEndian>()?,
@@ -1859,12 +1963,11 @@ impl JInodeVal {
             change_time: source.read_u64::<LittleEndian>()?,
             access_time: source.read_u64::<LittleEndian>()?,
 
-            internal_flags: source.read_u64::<LittleEndian>()?,
+            internal_flags: import_flags!(InodeFlags, source.read_u64::<LittleEndian>()?)?,
 
             nchildren_or_nlink: source.read_i32::<LittleEndian>()?,
 
-            default_protection_class: CpKeyClass::from_u32(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown crypto protection class"))?,
+            default_protection_class: import_enum!(CpKeyClass, from_u32, source.read_u32::<LittleEndian>()?)?,
             write_generation_counter: source.read_u32::<LittleEndian>()?,
             bsd_flags: source.read_u32::<LittleEndian>()?,
             owner: source.read_u32::<LittleEndian>()?,
@@ -1877,13 +1980,18 @@ impl JInodeVal {
         source.read_to_end(&mut value.xfields)?;
         Ok(value)
     }
+
+    /* The S_IF* type bits of the mode line up with the DT_* types */
+    pub fn file_type(&self) -> Result<FileType> {
+        import_enum!(FileType, from_u16, (self.mode & S_IFMT) >> S_IFMT_SHIFT)
+    }
 }
 
 const J_DREC_LEN_MASK : u32 = 0x000003ff;
-const J_DREC_HASH_MASK : u32 = 0xfffff400;
+const J_DREC_HASH_MASK : u32 = 0xfffffc00;
 const J_DREC_HASH_SHIFT : usize = 10;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDrecKey {
     //hdr: JKey,
     name_len: u16,
@@ -1892,40 +2000,60 @@ pub struct JDrecKey {
 }
 
 impl JDrecKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let name_len = source.read_u16::<LittleEndian>()?;
         let mut name = vec![0u8; name_len as usize];
         source.read_exact(&mut name)?;
         Ok(Self {
             name_len,
             name: String::from_utf8(name)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))?,
+                .map_err(|_| ApfsError::InvalidString)?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDrecHashedKey {
     //hdr: JKey,
     name_len_and_hash: u32,
-    //name: Vec<u8>,
-    name: String,  // XXX: Should this be raw bytes and checked later or a structured type?
+    /* Kept as stored so that a name which is not valid UTF-8 does not
+       stop the rest of its directory from being read */
+    pub name: Vec<u8>,
 }
 
 impl JDrecHashedKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn new(hash: u32, name: &str) -> Self {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        Self {
+            name_len_and_hash: ((hash << J_DREC_HASH_SHIFT) & J_DREC_HASH_MASK) | (name.len() as u32 & J_DREC_LEN_MASK),
+            name,
+        }
+    }
+
+    pub fn hash(&self) -> u32 {
+        (self.name_len_and_hash & J_DREC_HASH_MASK) >> J_DREC_HASH_SHIFT
+    }
+
+    /* Stored names include their NUL terminator */
+    pub fn file_name(&self) -> Result<&str> {
+        std::str::from_utf8(&self.name)
+            .map(|name| name.trim_end_matches('\0'))
+            .map_err(|_| ApfsError::InvalidString)
+    }
+
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let name_len_and_hash = source.read_u32::<LittleEndian>()?;
         let mut name = vec![0u8; (name_len_and_hash  & J_DREC_LEN_MASK) as usize];
         source.read_exact(&mut name)?;
         Ok(Self {
             name_len_and_hash,
-            name: String::from_utf8(name)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))?,
+            name,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDrecVal {
     file_id: u64,
     date_added: u64,
@@ -1934,7 +2062,7 @@ pub struct JDrecVal {
 }
 
 impl JDrecVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             file_id: source.read_u64::<LittleEndian>()?,
             date_added: source.read_u64::<LittleEndian>()?,
@@ -1944,22 +2072,31 @@ impl JDrecVal {
         source.read_to_end(&mut value.xfields)?;
         Ok(value)
     }
+
+    pub fn file_id(&self) -> u64 {
+        self.file_id
+    }
+
+    /* The low bits of the flags hold the DT_* type of the entry */
+    pub fn file_type(&self) -> Result<FileType> {
+        import_enum!(FileType, from_u16, self.flags & DREC_TYPE_MASK)
+    }
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDirStatsKey {
     //hdr: JKey,
 }
 
 impl JDirStatsKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDirStatsVal {
     num_children: u64,
     total_size: u64,
@@ -1968,7 +2105,7 @@ pub struct JDirStatsVal {
 }
 
 impl JDirStatsVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             num_children: source.read_u64::<LittleEndian>()?,
             total_size: source.read_u64::<LittleEndian>()?,
@@ -1978,7 +2115,7 @@ impl JDirStatsVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JXattrKey {
     //hdr: JKey,
     name_len: u16,
@@ -1987,31 +2124,93 @@ pub struct JXattrKey {
 }
 
 impl JXattrKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let name_len = source.read_u16::<LittleEndian>()?;
         let mut name = vec![0u8; name_len as usize];
         source.read_exact(&mut name)?;
         Ok(Self {
             name_len,
             name: String::from_utf8(name)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))?,
+                .map_err(|_| ApfsError::InvalidString)?,
         })
     }
 }
 
-#[derive(Debug)]
+bitflags! {
+    pub struct XattrFlags: u16 {
+        const DATA_STREAM = 0x00000001;
+        const DATA_EMBEDDED = 0x00000002;
+        const FILE_SYSTEM_OWNED = 0x00000004;
+        const RESERVED_8 = 0x00000008;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct JXattrVal {
-    flags: u16,
+    pub flags: XattrFlags,
     xdata_len: u16,
-    xdata: Vec<u8>,
+    /* Either the embedded value or a JXattrDstream */
+    pub xdata: Vec<u8>,
 }
 
 impl JXattrVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
-        Ok(Self {
-            flags: source.read_u16::<LittleEndian>()?,
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
+        let mut value = Self {
+            flags: import_flags!(XattrFlags, source.read_u16::<LittleEndian>()?)?,
             xdata_len: source.read_u16::<LittleEndian>()?,
             xdata: vec![],
+        };
+        value.xdata = vec![0u8; value.xdata_len as usize];
+        source.read_exact(&mut value.xdata)?;
+        Ok(value)
+    }
+}
+
+pub const XATTR_DECMPFS_NAME: &str = "com.apple.decmpfs";
+pub const XATTR_RESOURCEFORK_NAME: &str = "com.apple.ResourceFork";
+
+
+// Compression
+
+/* BSD flag of files whose data is kept by decmpfs */
+pub const UF_COMPRESSED: u32 = 0x00000020;
+
+pub const DECMPFS_MAGIC: u32 = u32_code!(b"cmpf");
+pub const DECMPFS_HEADER_SIZE: usize = 16;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum DecmpfsType {
+    UncompressedAttr = 1,
+    ZlibAttr = 3,
+    ZlibRsrc = 4,
+    LzvnAttr = 7,
+    LzvnRsrc = 8,
+    LzfseAttr = 11,
+    LzfseRsrc = 12,
+}
+
+impl DecmpfsType {
+    /* The data is kept in the resource fork rather than after the
+       header in the decmpfs attribute */
+    pub fn in_resource_fork(&self) -> bool {
+        matches!(self, DecmpfsType::ZlibRsrc | DecmpfsType::LzvnRsrc | DecmpfsType::LzfseRsrc)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecmpfsHeader {
+    pub compression_magic: u32,
+    pub compression_type: DecmpfsType,
+    pub uncompressed_size: u64,
+}
+
+impl DecmpfsHeader {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
+        Ok(Self {
+            compression_magic: source.read_u32::<LittleEndian>()?,
+            compression_type: import_enum!(DecmpfsType, from_u32, source.read_u32::<LittleEndian>()?)?,
+            uncompressed_size: source.read_u64::<LittleEndian>()?,
         })
     }
 }
@@ -2019,13 +2218,13 @@ impl JXattrVal {
 
 // Data Streams
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JPhysExtKey {
     //hdr: JKey,
 }
 
 impl JPhysExtKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
@@ -2035,7 +2234,7 @@ const PEXT_LEN_MASK : u64 = 0x0fffffffffffffff;
 const PEXT_KIND_MASK : u64 = 0xf000000000000000;
 const PEXT_KIND_SHIFT : usize = 60;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JPhysExtVal {
     len_and_kind: u64,
     owning_obj_id: u64,
@@ -2043,7 +2242,7 @@ pub struct JPhysExtVal {
 }
 
 impl JPhysExtVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             len_and_kind: source.read_u64::<LittleEndian>()?,
             owning_obj_id: source.read_u64::<LittleEndian>()?,
@@ -2052,14 +2251,14 @@ impl JPhysExtVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JFileExtentKey {
     //hdr: JKey,
-    logical_addr: u64,
+    pub logical_addr: u64,
 }
 
 impl JFileExtentKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             logical_addr: source.read_u64::<LittleEndian>()?,
         })
@@ -2070,59 +2269,97 @@ const J_FILE_EXTENT_LEN_MASK : u64 = 0x00ffffffffffffff;
 const J_FILE_EXTENT_FLAG_MASK : u64 = 0xff00000000000000;
 const J_FILE_EXTENT_FLAG_SHIFT : usize = 56;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JFileExtentVal {
     len_and_flags: u64,
     pub phys_block_num: u64,
-    crypto_id: u64,
+    pub crypto_id: u64,
 }
 
 impl JFileExtentVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             len_and_flags: source.read_u64::<LittleEndian>()?,
             phys_block_num: source.read_u64::<LittleEndian>()?,
             crypto_id: source.read_u64::<LittleEndian>()?,
         })
     }
+
+    pub fn length(&self) -> u64 {
+        self.len_and_flags & J_FILE_EXTENT_LEN_MASK
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct FextTreeKey {
+    pub private_id: u64,
+    pub logical_addr: u64,
+}
+
+impl FextTreeKey {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
+        Ok(Self {
+            private_id: source.read_u64::<LittleEndian>()?,
+            logical_addr: source.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FextTreeVal {
+    len_and_flags: u64,
+    pub phys_block_num: u64,
+}
+
+impl FextTreeVal {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
+        Ok(Self {
+            len_and_flags: source.read_u64::<LittleEndian>()?,
+            phys_block_num: source.read_u64::<LittleEndian>()?,
+        })
+    }
+
+    pub fn length(&self) -> u64 {
+        self.len_and_flags & J_FILE_EXTENT_LEN_MASK
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct JDstreamIdKey {
     //hdr: JKey,
 }
 
 impl JDstreamIdKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDstreamIdVal {
     refcnt: u32,
 }
 
 impl JDstreamIdVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             refcnt: source.read_u32::<LittleEndian>()?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JDstream {
     pub size: u64,
-    alloced_size: u64,
+    pub alloced_size: u64,
     default_crypto_id: u64,
     total_bytes_written: u64,
     total_bytes_read: u64,
 }
 
 impl JDstream {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             size: source.read_u64::<LittleEndian>()?,
             alloced_size: source.read_u64::<LittleEndian>()?,
@@ -2133,14 +2370,14 @@ impl JDstream {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JXattrDstream {
-    xattr_obj_id: u64,
-    dstream: JDstream,
+    pub xattr_obj_id: u64,
+    pub dstream: JDstream,
 }
 
 impl JXattrDstream {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             xattr_obj_id: source.read_u64::<LittleEndian>()?,
             dstream: JDstream::import(source)?,
@@ -2151,7 +2388,10 @@ impl JXattrDstream {
 
 // Extended Fields
 
-#[derive(Debug)]
+pub const XF_BLOB_SIZE: usize = 4;
+pub const XFIELD_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
 pub struct XfBlob {
     pub num_exts: u16,
     used_data: u16,
@@ -2159,7 +2399,7 @@ pub struct XfBlob {
 }
 
 impl XfBlob {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             num_exts: source.read_u16::<LittleEndian>()?,
             used_data: source.read_u16::<LittleEndian>()?,
@@ -2211,7 +2451,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct XField<T: FromPrimitive> {
     pub r#type: T,
     flags: XFieldFlags,
@@ -2219,12 +2459,13 @@ pub struct XField<T: FromPrimitive> {
 }
 
 impl<T: FromPrimitive> XField<T> {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
-            r#type: T::from_u8(source.read_u8()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown extended type"))?,
-            flags: XFieldFlags::from_bits(source.read_u8()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown flags"))?,
+            r#type: {
+                let value = source.read_u8()?;
+                T::from_u8(value).ok_or(ApfsError::UnknownValue { r#type: "extended field type", value: value as u64 })?
+            },
+            flags: import_flags!(XFieldFlags, source.read_u8()?)?,
             size: source.read_u16::<LittleEndian>()?,
         })
     }
@@ -2236,21 +2477,21 @@ pub type XFieldDrec = XField<DrecExtType>;
 
 // Siblings
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSiblingKey {
     //hdr: JKey,
-    sibling_id: u64,
+    pub sibling_id: u64,
 }
 
 impl JSiblingKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             sibling_id: source.read_u64::<LittleEndian>()?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSiblingVal {
     parent_id: u64,
     name_len: u16,
@@ -2258,7 +2499,7 @@ pub struct JSiblingVal {
 }
 
 impl JSiblingVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             parent_id: source.read_u64::<LittleEndian>()?,
             name_len: source.read_u16::<LittleEndian>()?,
@@ -2267,25 +2508,25 @@ impl JSiblingVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSiblingMapKey {
     //hdr: JKey,
 }
 
 impl JSiblingMapKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSiblingMapVal {
     file_id: u64,
 }
 
 impl JSiblingMapVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             file_id: source.read_u64::<LittleEndian>()?,
         })
@@ -2302,19 +2543,19 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSnapMetadataKey {
     //hdr: JKey,
 }
 
 impl JSnapMetadataKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSnapMetadataVal {
     extentref_tree_oid: Oid,
     sblock_oid: Oid,
@@ -2329,7 +2570,7 @@ pub struct JSnapMetadataVal {
 }
 
 impl JSnapMetadataVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             extentref_tree_oid: Oid::import(source)?,
             sblock_oid: Oid::import(source)?,
@@ -2337,20 +2578,19 @@ impl JSnapMetadataVal {
             change_time: source.read_u64::<LittleEndian>()?,
             inum: source.read_u64::<LittleEndian>()?,
             extentref_tree_type: source.read_u32::<LittleEndian>()?,
-            flags: SnapMetaFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown reaper list flags"))?,
+            flags: import_flags!(SnapMetaFlags, source.read_u32::<LittleEndian>()?)?,
             name_len: source.read_u16::<LittleEndian>()?,
             name: String::new(),
         };
         let mut name = vec![0u8; value.name_len as usize];
         source.read_exact(&mut name)?;
         value.name = String::from_utf8(name)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))?;
+            .map_err(|_| ApfsError::InvalidString)?;
         Ok(value)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSnapNameKey {
     //hdr: JKey,
     name_len: u16,
@@ -2359,7 +2599,7 @@ pub struct JSnapNameKey {
 }
 
 impl JSnapNameKey {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             name_len: source.read_u16::<LittleEndian>()?,
             name: String::new(),
@@ -2367,25 +2607,25 @@ impl JSnapNameKey {
         let mut name = vec![0u8; value.name_len as usize];
         source.read_exact(&mut name)?;
         value.name = String::from_utf8(name)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))?;
+            .map_err(|_| ApfsError::InvalidString)?;
         Ok(value)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JSnapNameVal {
     snap_xid: Xid,
 }
 
 impl JSnapNameVal {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             snap_xid: Xid::import(source)?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct SnapMetaExt {
     version: u32,
 
@@ -2397,7 +2637,7 @@ struct SnapMetaExt {
 }
 
 impl SnapMetaExt {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             version: source.read_u32::<LittleEndian>()?,
 
@@ -2410,14 +2650,14 @@ impl SnapMetaExt {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SnapMetaExtObjPhys {
     //smeop_o: ObjPhys,
     sme: SnapMetaExt,
 }
 
 impl SnapMetaExtObjPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             sme: SnapMetaExt::import(source)?,
         })
@@ -2450,7 +2690,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxReaperPhys {
     //nr_o: ObjPhys,
     next_reap_id: u64,
@@ -2470,32 +2710,33 @@ pub struct NxReaperPhys {
 }
 
 impl NxReaperPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             next_reap_id: source.read_u64::<LittleEndian>()?,
             completed_id: source.read_u64::<LittleEndian>()?,
             head: Oid::import(source)?,
             tail: Oid::import(source)?,
-            flags: NrFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown reaper flags"))?,
+            flags: import_flags!(NrFlags, source.read_u32::<LittleEndian>()?)?,
             rlcount: source.read_u32::<LittleEndian>()?,
             r#type: source.read_u32::<LittleEndian>()?,
             size: source.read_u32::<LittleEndian>()?,
             fs_oid: Oid::import(source)?,
             oid: Oid::import(source)?,
             xid: Xid::import(source)?,
-            nrle_flags: NrleFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown reaper list entry flags"))?,
+            nrle_flags: import_flags!(NrleFlags, source.read_u32::<LittleEndian>()?)?,
             state_buffer_size: source.read_u32::<LittleEndian>()?,
             state_buffer: vec![],
         };
-        value.state_buffer.resize(value.state_buffer_size as usize, 0);
-        source.read(&mut value.state_buffer)?;
+        /* Read through take so a corrupt size cannot force a huge allocation */
+        source.take(value.state_buffer_size as u64).read_to_end(&mut value.state_buffer)?;
+        if value.state_buffer.len() != value.state_buffer_size as usize {
+            return Err(ApfsError::Truncated);
+        }
         Ok(value)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct NxReapListEntry {
     next: u32,
     flags: NrleFlags,
@@ -2507,11 +2748,10 @@ struct NxReapListEntry {
 }
 
 impl NxReapListEntry {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             next: source.read_u32::<LittleEndian>()?,
-            flags: NrleFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown reaper list entry flags"))?,
+            flags: import_flags!(NrleFlags, source.read_u32::<LittleEndian>()?)?,
             r#type: source.read_u32::<LittleEndian>()?,
             size: source.read_u32::<LittleEndian>()?,
             fs_oid: Oid::import(source)?,
@@ -2521,7 +2761,7 @@ impl NxReapListEntry {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct NxReapListPhys {
     //nrl_o: ObjPhys,
     next: Oid,
@@ -2535,11 +2775,10 @@ struct NxReapListPhys {
 }
 
 impl NxReapListPhys {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         let mut value = Self {
             next: Oid::import(source)?,
-            flags: NrlFlags::from_bits(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown reaper list flags"))?,
+            flags: import_flags!(NrlFlags, source.read_u32::<LittleEndian>()?)?,
             max: source.read_u32::<LittleEndian>()?,
             count: source.read_u32::<LittleEndian>()?,
             first: source.read_u32::<LittleEndian>()?,
@@ -2555,7 +2794,7 @@ impl NxReapListPhys {
 }
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, PartialEq, FromPrimitive)]
 enum ApfsReapPhase {
     Start = 0,
     Snapshots = 1,
@@ -2564,23 +2803,22 @@ enum ApfsReapPhase {
     Done = 4,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct OmapReapState {
     phase: OmapReapPhase,
     ok: OmapKey,
 }
 
 impl OmapReapState {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
-            phase: OmapReapPhase::from_u32(source.read_u32::<LittleEndian>()?)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown Omap Reap Phase"))?,
+            phase: import_enum!(OmapReapPhase, from_u32, source.read_u32::<LittleEndian>()?)?,
             ok: OmapKey::import(source)?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct OmapCleanupState {
     cleaning: u32,
     omsflags: u32,
@@ -2592,7 +2830,7 @@ struct OmapCleanupState {
 }
 
 impl OmapCleanupState {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             cleaning: source.read_u32::<LittleEndian>()?,
             omsflags: source.read_u32::<LittleEndian>()?,
@@ -2605,7 +2843,7 @@ impl OmapCleanupState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct ApfsReapState {
     last_pbn: u64,
     cur_snap_xid: Xid,
@@ -2613,11 +2851,11 @@ struct ApfsReapState {
 }
 
 impl ApfsReapState {
-    pub fn import(source: &mut dyn Read) -> io::Result<Self> {
+    pub fn import(source: &mut dyn Read) -> Result<Self> {
         Ok(Self {
             last_pbn: source.read_u64::<LittleEndian>()?,
             cur_snap_xid: Xid::import(source)?,
-            phase: ApfsReapPhase::from_u32(source.read_u32::<LittleEndian>()?).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown APFS Reap Phase"))?,
+            phase: import_enum!(ApfsReapPhase, from_u32, source.read_u32::<LittleEndian>()?)?,
         })
     }
 }
@@ -271,7 +271,7 @@ fn test_load_checkpoint_mappings() {
     assert_eq!(mapping.map[0].pad, 0, "pad");
     assert_eq!(mapping.map[0].fs_oid, Oid(0), "fs oid");
     assert_eq!(mapping.map[0].oid, Oid(0x400), "oid");
-    assert_eq!(mapping.map[0].paddr, Oid(0x13), "paddr");
+    assert_eq!(mapping.map[0].paddr, Paddr(0x13), "paddr");
 
     assert_eq!(mapping.map[1].r#type.r#type(), ObjectType::Btree, "type");
     assert_eq!(mapping.map[1].r#type.storage(), StorageType::Ephemeral, "type");
@@ -280,7 +280,7 @@ fn test_load_checkpoint_mappings() {
     assert_eq!(mapping.map[1].pad, 0, "pad");
     assert_eq!(mapping.map[1].fs_oid, Oid(0), "fs oid");
     assert_eq!(mapping.map[1].oid, Oid(0x403), "oid");
-    assert_eq!(mapping.map[1].paddr, Oid(0x14), "paddr");
+    assert_eq!(mapping.map[1].paddr, Paddr(0x14), "paddr");
 
     assert_eq!(mapping.map[2].r#type.r#type(), ObjectType::Btree, "type");
     assert_eq!(mapping.map[2].r#type.storage(), StorageType::Ephemeral, "type");
@@ -289,7 +289,7 @@ fn test_load_checkpoint_mappings() {
     assert_eq!(mapping.map[2].pad, 0, "pad");
     assert_eq!(mapping.map[2].fs_oid, Oid(0), "fs oid");
     assert_eq!(mapping.map[2].oid, Oid(0x405), "oid");
-    assert_eq!(mapping.map[2].paddr, Oid(0x15), "paddr");
+    assert_eq!(mapping.map[2].paddr, Paddr(0x15), "paddr");
 
     assert_eq!(mapping.map[3].r#type.r#type(), ObjectType::NxReaper, "type");
     assert_eq!(mapping.map[3].r#type.storage(), StorageType::Ephemeral, "type");
@@ -298,7 +298,7 @@ fn test_load_checkpoint_mappings() {
     assert_eq!(mapping.map[3].pad, 0, "pad");
     assert_eq!(mapping.map[3].fs_oid, Oid(0), "fs oid");
     assert_eq!(mapping.map[3].oid, Oid(0x401), "oid");
-    assert_eq!(mapping.map[3].paddr, Oid(0x16), "paddr");
+    assert_eq!(mapping.map[3].paddr, Paddr(0x16), "paddr");
 }
 
 #[test]
@@ -10,44 +10,30 @@ extern crate num_derive;
 
 #[cfg(test)]
 mod tests {
-    use std::{path::PathBuf, collections::HashMap};
+    use std::{path::PathBuf, collections::HashMap, io::{Read, Seek, SeekFrom}, time::{Duration, UNIX_EPOCH}};
 
     use super::*;
 
     pub struct DummySource {
-        pub position: u64,
         pub block_size: u64,
         pub blocks: HashMap<u64, Vec<u8>>,
     }
 
-    impl Read for DummySource {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            match self.blocks.get(&(self.position/self.block_size)) {
+    impl BlockSource for DummySource {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            match self.blocks.get(&(offset/self.block_size)) {
                 Some(data) => {
                     buf.copy_from_slice(data);
-                    self.position += buf.len() as u64;
-                    Ok(buf.len())
+                    Ok(())
                 },
                 None => {
                     buf.fill(0);
-                    self.position += buf.len() as u64;
-                    Ok(buf.len())
+                    Ok(())
                 },
             }
         }
     }
 
-    impl Seek for DummySource {
-        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-            self.position = match pos {
-                io::SeekFrom::Start(offset) => offset,
-                io::SeekFrom::End(offset) => 0,
-                io::SeekFrom::Current(offset) => offset as u64 + self.position,
-            };
-            Ok(self.position)
-        }
-    }
-
     pub fn test_dir() -> PathBuf {
         let root = ::std::env::var_os("CARGO_MANIFEST_DIR").map(|x| PathBuf::from(x))
             .unwrap_or_else(|| ::std::env::current_dir().unwrap());
@@ -69,7 +55,7 @@ mod tests {
     pub const TEST_APFS_FILE : &str = "test-apfs.img";
     pub const TEST_16KB_APFS_FILE : &str = "apfs-16k-cs.img";
 
-    fn load_test_apfs_image(file: &str) -> APFS<File> {
+    fn load_test_apfs_image(file: &str) -> APFS<ImageSource> {
         APFS::open(test_dir().join(file)).unwrap()
     }
 
@@ -111,13 +97,13 @@ mod tests {
         assert!(block_result.is_err());
     }
 
-    pub fn load_test_apfs_superblock(file: &str) -> (APFS<File>, NxSuperblockObject) {
+    pub fn load_test_apfs_superblock(file: &str) -> (APFS<ImageSource>, NxSuperblockObject) {
         let mut apfs = load_test_apfs_image(file);
         let object_result = apfs.load_object_addr(Paddr(0));
         assert!(object_result.is_ok());
         let object = object_result.unwrap();
-        let superblock = match object {
-            APFSObject::Superblock(x) => x,
+        let superblock = match *object {
+            APFSObject::Superblock(ref x) => x.clone(),
             _ => { panic!("Wrong object type!"); },
         };
         (apfs, superblock)
@@ -137,28 +123,1137 @@ mod tests {
     #[test]
     fn test_load_block0_bad_checksum() {
         let block = [0u8; NX_DEFAULT_BLOCK_SIZE];
-        let mut source = Cursor::new(&block[..]);
-        let mut apfs = APFS { source, block_size: NX_DEFAULT_BLOCK_SIZE };
+        let mut source = ReadSeekSource::new(Cursor::new(&block[..]));
+        let mut apfs = APFS::new(source, NX_DEFAULT_BLOCK_SIZE);
         let object_result = apfs.load_object_addr(Paddr(0));
         assert!(object_result.is_err(), "failed to detect bad checksum");
     }
 
+    #[test]
+    fn test_load_block0_bad_checksum_error() {
+        let mut block = [0u8; NX_DEFAULT_BLOCK_SIZE];
+        block[0] = 1;
+        let source = &block[..];
+        let apfs = APFS::new(source, NX_DEFAULT_BLOCK_SIZE);
+        match apfs.load_object_addr(Paddr(0)) {
+            Err(ApfsError::BadChecksum { paddr, stored, computed }) => {
+                assert_eq!(paddr, Paddr(0));
+                assert_eq!(stored, 1);
+                assert_eq!(computed, fletcher64(&block[8..]));
+            },
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+    }
+
+    #[test]
+    fn test_load_virtual_object_unsupported() {
+        let block = [0u8; NX_DEFAULT_BLOCK_SIZE];
+        let source = &block[..];
+        let apfs = APFS::new(source, NX_DEFAULT_BLOCK_SIZE);
+        match apfs.load_object_oid(Oid(1026), StorageType::Virtual, None) {
+            Err(ApfsError::UnsupportedStorage { oid, storage }) => {
+                assert_eq!(oid, Oid(1026));
+                assert_eq!(storage, StorageType::Virtual);
+            },
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+    }
+
+    #[test]
+    fn test_open_invalid_block_size() {
+        let mut body = vec![0u8; 64];
+        put_u32(&mut body, 0, NX_MAGIC);
+        put_u32(&mut body, 4, 1000);
+        let block = build_object(NX_DEFAULT_BLOCK_SIZE, 1, 1, ObjectType::NxSuperblock as u32 | StorageType::Ephemeral as u32, 0, &body);
+        assert!(matches!(APFS::open_source(block), Err(ApfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_load_block_out_of_range() {
+        let block = [0u8; NX_DEFAULT_BLOCK_SIZE];
+        let apfs = APFS::new(&block[..], NX_DEFAULT_BLOCK_SIZE);
+        assert!(matches!(apfs.load_block(Paddr(-1)), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(apfs.load_block(Paddr(i64::MAX)), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(apfs.load_block(Paddr(1)), Err(ApfsError::Io(_))));
+    }
+
+    pub fn build_object(size: usize, oid: u64, xid: u64, r#type: u32, subtype: u32, body: &[u8]) -> Vec<u8> {
+        let mut block = vec![0u8; size];
+        block[8..16].copy_from_slice(&oid.to_le_bytes());
+        block[16..24].copy_from_slice(&xid.to_le_bytes());
+        block[24..28].copy_from_slice(&r#type.to_le_bytes());
+        block[28..32].copy_from_slice(&subtype.to_le_bytes());
+        block[32..32+body.len()].copy_from_slice(body);
+        let checksum = fletcher64(&block[8..]);
+        block[0..8].copy_from_slice(&checksum.to_le_bytes());
+        block
+    }
+
+    pub fn put_u32(body: &mut [u8], offset: usize, value: u32) {
+        body[offset..offset+4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(body: &mut [u8], offset: usize, value: u64) {
+        body[offset..offset+8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    const SYNTHETIC_DESC_BASE: u64 = 1;
+    const SYNTHETIC_DESC_BLOCKS: u64 = 4;
+    const SYNTHETIC_DATA_BASE: u64 = 10;
+    const SYNTHETIC_DATA_BLOCKS: u64 = 4;
+    const SYNTHETIC_OMAP_BLOCK: u64 = 30;
+    const SYNTHETIC_OMAP_TREE_BLOCK: u64 = 31;
+
+    /* A single root and leaf node object map B-tree with fixed size
+       records of (oid, xid, flags, paddr) */
+    pub fn build_omap_btree(oid: u64, xid: u64, records: &[(u64, u64, u32, u64)]) -> Vec<u8> {
+        const NODE_HEADER_SIZE: usize = 56;
+        const INFO_SIZE: usize = 40;
+        let mut body = vec![0u8; NX_DEFAULT_BLOCK_SIZE - 32];
+        let data_len = NX_DEFAULT_BLOCK_SIZE - NODE_HEADER_SIZE - INFO_SIZE;
+        let toc_len = 4 * records.len();
+        put_u32(&mut body, 0, (BtnFlags::ROOT | BtnFlags::LEAF | BtnFlags::FIXED_KV_SIZE).bits() as u32);
+        put_u32(&mut body, 4, records.len() as u32);
+        body[10..12].copy_from_slice(&(toc_len as u16).to_le_bytes());
+        for (idx, (oid, xid, flags, paddr)) in records.iter().enumerate() {
+            let k = 16 * idx;
+            let v = 16 * (idx + 1);
+            body[24 + 4 * idx..26 + 4 * idx].copy_from_slice(&(k as u16).to_le_bytes());
+            body[26 + 4 * idx..28 + 4 * idx].copy_from_slice(&(v as u16).to_le_bytes());
+            put_u64(&mut body, 24 + toc_len + k, *oid);
+            put_u64(&mut body, 24 + toc_len + k + 8, *xid);
+            put_u32(&mut body, 24 + data_len - v, *flags);
+            put_u32(&mut body, 24 + data_len - v + 4, NX_DEFAULT_BLOCK_SIZE as u32);
+            put_u64(&mut body, 24 + data_len - v + 8, *paddr);
+        }
+        let info = 24 + data_len;
+        put_u32(&mut body, info + 4, NX_DEFAULT_BLOCK_SIZE as u32);
+        put_u32(&mut body, info + 8, 16);
+        put_u32(&mut body, info + 12, 16);
+        put_u64(&mut body, info + 24, records.len() as u64);
+        put_u64(&mut body, info + 32, 1);
+        build_object(NX_DEFAULT_BLOCK_SIZE, oid, xid, ObjectType::Btree as u32 | StorageType::Physical as u32, ObjectType::Omap as u32, &body)
+    }
+
+    pub fn build_omap(oid: u64, xid: u64, tree_oid: u64) -> Vec<u8> {
+        let tree_type = ObjectType::Btree as u32 | StorageType::Physical as u32;
+        let mut body = vec![0u8; 56];
+        put_u32(&mut body, 8, tree_type);
+        put_u32(&mut body, 12, tree_type);
+        put_u64(&mut body, 16, tree_oid);
+        build_object(NX_DEFAULT_BLOCK_SIZE, oid, xid, ObjectType::Omap as u32 | StorageType::Physical as u32, 0, &body)
+    }
+
+    /* A physical B-tree node with variable size records, where a
+       missing value is stored as a ghost */
+    pub fn build_btree_node(oid: u64, xid: u64, subtype: ObjectType, flags: BtnFlags, level: u16, tree_flags: BtFlags, records: &[(Vec<u8>, Option<Vec<u8>>)]) -> Vec<u8> {
+        const INFO_SIZE: usize = 40;
+        let mut body = vec![0u8; NX_DEFAULT_BLOCK_SIZE - 32];
+        let root = flags.contains(BtnFlags::ROOT);
+        let data_len = body.len() - 24 - if root { INFO_SIZE } else { 0 };
+        let toc_len = 8 * records.len();
+        body[0..2].copy_from_slice(&flags.bits().to_le_bytes());
+        body[2..4].copy_from_slice(&level.to_le_bytes());
+        put_u32(&mut body, 4, records.len() as u32);
+        body[10..12].copy_from_slice(&(toc_len as u16).to_le_bytes());
+        let (mut k, mut v) = (0, 0);
+        for (idx, (key, value)) in records.iter().enumerate() {
+            let toc = 24 + 8 * idx;
+            body[toc..toc+2].copy_from_slice(&(k as u16).to_le_bytes());
+            body[toc+2..toc+4].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            body[24+toc_len+k..24+toc_len+k+key.len()].copy_from_slice(key);
+            k += key.len();
+            match value {
+                Some(value) => {
+                    v += value.len();
+                    body[toc+4..toc+6].copy_from_slice(&(v as u16).to_le_bytes());
+                    body[toc+6..toc+8].copy_from_slice(&(value.len() as u16).to_le_bytes());
+                    body[24+data_len-v..24+data_len-v+value.len()].copy_from_slice(value);
+                },
+                None => body[toc+4..toc+6].copy_from_slice(&BTOFF_INVALID.to_le_bytes()),
+            }
+        }
+        body[12..14].copy_from_slice(&(k as u16).to_le_bytes());
+        body[14..16].copy_from_slice(&((data_len - toc_len - k - v) as u16).to_le_bytes());
+        let r#type = if root { ObjectType::Btree } else { ObjectType::BtreeNode };
+        if root {
+            let info = 24 + data_len;
+            let longest_key = records.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+            let longest_val = records.iter().filter_map(|(_, value)| value.as_ref().map(|value| value.len())).max().unwrap_or(0);
+            put_u32(&mut body, info, tree_flags.bits());
+            put_u32(&mut body, info + 4, NX_DEFAULT_BLOCK_SIZE as u32);
+            put_u32(&mut body, info + 16, longest_key as u32);
+            put_u32(&mut body, info + 20, longest_val as u32);
+            put_u64(&mut body, info + 24, records.len() as u64);
+            put_u64(&mut body, info + 32, 1);
+        }
+        build_object(NX_DEFAULT_BLOCK_SIZE, oid, xid, r#type as u32 | StorageType::Physical as u32, subtype as u32, &body)
+    }
+
+    pub fn fs_key(oid: u64, r#type: JObjTypes, subkey: &[u8]) -> Vec<u8> {
+        let mut key = (oid | ((r#type as u64) << 60)).to_le_bytes().to_vec();
+        key.extend_from_slice(subkey);
+        key
+    }
+
+    fn build_nx_superblock(xid: u64, desc_index: u32) -> Vec<u8> {
+        let mut body = vec![0u8; 1024];
+        put_u32(&mut body, 0, NX_MAGIC);
+        put_u32(&mut body, 4, NX_DEFAULT_BLOCK_SIZE as u32);
+        put_u32(&mut body, 72, SYNTHETIC_DESC_BLOCKS as u32);
+        put_u32(&mut body, 76, SYNTHETIC_DATA_BLOCKS as u32);
+        put_u64(&mut body, 80, SYNTHETIC_DESC_BASE);
+        put_u64(&mut body, 88, SYNTHETIC_DATA_BASE);
+        put_u32(&mut body, 104, desc_index);
+        put_u32(&mut body, 108, 2);
+        put_u64(&mut body, 120, 0x400);
+        put_u64(&mut body, 128, SYNTHETIC_OMAP_BLOCK);
+        put_u64(&mut body, 136, 0x401);
+        put_u64(&mut body, 152, 0x402);
+        build_object(NX_DEFAULT_BLOCK_SIZE, 1, xid, ObjectType::NxSuperblock as u32 | StorageType::Ephemeral as u32, 0, &body)
+    }
+
+    fn build_checkpoint_map(xid: u64, mappings: &[(u32, u32, u64, u64)]) -> Vec<u8> {
+        let mut body = vec![0u8; 8 + 40 * mappings.len()];
+        put_u32(&mut body, 0, CpmFlags::LAST.bits());
+        put_u32(&mut body, 4, mappings.len() as u32);
+        for (idx, (r#type, size, oid, paddr)) in mappings.iter().enumerate() {
+            let offset = 8 + 40 * idx;
+            put_u32(&mut body, offset, *r#type | StorageType::Ephemeral as u32);
+            put_u32(&mut body, offset + 8, *size);
+            put_u64(&mut body, offset + 24, *oid);
+            put_u64(&mut body, offset + 32, *paddr);
+        }
+        build_object(NX_DEFAULT_BLOCK_SIZE, 0, xid, ObjectType::CheckpointMap as u32 | StorageType::Physical as u32, 0, &body)
+    }
+
+    /* Two checkpoints in the descriptor ring with the newest mapping a
+       two block object that wraps around the end of the data ring */
+    fn load_synthetic_checkpoints() -> APFS<DummySource> {
+        let reaper_type = ObjectType::NxReaper as u32 | StorageType::Ephemeral as u32;
+        let mut source = DummySource {
+            block_size: NX_DEFAULT_BLOCK_SIZE as u64,
+            blocks: HashMap::new(),
+        };
+        source.blocks.insert(0, build_nx_superblock(1, 0));
+        source.blocks.insert(1, build_checkpoint_map(1, &[(ObjectType::Spaceman as u32, 4096, 0x400, 10)]));
+        source.blocks.insert(2, build_nx_superblock(1, 0));
+        source.blocks.insert(3, build_checkpoint_map(2, &[
+            (ObjectType::Spaceman as u32, 4096, 0x400, 12),
+            (ObjectType::NxReaper as u32, 8192, 0x401, 13),
+        ]));
+        source.blocks.insert(4, build_nx_superblock(2, 2));
+        source.blocks.insert(12, build_object(NX_DEFAULT_BLOCK_SIZE, 0x400, 2, ObjectType::Spaceman as u32 | StorageType::Ephemeral as u32, 0, &[]));
+        let reaper = build_object(2 * NX_DEFAULT_BLOCK_SIZE, 0x401, 2, reaper_type, 0, &[]);
+        source.blocks.insert(13, reaper[..NX_DEFAULT_BLOCK_SIZE].to_vec());
+        source.blocks.insert(10, reaper[NX_DEFAULT_BLOCK_SIZE..].to_vec());
+        source.blocks.insert(SYNTHETIC_OMAP_BLOCK, build_omap(SYNTHETIC_OMAP_BLOCK, 2, SYNTHETIC_OMAP_TREE_BLOCK));
+        source.blocks.insert(SYNTHETIC_OMAP_TREE_BLOCK, build_omap_btree(SYNTHETIC_OMAP_TREE_BLOCK, 2, &[
+            (0x402, 1, 0, 20),
+            (0x402, 3, 0, 21),
+        ]));
+        source.blocks.insert(20, build_object(NX_DEFAULT_BLOCK_SIZE, 0x402, 1, ObjectType::Fs as u32 | StorageType::Virtual as u32, 0, &[]));
+        APFS::new(source, NX_DEFAULT_BLOCK_SIZE)
+    }
+
+    fn checkpoints_with_desc_area(desc_blocks: u32, desc_base: u64) -> Result<Vec<Checkpoint>> {
+        let mut image = vec![0u8; 5 * NX_DEFAULT_BLOCK_SIZE];
+        let mut block = build_nx_superblock(1, 0);
+        put_u32(&mut block, 32 + 72, desc_blocks);
+        put_u64(&mut block, 32 + 80, desc_base);
+        let checksum = fletcher64(&block[8..]);
+        block[0..8].copy_from_slice(&checksum.to_le_bytes());
+        image[..NX_DEFAULT_BLOCK_SIZE].copy_from_slice(&block);
+        APFSMount::checkpoints(&APFS::new(image, NX_DEFAULT_BLOCK_SIZE))
+    }
+
+    #[test]
+    fn checkpoint_descriptor_area_must_lie_within_the_image() {
+        assert!(checkpoints_with_desc_area(4, 1).unwrap().is_empty());
+        assert!(matches!(checkpoints_with_desc_area(5, 1), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(checkpoints_with_desc_area(0x7fffffff, 1), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(checkpoints_with_desc_area(4, u64::MAX), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(checkpoints_with_desc_area((1 << 31) | 4, 1), Err(ApfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn mounting_synthetic_checkpoints_selects_latest() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.superblock.header.xid, Xid(2));
+        assert_eq!(mount.checkpoint_maps.len(), 1);
+        assert_eq!(mount.checkpoint_maps[0].body.map.len(), 2);
+    }
+
+    #[test]
+    fn mounting_synthetic_checkpoints_skips_mismatched_map() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.insert(3, build_checkpoint_map(3, &[]));
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.superblock.header.xid, Xid(1));
+    }
+
+    #[test]
+    fn can_load_ephemeral_objects() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        let spaceman_oid = mount.superblock.body.spaceman_oid;
+        let object = mount.load_object_oid(spaceman_oid, StorageType::Ephemeral).unwrap();
+        assert_eq!(object.object_type(), ObjectType::Spaceman);
+        assert_eq!(object.header().oid, Oid(0x400));
+        let reaper_oid = mount.superblock.body.reaper_oid;
+        let object = mount.load_object_oid(reaper_oid, StorageType::Ephemeral).unwrap();
+        assert_eq!(object.object_type(), ObjectType::NxReaper);
+        assert_eq!(object.header().oid, Oid(0x401));
+    }
+
+    #[test]
+    fn missing_ephemeral_object_is_not_found() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        match mount.load_object_oid(Oid(0x402), StorageType::Ephemeral) {
+            Err(ApfsError::ObjectNotFound { oid, xid }) => {
+                assert_eq!(oid, Oid(0x402));
+                assert_eq!(xid, Xid(2));
+            },
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+    }
+
+    #[test]
+    fn can_load_container_objects_from_mount() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.xid(), Xid(2));
+        assert_eq!(mount.superblock().body.magic, NX_MAGIC);
+        assert_eq!(mount.object_map().unwrap().header.oid, Oid(SYNTHETIC_OMAP_BLOCK));
+        assert_eq!(mount.spaceman().unwrap().header.oid, Oid(0x400));
+        assert_eq!(mount.reaper().unwrap().header.oid, Oid(0x401));
+        assert!(mount.efi_jumpstart().unwrap().is_none());
+    }
+
+    #[test]
+    fn can_load_virtual_objects_through_object_map() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        let omap = mount.object_map_btree();
+        assert!(Arc::ptr_eq(&omap, &mount.object_map_btree()));
+        let object = mount.apfs().load_object_oid(Oid(0x402), StorageType::Virtual, Some((&omap, Xid(2)))).unwrap();
+        assert_eq!(object.object_type(), ObjectType::Fs);
+        assert_eq!(object.header().xid, Xid(1));
+        match mount.apfs().load_object_oid(Oid(0x403), StorageType::Virtual, Some((&omap, Xid(2)))) {
+            Err(ApfsError::ObjectNotFound { oid, .. }) => assert_eq!(oid, Oid(0x403)),
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+        assert_eq!(mount.load_object_oid(Oid(0x402), StorageType::Virtual).unwrap().header().oid, Oid(0x402));
+    }
+
+    const SYNTHETIC_OMAP_SNAPSHOT_BLOCK: u64 = 32;
+
+    /* Object map snapshots at transactions 1 and 2, where the second
+       has been deleted */
+    fn add_omap_snapshots(apfs: &mut APFS<DummySource>) {
+        let mut omap = build_omap(SYNTHETIC_OMAP_BLOCK, 2, SYNTHETIC_OMAP_TREE_BLOCK);
+        put_u32(&mut omap, 32 + 4, 2);
+        put_u64(&mut omap, 32 + 24, SYNTHETIC_OMAP_SNAPSHOT_BLOCK);
+        put_u64(&mut omap, 32 + 32, 2);
+        let checksum = fletcher64(&omap[8..]);
+        omap[0..8].copy_from_slice(&checksum.to_le_bytes());
+        let snapshot = |flags: OmsFlags| {
+            let mut value = vec![0u8; 16];
+            put_u32(&mut value, 0, flags.bits());
+            Some(value)
+        };
+        apfs.source.blocks.insert(SYNTHETIC_OMAP_BLOCK, omap);
+        apfs.source.blocks.insert(SYNTHETIC_OMAP_SNAPSHOT_BLOCK, build_btree_node(SYNTHETIC_OMAP_SNAPSHOT_BLOCK, 2, ObjectType::OmapSnapshot, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::PHYSICAL, &[
+            (1u64.to_le_bytes().to_vec(), snapshot(OmsFlags::empty())),
+            (2u64.to_le_bytes().to_vec(), snapshot(OmsFlags::DELETED)),
+        ]));
+    }
+
+    #[test]
+    fn object_map_without_snapshots_has_no_snapshot_tree() {
+        let mount = APFSMount::mount(load_synthetic_checkpoints()).unwrap();
+        assert!(mount.object_map_snapshots().unwrap().is_none());
+    }
+
+    #[test]
+    fn can_list_object_map_snapshots() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_omap_snapshots(&mut apfs);
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.object_map().unwrap().body.snap_count, 2);
+        let snapshots = mount.object_map_snapshots().unwrap().expect("Missing snapshot tree");
+        let flags: Vec<_> = snapshots.iter(mount.apfs()).map(|record| {
+            let record = record.unwrap();
+            (record.key, record.value.flags)
+        }).collect();
+        assert_eq!(flags, vec![(Xid(1), OmsFlags::empty()), (Xid(2), OmsFlags::DELETED)]);
+        assert_eq!(mount.apfs().snapshot_xids(&snapshots).unwrap(), vec![Xid(1)]);
+    }
+
+    #[test]
+    fn can_load_objects_at_live_snapshots_only() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_omap_snapshots(&mut apfs);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let omap = mount.object_map_btree();
+        let snapshots = mount.object_map_snapshots().unwrap().expect("Missing snapshot tree");
+        let object = mount.apfs().load_object_snapshot(&omap, &snapshots, Oid(0x402), Xid(1)).unwrap();
+        assert_eq!(object.header().xid, Xid(1));
+        match mount.apfs().load_object_snapshot(&omap, &snapshots, Oid(0x402), Xid(2)) {
+            Err(ApfsError::SnapshotDeleted { xid }) => assert_eq!(xid, Xid(2)),
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+        match mount.apfs().load_object_snapshot(&omap, &snapshots, Oid(0x402), Xid(3)) {
+            Err(ApfsError::SnapshotNotFound { xid }) => assert_eq!(xid, Xid(3)),
+            other => { panic!("Unexpected result: {:?}", other.err()); },
+        }
+    }
+
+    #[test]
+    fn can_list_volumes_from_mount() {
+        let apfs = load_synthetic_checkpoints();
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.volume_oids(), vec![Oid(0x402)]);
+        let volumes = mount.volumes().unwrap();
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].header.oid, Oid(0x402));
+        assert_eq!(volumes[0].header.xid, Xid(1));
+    }
+
+    const SYNTHETIC_VOLUME_UUID: [u8; 16] = [0x5a; 16];
+
+    /* Give volume 0x402 a name, role and trees: an object map at block
+       40 mapping its root tree 0x404 to block 42, and a physical
+       extent reference tree at block 43 */
+    fn add_volume_trees(apfs: &mut APFS<DummySource>, incompat: VolumeIncompatFlags, records: &[(Vec<u8>, Option<Vec<u8>>)]) {
+        let mut body = vec![0u8; NX_DEFAULT_BLOCK_SIZE - 32];
+        put_u32(&mut body, 0, APFS_MAGIC);
+        put_u64(&mut body, 24, incompat.bits());
+        put_u32(&mut body, 84, ObjectType::Btree as u32 | StorageType::Virtual as u32);
+        put_u32(&mut body, 88, ObjectType::Btree as u32 | StorageType::Physical as u32);
+        put_u64(&mut body, 96, 40);
+        put_u64(&mut body, 104, 0x404);
+        put_u64(&mut body, 112, 43);
+        body[208..224].copy_from_slice(&SYNTHETIC_VOLUME_UUID);
+        put_u64(&mut body, 232, VolumeFlags::UNENCRYPTED.bits());
+        body[672..676].copy_from_slice(b"Data");
+        body[932..934].copy_from_slice(&VolumeRoles::ROLE_DATA.bits().to_le_bytes());
+        let sources = &mut apfs.source.blocks;
+        sources.insert(20, build_object(NX_DEFAULT_BLOCK_SIZE, 0x402, 1, ObjectType::Fs as u32 | StorageType::Virtual as u32, 0, &body));
+        sources.insert(40, build_omap(40, 1, 41));
+        sources.insert(41, build_omap_btree(41, 1, &[(0x404, 1, 0, 42)]));
+        sources.insert(42, build_btree_node(0x404, 1, ObjectType::Fstree, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::empty(), records));
+        sources.insert(43, build_btree_node(43, 1, ObjectType::Blockreftree, BtnFlags::ROOT | BtnFlags::LEAF, 0, BtFlags::PHYSICAL, &[]));
+    }
+
+    #[test]
+    fn can_open_volume_by_index() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &[]);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.index(), 0);
+        assert_eq!(volume.xid(), Xid(2));
+        assert_eq!(volume.name().unwrap(), "Data");
+        assert_eq!(volume.uuid(), Uuid::from_bytes(SYNTHETIC_VOLUME_UUID));
+        assert_eq!(volume.role(), VolumeRoles::ROLE_DATA);
+        assert_eq!(volume.crypto_flags(), VolumeFlags::UNENCRYPTED);
+        assert!(!volume.is_encrypted());
+        assert!(!volume.is_sealed());
+        assert_eq!(mount.apfs().resolve_virtual_oid(volume.object_map(), Oid(0x404), Xid(2)).unwrap().paddr, Paddr(42));
+        assert_eq!(volume.root_tree().iter(mount.apfs()).count(), 0);
+        assert_eq!(volume.extentref_tree().expect("Missing extent reference tree").iter(mount.apfs()).count(), 0);
+        assert!(matches!(volume.extents(), ExtentTree::FsTree(_)));
+    }
+
+    #[test]
+    fn can_find_volume_by_name_uuid_and_role() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &[]);
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert_eq!(mount.volume_by_name("Data").unwrap().index(), 0);
+        assert_eq!(mount.volume_by_uuid(&Uuid::from_bytes(SYNTHETIC_VOLUME_UUID)).unwrap().index(), 0);
+        assert_eq!(mount.volume_by_role(VolumeRoles::ROLE_DATA).unwrap().index(), 0);
+    }
+
+    #[test]
+    fn missing_volume_is_not_found() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &[]);
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert!(matches!(mount.volume(1), Err(ApfsError::VolumeNotFound(_))));
+        assert!(matches!(mount.volume_by_name("System"), Err(ApfsError::VolumeNotFound(_))));
+        assert!(matches!(mount.volume_by_uuid(&Uuid::nil()), Err(ApfsError::VolumeNotFound(_))));
+        assert!(matches!(mount.volume_by_role(VolumeRoles::ROLE_SYSTEM), Err(ApfsError::VolumeNotFound(_))));
+    }
+
+    pub fn inode_val(parent_id: u64) -> Option<Vec<u8>> {
+        let mut value = vec![0u8; 92];
+        put_u64(&mut value, 0, parent_id);
+        Some(value)
+    }
+
+    pub fn inode_val_with_dstream(parent_id: u64, private_id: u64, size: u64) -> Option<Vec<u8>> {
+        let mut value = inode_val(parent_id).unwrap();
+        put_u64(&mut value, 8, private_id);
+        value.extend_from_slice(&1u16.to_le_bytes());
+        value.extend_from_slice(&40u16.to_le_bytes());
+        value.extend_from_slice(&[InoExtType::Dstream as u8, 0]);
+        value.extend_from_slice(&40u16.to_le_bytes());
+        value.extend_from_slice(&size.to_le_bytes());
+        value.extend_from_slice(&[0u8; 32]);
+        Some(value)
+    }
+
+    /* Directory entry keys carry the name length and the hash the
+       volume derives from the name */
+    pub fn drec_key(parent: u64, incompat: VolumeIncompatFlags, name: &str) -> Vec<u8> {
+        let hash = volume::name_hash(name, incompat.contains(VolumeIncompatFlags::CASE_INSENSITIVE));
+        raw_drec_key(parent, hash, name.as_bytes())
+    }
+
+    pub fn raw_drec_key(parent: u64, hash: u32, name: &[u8]) -> Vec<u8> {
+        let mut subkey = ((name.len() as u32 + 1) | (hash << 10)).to_le_bytes().to_vec();
+        subkey.extend_from_slice(name);
+        subkey.push(0);
+        fs_key(parent, JObjTypes::DirRec, &subkey)
+    }
+
+    pub fn drec_val(file_id: u64, file_type: FileType) -> Option<Vec<u8>> {
+        let mut value = file_id.to_le_bytes().to_vec();
+        value.extend_from_slice(&0u64.to_le_bytes());
+        value.extend_from_slice(&(file_type as u16).to_le_bytes());
+        Some(value)
+    }
+
+    pub fn drec_val_with_sibling(file_id: u64, file_type: FileType, sibling_id: u64) -> Option<Vec<u8>> {
+        let mut value = drec_val(file_id, file_type).unwrap();
+        value.extend_from_slice(&1u16.to_le_bytes());
+        value.extend_from_slice(&8u16.to_le_bytes());
+        value.extend_from_slice(&[DrecExtType::DrecExtTypeSiblingId as u8, 0]);
+        value.extend_from_slice(&8u16.to_le_bytes());
+        value.extend_from_slice(&sibling_id.to_le_bytes());
+        Some(value)
+    }
+
+    /* The root holds a directory, a file and a name stored decomposed,
+       and the directory holds a symlink and a name that only matches
+       its upper case form under full case folding. Entries are ordered
+       by the hash of their name, which depends on the volume's flags */
+    pub fn directory_records(incompat: VolumeIncompatFlags) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let entries = |parent: u64, mut entries: Vec<(&'static str, Option<Vec<u8>>)>| {
+            entries.sort_by_key(|&(name, _)| volume::name_hash(name, incompat.contains(VolumeIncompatFlags::CASE_INSENSITIVE)));
+            entries.into_iter().map(move |(name, value)| (drec_key(parent, incompat, name), value))
+        };
+        let mut records = vec![(fs_key(ROOT_DIR_INO_NUM, JObjTypes::Inode, &[]), inode_val(ROOT_DIR_PARENT))];
+        records.extend(entries(ROOT_DIR_INO_NUM, vec![
+            ("dir", drec_val(16, FileType::Directory)),
+            ("file.txt", drec_val_with_sibling(17, FileType::Regular, 0x30)),
+            ("Cafe\u{301}", drec_val(18, FileType::Regular)),
+        ]));
+        records.push((fs_key(16, JObjTypes::Inode, &[]), inode_val(ROOT_DIR_INO_NUM)));
+        records.extend(entries(16, vec![
+            ("inner", drec_val(19, FileType::Symlink)),
+            ("Stra\u{df}e", drec_val(21, FileType::Regular)),
+        ]));
+        records.push((fs_key(17, JObjTypes::Inode, &[]), inode_val(ROOT_DIR_INO_NUM)));
+        records
+    }
+
+    const FILE_SIZE: u64 = 10000;
+
+    pub fn xattr_key(ino: u64, name: &str) -> Vec<u8> {
+        let mut subkey = (name.len() as u16 + 1).to_le_bytes().to_vec();
+        subkey.extend_from_slice(name.as_bytes());
+        subkey.push(0);
+        fs_key(ino, JObjTypes::Xattr, &subkey)
+    }
+
+    pub fn xattr_val(flags: XattrFlags, xdata: &[u8]) -> Option<Vec<u8>> {
+        let mut value = flags.bits().to_le_bytes().to_vec();
+        value.extend_from_slice(&(xdata.len() as u16).to_le_bytes());
+        value.extend_from_slice(xdata);
+        Some(value)
+    }
+
+    const XATTR_STREAM_ID: u64 = 30;
+    const XATTR_STREAM_SIZE: u64 = 5000;
+
+    /* File 17 has a data block, a sparse extent and a second data block
+       cut short by its size, and file 20 has a size but no extents.
+       File 17 also has an embedded extended attribute and one stored
+       in a data stream of two blocks */
+    fn file_records(apfs: &mut APFS<DummySource>) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let extent = |id: u64, offset: u64, length: u64, block: u64| {
+            let mut value = length.to_le_bytes().to_vec();
+            value.extend_from_slice(&block.to_le_bytes());
+            value.extend_from_slice(&0u64.to_le_bytes());
+            (fs_key(id, JObjTypes::FileExtent, &offset.to_le_bytes()), Some(value))
+        };
+        apfs.source.blocks.insert(60, vec![b'a'; NX_DEFAULT_BLOCK_SIZE]);
+        apfs.source.blocks.insert(61, vec![b'b'; NX_DEFAULT_BLOCK_SIZE]);
+        apfs.source.blocks.insert(62, vec![b'x'; NX_DEFAULT_BLOCK_SIZE]);
+        apfs.source.blocks.insert(63, vec![b'y'; NX_DEFAULT_BLOCK_SIZE]);
+        let mut stream = XATTR_STREAM_ID.to_le_bytes().to_vec();
+        stream.extend_from_slice(&XATTR_STREAM_SIZE.to_le_bytes());
+        stream.extend_from_slice(&[0u8; 32]);
+        let mut records = directory_records(VolumeIncompatFlags::empty());
+        records.pop();
+        records.extend([
+            (fs_key(17, JObjTypes::Inode, &[]), inode_val_with_dstream(ROOT_DIR_INO_NUM, 17, FILE_SIZE)),
+            (xattr_key(17, "com.apple.ResourceFork"), xattr_val(XattrFlags::DATA_STREAM, &stream)),
+            (xattr_key(17, "com.apple.quarantine"), xattr_val(XattrFlags::DATA_EMBEDDED, b"0081;5f5e1000;Safari;")),
+            extent(17, 0, 4096, 60),
+            extent(17, 4096, 4096, 0),
+            extent(17, 8192, 4096, 61),
+            (fs_key(20, JObjTypes::Inode, &[]), inode_val_with_dstream(ROOT_DIR_INO_NUM, 20, 5000)),
+            extent(XATTR_STREAM_ID, 0, 8192, 62),
+        ]);
+        records
+    }
+
+    fn expected_file() -> Vec<u8> {
+        let mut expected = vec![b'a'; 4096];
+        expected.extend_from_slice(&[0; 4096]);
+        expected.extend_from_slice(&[b'b'; FILE_SIZE as usize - 8192]);
+        expected
+    }
+
+    #[test]
+    fn can_read_files_with_sparse_extents() {
+        let mut apfs = load_synthetic_checkpoints();
+        let records = file_records(&mut apfs);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let mut file = volume.open_file(17).unwrap();
+        assert_eq!(file.len(), FILE_SIZE);
+        let mut data = vec![];
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected_file());
+        let mut data = vec![];
+        volume.open_file(20).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0; 5000]);
+        assert!(volume.open_file(ROOT_DIR_INO_NUM).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_encrypted_extents() {
+        let mut apfs = load_synthetic_checkpoints();
+        let mut records = file_records(&mut apfs);
+        let mut value = 4096u64.to_le_bytes().to_vec();
+        value.extend_from_slice(&63u64.to_le_bytes());
+        value.extend_from_slice(&5u64.to_le_bytes());
+        let stream_extent = records.pop().unwrap();
+        records.push((fs_key(20, JObjTypes::FileExtent, &0u64.to_le_bytes()), Some(value)));
+        records.push(stream_extent);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let err = volume.open_file(20).unwrap().read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn can_read_extended_attributes() {
+        let mut apfs = load_synthetic_checkpoints();
+        let records = file_records(&mut apfs);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.list_xattrs(17).unwrap(), vec!["com.apple.ResourceFork", "com.apple.quarantine"]);
+        assert_eq!(volume.get_xattr(17, "com.apple.quarantine").unwrap().unwrap(), b"0081;5f5e1000;Safari;");
+        let mut expected = vec![b'x'; 4096];
+        expected.extend_from_slice(&[b'y'; XATTR_STREAM_SIZE as usize - 4096]);
+        assert_eq!(volume.get_xattr(17, "com.apple.ResourceFork").unwrap().unwrap(), expected);
+        assert!(volume.get_xattr(17, "com.apple.FinderInfo").unwrap().is_none());
+        assert!(volume.list_xattrs(20).unwrap().is_empty());
+        assert!(volume.get_xattr(20, "com.apple.quarantine").unwrap().is_none());
+    }
+
+    fn detailed_inode(internal_flags: u64) -> Option<Vec<u8>> {
+        let mut value = inode_val_with_dstream(ROOT_DIR_INO_NUM, 17, FILE_SIZE).unwrap();
+        put_u64(&mut value, 16, 1_600_000_000_000_000_001);
+        put_u64(&mut value, 24, 1_600_000_001_000_000_000);
+        put_u64(&mut value, 32, 1_600_000_002_000_000_000);
+        put_u64(&mut value, 40, 1_600_000_003_000_000_000);
+        put_u64(&mut value, 48, internal_flags);
+        put_u32(&mut value, 56, 2);
+        put_u32(&mut value, 64, 7);
+        put_u32(&mut value, 68, 0x8000);
+        put_u32(&mut value, 72, 501);
+        put_u32(&mut value, 76, 20);
+        value[80..82].copy_from_slice(&0o100644u16.to_le_bytes());
+        put_u64(&mut value, 92 + 8 + 8, 3 * 4096);
+        Some(value)
+    }
+
+    #[test]
+    fn can_read_inode_metadata() {
+        let mut apfs = load_synthetic_checkpoints();
+        let mut records = directory_records(VolumeIncompatFlags::empty());
+        let flags = InodeFlags::HAS_FINDER_INFO | InodeFlags::IS_SPARSE;
+        records.pop();
+        records.push((fs_key(17, JObjTypes::Inode, &[]), detailed_inode(flags.bits())));
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let (ino, _) = volume.lookup("/file.txt").unwrap();
+        let metadata = volume.metadata(ino).unwrap();
+        let time = |secs: u64, nanos: u32| UNIX_EPOCH + Duration::new(secs, nanos);
+        assert_eq!(metadata, Metadata {
+            ino: 17,
+            parent_id: ROOT_DIR_INO_NUM,
+            mode: 0o100644,
+            file_type: FileType::Regular,
+            uid: 501,
+            gid: 20,
+            nlink: 2,
+            created: time(1_600_000_000, 1),
+            modified: time(1_600_000_001, 0),
+            changed: time(1_600_000_002, 0),
+            accessed: time(1_600_000_003, 0),
+            bsd_flags: 0x8000,
+            internal_flags: flags,
+            size: FILE_SIZE,
+            allocated_size: 3 * 4096,
+            generation: 7,
+        });
+        assert_eq!(metadata.permissions(), 0o644);
+        assert!(metadata.is_file());
+        assert!(!volume.metadata(16).unwrap().is_dir());
+    }
+
+    #[test]
+    fn unknown_inode_flags_are_rejected() {
+        let mut apfs = load_synthetic_checkpoints();
+        let mut records = directory_records(VolumeIncompatFlags::empty());
+        records.pop();
+        records.push((fs_key(17, JObjTypes::Inode, &[]), detailed_inode(0x80000000)));
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        assert!(matches!(mount.volume(0), Err(ApfsError::UnknownFlags { r#type: "InodeFlags", .. })));
+    }
+
+    #[test]
+    fn can_seek_in_files() {
+        let mut apfs = load_synthetic_checkpoints();
+        let records = file_records(&mut apfs);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let mut file = volume.open_file(17).unwrap();
+        let mut buf = [0xffu8; 12];
+        assert_eq!(file.seek(SeekFrom::Start(4090)).unwrap(), 4090);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &expected_file()[4090..4102]);
+        assert_eq!(file.seek(SeekFrom::Current(4090)).unwrap(), 8192);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [b'b'; 12]);
+        assert_eq!(file.seek(SeekFrom::End(-4)).unwrap(), FILE_SIZE - 4);
+        let mut tail = vec![];
+        assert_eq!(file.read_to_end(&mut tail).unwrap(), 4);
+        assert_eq!(file.seek(SeekFrom::End(10)).unwrap(), FILE_SIZE + 10);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert!(file.seek(SeekFrom::Current(-(FILE_SIZE as i64) - 11)).is_err());
+    }
+
+    fn compressed_inode() -> Option<Vec<u8>> {
+        let mut value = inode_val(ROOT_DIR_INO_NUM).unwrap();
+        put_u32(&mut value, 68, UF_COMPRESSED);
+        Some(value)
+    }
+
+    fn decmpfs_xattr(r#type: u32, size: u64, data: &[u8]) -> Option<Vec<u8>> {
+        let mut xattr = DECMPFS_MAGIC.to_le_bytes().to_vec();
+        xattr.extend_from_slice(&r#type.to_le_bytes());
+        xattr.extend_from_slice(&size.to_le_bytes());
+        xattr.extend_from_slice(data);
+        xattr_val(XattrFlags::DATA_EMBEDDED, &xattr)
+    }
+
+    const LZVN_FORK_ID: u64 = 40;
+
+    fn zlib_contents() -> Vec<u8> {
+        let mut contents: Vec<u8> = b"zlib".iter().copied().cycle().take(0x10000).collect();
+        contents.extend_from_slice(b"raw tail");
+        contents
+    }
+
+    fn lzvn_contents() -> Vec<u8> {
+        let mut contents = vec![b'q'; 0x10000];
+        contents.extend_from_slice(b"tail data!");
+        contents
+    }
+
+    /* Files 21, 22, 23 and 26 keep zlib, LZVN, LZFSE and uncompressed
+       data in the decmpfs attribute. File 24 has an LZVN resource fork
+       in a data stream and file 25 an embedded zlib resource fork, both
+       of two blocks with the second stored as is. File 27 uses a
+       compression type that is not supported */
+    fn compressed_records(apfs: &mut APFS<DummySource>) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let zlib = miniz_oxide::deflate::compress_to_vec_zlib(b"compressed with zlib, compressed with zlib", 6);
+        let lzvn = [0xe6, b'h', b'e', b'l', b'l', b'o', b' ', 0x38, 0x06, 0xf1, 0xe1, b'!', 0x06];
+        let mut lzfse = u32_code!(b"-xvb").to_le_bytes().to_vec();
+        lzfse.extend_from_slice(&11u32.to_le_bytes());
+        lzfse.extend_from_slice(b"plain lzfse");
+        lzfse.extend_from_slice(&u32_code!(b"$xvb").to_le_bytes());
+
+        /* Block offset table, then a run of 'q' and a stored block */
+        let mut block = vec![0xe1, b'q', 0x07, 0x01, 0x00];
+        let mut remaining = 0x10000 - 4;
+        while remaining > 0 {
+            let length = remaining.min(271);
+            block.extend_from_slice(&[0xf0, (length - 16) as u8]);
+            remaining -= length;
+        }
+        block.push(0x06);
+        let mut fork = vec![];
+        for offset in [12, 12 + block.len(), 12 + block.len() + 11] {
+            fork.extend_from_slice(&(offset as u32).to_le_bytes());
+        }
+        fork.extend_from_slice(&block);
+        fork.push(0x06);
+        fork.extend_from_slice(b"tail data!");
+        let mut stream = LZVN_FORK_ID.to_le_bytes().to_vec();
+        stream.extend_from_slice(&(fork.len() as u64).to_le_bytes());
+        stream.extend_from_slice(&[0u8; 32]);
+        fork.resize(NX_DEFAULT_BLOCK_SIZE, 0);
+        apfs.source.blocks.insert(64, fork);
+        let mut extent = 4096u64.to_le_bytes().to_vec();
+        extent.extend_from_slice(&64u64.to_le_bytes());
+        extent.extend_from_slice(&0u64.to_le_bytes());
+
+        /* Classic resource fork whose data starts at 0x100 with the
+           block table */
+        let block = miniz_oxide::deflate::compress_to_vec_zlib(&zlib_contents()[..0x10000], 6);
+        let mut zlib_fork = vec![0u8; 0x100];
+        zlib_fork[..4].copy_from_slice(&0x100u32.to_be_bytes());
+        zlib_fork.extend_from_slice(&(block.len() as u32 + 29).to_be_bytes());
+        zlib_fork.extend_from_slice(&2u32.to_le_bytes());
+        for (offset, length) in [(20, block.len()), (20 + block.len(), 9)] {
+            zlib_fork.extend_from_slice(&(offset as u32).to_le_bytes());
+            zlib_fork.extend_from_slice(&(length as u32).to_le_bytes());
+        }
+        zlib_fork.extend_from_slice(&block);
+        zlib_fork.push(0xff);
+        zlib_fork.extend_from_slice(b"raw tail");
+
+        vec![
+            (fs_key(21, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(21, XATTR_DECMPFS_NAME), decmpfs_xattr(3, 42, &zlib)),
+            (fs_key(22, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(22, XATTR_DECMPFS_NAME), decmpfs_xattr(7, 18, &lzvn)),
+            (fs_key(23, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(23, XATTR_DECMPFS_NAME), decmpfs_xattr(11, 11, &lzfse)),
+            (fs_key(24, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(24, XATTR_RESOURCEFORK_NAME), xattr_val(XattrFlags::DATA_STREAM, &stream)),
+            (xattr_key(24, XATTR_DECMPFS_NAME), decmpfs_xattr(8, lzvn_contents().len() as u64, &[])),
+            (fs_key(25, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(25, XATTR_RESOURCEFORK_NAME), xattr_val(XattrFlags::DATA_EMBEDDED, &zlib_fork)),
+            (xattr_key(25, XATTR_DECMPFS_NAME), decmpfs_xattr(4, zlib_contents().len() as u64, &[])),
+            (fs_key(26, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(26, XATTR_DECMPFS_NAME), decmpfs_xattr(1, 12, b"stored as is")),
+            (fs_key(27, JObjTypes::Inode, &[]), compressed_inode()),
+            (xattr_key(27, XATTR_DECMPFS_NAME), decmpfs_xattr(13, 5, &[0; 8])),
+            (fs_key(LZVN_FORK_ID, JObjTypes::FileExtent, &0u64.to_le_bytes()), Some(extent)),
+        ]
+    }
+
+    #[test]
+    fn can_read_compressed_files() {
+        let mut apfs = load_synthetic_checkpoints();
+        let records = compressed_records(&mut apfs);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let read = |ino: u64| {
+            let mut file = volume.open_file(ino).unwrap();
+            assert!(file.is_compressed());
+            let mut data = vec![];
+            file.read_to_end(&mut data).unwrap();
+            assert_eq!(data.len() as u64, file.len());
+            data
+        };
+        assert_eq!(read(21), b"compressed with zlib, compressed with zlib");
+        assert_eq!(read(22), b"hello hello hello!");
+        assert_eq!(read(23), b"plain lzfse");
+        assert_eq!(read(24), lzvn_contents());
+        assert_eq!(read(25), zlib_contents());
+        assert_eq!(read(26), b"stored as is");
+        assert_eq!(volume.metadata(24).unwrap().size, lzvn_contents().len() as u64);
+        assert!(matches!(volume.open_file(27), Err(ApfsError::UnknownValue { r#type: "DecmpfsType", value: 13 })));
+    }
+
+    #[test]
+    fn can_seek_in_compressed_files() {
+        let mut apfs = load_synthetic_checkpoints();
+        let records = compressed_records(&mut apfs);
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records);
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let mut file = volume.open_file(25).unwrap();
+        let mut buf = [0u8; 12];
+        assert_eq!(file.seek(SeekFrom::Start(0x10000 - 4)).unwrap(), 0x10000 - 4);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"zlibraw tail");
+        file.seek(SeekFrom::Start(2)).unwrap();
+        file.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"ibzl");
+        let mut file = volume.open_file(24).unwrap();
+        file.seek(SeekFrom::End(-12)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"qqtail data!");
+    }
+
+    #[test]
+    fn can_look_up_paths() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &directory_records(VolumeIncompatFlags::empty()));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.lookup("/").unwrap(), (ROOT_DIR_INO_NUM, FileType::Directory));
+        assert_eq!(volume.lookup("/file.txt").unwrap(), (17, FileType::Regular));
+        assert_eq!(volume.lookup("dir/inner").unwrap(), (19, FileType::Symlink));
+        assert_eq!(volume.lookup("/dir//./inner").unwrap(), (19, FileType::Symlink));
+        assert_eq!(volume.lookup("/dir/").unwrap(), (16, FileType::Directory));
+        assert_eq!(volume.lookup("/dir/../file.txt").unwrap(), (17, FileType::Regular));
+        assert_eq!(volume.lookup("/../dir/..").unwrap(), (ROOT_DIR_INO_NUM, FileType::Directory));
+        assert_eq!(volume.lookup("/Cafe\u{301}").unwrap(), (18, FileType::Regular));
+        assert!(matches!(volume.lookup("/Caf\u{e9}"), Err(ApfsError::PathNotFound(_))));
+    }
+
+    #[test]
+    fn lookup_ignores_normalization_on_normalization_insensitive_volumes() {
+        let mut apfs = load_synthetic_checkpoints();
+        let incompat = VolumeIncompatFlags::NORMALIZATION_INSENSITIVE;
+        add_volume_trees(&mut apfs, incompat, &directory_records(incompat));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.lookup("/Caf\u{e9}").unwrap(), (18, FileType::Regular));
+        assert_eq!(volume.lookup("/Cafe\u{301}").unwrap(), (18, FileType::Regular));
+        assert!(matches!(volume.lookup("/FILE.TXT"), Err(ApfsError::PathNotFound(_))));
+    }
+
+    #[test]
+    fn lookup_reports_missing_and_non_directory_paths() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &directory_records(VolumeIncompatFlags::empty()));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        match volume.lookup("/dir/missing/inner") {
+            Err(ApfsError::PathNotFound(path)) => assert_eq!(path, "/dir/missing"),
+            other => { panic!("Unexpected result: {:?}", other); },
+        }
+        assert!(matches!(volume.lookup("/FILE.TXT"), Err(ApfsError::PathNotFound(_))));
+        match volume.lookup("/file.txt/inner") {
+            Err(ApfsError::NotADirectory(path)) => assert_eq!(path, "/file.txt"),
+            other => { panic!("Unexpected result: {:?}", other); },
+        }
+        assert!(matches!(volume.lookup("/file.txt/"), Err(ApfsError::NotADirectory(_))));
+        assert!(matches!(volume.lookup("/file.txt/.."), Err(ApfsError::NotADirectory(_))));
+    }
+
+    #[test]
+    fn can_list_directories() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &directory_records(VolumeIncompatFlags::empty()));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let entries = volume.read_dir(ROOT_DIR_INO_NUM).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(entries, vec![
+            DirEntry { name: "file.txt".to_string(), inode: 17, file_type: FileType::Regular, sibling_id: Some(0x30) },
+            DirEntry { name: "dir".to_string(), inode: 16, file_type: FileType::Directory, sibling_id: None },
+            DirEntry { name: "Cafe\u{301}".to_string(), inode: 18, file_type: FileType::Regular, sibling_id: None },
+        ]);
+        let names: Vec<_> = volume.read_dir(16).map(|entry| entry.unwrap().name).collect();
+        assert_eq!(names, vec!["Stra\u{df}e", "inner"]);
+        assert_eq!(volume.read_dir(17).count(), 0);
+        assert_eq!(volume.read_dir(99).count(), 0);
+    }
+
+    #[test]
+    fn lookup_ignores_case_on_case_insensitive_volumes() {
+        let mut apfs = load_synthetic_checkpoints();
+        let incompat = VolumeIncompatFlags::CASE_INSENSITIVE;
+        add_volume_trees(&mut apfs, incompat, &directory_records(incompat));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.lookup("/DIR/Inner").unwrap(), (19, FileType::Symlink));
+        assert_eq!(volume.lookup("/CAF\u{c9}").unwrap(), (18, FileType::Regular));
+        assert_eq!(volume.lookup("/DIR/STRASSE").unwrap(), (21, FileType::Regular));
+    }
+
+    /* Adds a root entry whose name is not valid UTF-8, sharing its hash
+       with and sorting just before file.txt */
+    fn records_with_name_not_utf8(incompat: VolumeIncompatFlags) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let mut records = directory_records(incompat);
+        let file = drec_key(ROOT_DIR_INO_NUM, incompat, "file.txt");
+        let at = records.iter().position(|(key, _)| *key == file).unwrap();
+        let hash = volume::name_hash("file.txt", incompat.contains(VolumeIncompatFlags::CASE_INSENSITIVE));
+        records.insert(at, (raw_drec_key(ROOT_DIR_INO_NUM, hash, b"a\xff"), drec_val(22, FileType::Regular)));
+        records
+    }
+
+    #[test]
+    fn lookup_skips_names_that_are_not_utf8() {
+        let mut apfs = load_synthetic_checkpoints();
+        let incompat = VolumeIncompatFlags::CASE_INSENSITIVE;
+        add_volume_trees(&mut apfs, incompat, &records_with_name_not_utf8(incompat));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        assert_eq!(volume.lookup("/FILE.TXT").unwrap(), (17, FileType::Regular));
+        assert_eq!(volume.lookup("/dir").unwrap(), (16, FileType::Directory));
+    }
+
+    #[test]
+    fn listing_reports_names_that_are_not_utf8_and_goes_on() {
+        let mut apfs = load_synthetic_checkpoints();
+        add_volume_trees(&mut apfs, VolumeIncompatFlags::empty(), &records_with_name_not_utf8(VolumeIncompatFlags::empty()));
+        let mount = APFSMount::mount(apfs).unwrap();
+        let volume = mount.volume(0).unwrap();
+        let mut entries = volume.read_dir(ROOT_DIR_INO_NUM);
+        assert!(matches!(entries.next(), Some(Err(ApfsError::InvalidString))));
+        let names: Vec<_> = entries.map(|entry| entry.unwrap().name).collect();
+        assert_eq!(names, vec!["file.txt", "dir", "Cafe\u{301}"]);
+    }
+
+    #[test]
+    fn can_list_synthetic_checkpoints() {
+        let apfs = load_synthetic_checkpoints();
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[0].xid, Xid(1));
+        assert_eq!(checkpoints[0].superblock_paddr, Paddr(2));
+        assert_eq!(checkpoints[0].map_paddrs, vec![Paddr(1)]);
+        assert!(matches!(checkpoints[0].status, CheckpointStatus::Valid));
+        assert_eq!(checkpoints[1].xid, Xid(2));
+        assert_eq!(checkpoints[1].superblock_paddr, Paddr(4));
+        assert_eq!(checkpoints[1].map_paddrs, vec![Paddr(3)]);
+        assert!(matches!(checkpoints[1].status, CheckpointStatus::Valid));
+    }
+
+    #[test]
+    fn listing_reports_torn_checkpoint() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.get_mut(&3).unwrap()[0] ^= 0xff;
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        assert!(matches!(checkpoints[0].status, CheckpointStatus::Valid));
+        assert!(matches!(checkpoints[1].status, CheckpointStatus::Torn(ApfsError::BadChecksum { paddr: Paddr(3), .. })));
+    }
+
+    #[test]
+    fn listing_reports_torn_superblock() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.get_mut(&4).unwrap()[0] ^= 0xff;
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[1].xid, Xid(2));
+        assert!(checkpoints[1].superblock.is_none());
+        assert!(matches!(checkpoints[1].status, CheckpointStatus::Torn(ApfsError::BadChecksum { paddr: Paddr(4), .. })));
+        assert_eq!(APFSMount::mount(apfs).unwrap().xid(), Xid(1));
+    }
+
+    #[test]
+    fn listing_reports_inconsistent_checkpoints() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.insert(3, build_checkpoint_map(3, &[]));
+        apfs.source.blocks.insert(2, build_nx_superblock(1, 3));
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        assert!(matches!(checkpoints[0].status, CheckpointStatus::Inconsistent(_)));
+        assert!(matches!(checkpoints[1].status, CheckpointStatus::Inconsistent(_)));
+    }
+
+    #[test]
+    fn mounting_checks_descriptor_index() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.insert(4, build_nx_superblock(2, 0));
+        assert_eq!(APFSMount::mount(apfs).unwrap().xid(), Xid(1));
+    }
+
+    #[test]
+    fn cannot_mount_invalid_checkpoint() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.source.blocks.insert(3, build_checkpoint_map(3, &[]));
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        assert!(matches!(APFSMount::mount_checkpoint(apfs, &checkpoints[1]), Err(ApfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn can_mount_older_checkpoint() {
+        let apfs = load_synthetic_checkpoints();
+        let checkpoints = APFSMount::checkpoints(&apfs).unwrap();
+        let mount = APFSMount::mount_checkpoint(apfs, &checkpoints[0]).unwrap();
+        assert_eq!(mount.xid(), Xid(1));
+        assert_eq!(mount.checkpoint_maps().len(), 1);
+        assert_eq!(mount.checkpoint_maps()[0].body.map[0].paddr, Paddr(10));
+    }
+
+    #[test]
+    fn mounted_container_can_be_shared_between_threads() {
+        let mount = APFSMount::mount(load_synthetic_checkpoints()).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    assert_eq!(mount.spaceman().unwrap().header.oid, Oid(0x400));
+                    assert_eq!(mount.volumes().unwrap().len(), 1);
+                });
+            }
+        });
+        assert!(mount.apfs().cache_stats().hits > 0);
+    }
+
+    #[test]
+    fn repeated_object_loads_are_cached() {
+        let mut apfs = load_synthetic_checkpoints();
+        apfs.load_object_addr(Paddr(4)).unwrap();
+        apfs.source.blocks.remove(&4);
+        let object = apfs.load_object_addr(Paddr(4)).unwrap();
+        assert_eq!(object.header().xid, Xid(2));
+        let stats = apfs.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.size, NX_DEFAULT_BLOCK_SIZE);
+        apfs.clear_cache();
+        assert!(apfs.load_object_addr(Paddr(4)).is_err());
+    }
+
+    #[test]
+    fn nodes_of_lent_blocks_are_read_in_place() {
+        let source = load_synthetic_checkpoints().source;
+        let mut image = vec![0; (SYNTHETIC_OMAP_TREE_BLOCK as usize + 1) * NX_DEFAULT_BLOCK_SIZE];
+        for (addr, block) in source.blocks {
+            let offset = addr as usize * NX_DEFAULT_BLOCK_SIZE;
+            image[offset..offset + block.len()].copy_from_slice(&block);
+        }
+        let apfs = APFS::new(image, NX_DEFAULT_BLOCK_SIZE);
+        match *apfs.load_object_addr(Paddr(SYNTHETIC_OMAP_TREE_BLOCK as i64)).unwrap() {
+            APFSObject::Btree(ref node) => assert!(matches!(node.body.data, Cow::Borrowed(_))),
+            ref other => panic!("Unexpected object: {:?}", other),
+        }
+        assert_eq!(apfs.cache_stats().size, 0);
+        assert!(matches!(*apfs.load_object_addr(Paddr(SYNTHETIC_OMAP_BLOCK as i64)).unwrap(), APFSObject::ObjectMap(_)));
+        assert_eq!(apfs.cache_stats().size, NX_DEFAULT_BLOCK_SIZE);
+    }
+
     #[test]
     fn test_load_checkpoint_descriptors() {
         let (mut apfs, superblock) = load_test_apfs_superblock(TEST_APFS_FILE);
         let object = apfs.load_object_addr(Paddr(0)).unwrap();
-        let superblock = match object {
-            APFSObject::Superblock(x) => x,
+        let superblock = match *object {
+            APFSObject::Superblock(ref x) => x.clone(),
             _ => { panic!("Wrong object type!"); },
         };
         let object_result = apfs.load_object_addr(superblock.body.xp_desc_base);
         assert!(object_result.is_ok(), "Bad checkpoint object load");
         let object = object_result.unwrap();
-        let mapping = match object {
-            APFSObject::CheckpointMapping(x) => x,
+        let mapping = match *object {
+            APFSObject::CheckpointMapping(ref x) => x.clone(),
             _ => { panic!("Wrong object type!"); },
         };
-        for idx in 0..superblock.body.xp_desc_blocks {
+        let desc_blocks = APFSMount::checkpoint_desc_blocks(&apfs, &superblock).unwrap();
+        for idx in 0..desc_blocks {
             let addr = superblock.body.xp_desc_base.0 + idx as i64;
             let object_result = apfs.load_object_addr(Paddr(addr));
             assert!(object_result.is_ok(), "Bad checkpoint object load");
@@ -176,7 +1271,6 @@ mod tests {
         let mut source = File::open(test_dir().join(TEST_APFS_FILE)).expect("Unable to load blob");
         let mut block = vec![0u8; BLOCK_SIZE];
         let mut dummy_source = DummySource {
-            position: 0,
             block_size: BLOCK_SIZE as u64,
             blocks: HashMap::new(),
         };
@@ -184,7 +1278,7 @@ mod tests {
             source.read_exact(&mut block).unwrap();
             dummy_source.blocks.insert(idx, block.clone());
         }
-        APFS { source: dummy_source, block_size: BLOCK_SIZE }
+        APFS::new(dummy_source, BLOCK_SIZE)
     }
 
     #[test]
@@ -320,96 +1414,127 @@ mod tests {
     }
 }
 
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{self, prelude::*, Cursor, SeekFrom};
 use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use btree::{Key, Value, Record};
 use num_traits::FromPrimitive;
 
-pub use btree::{Btree, OmapRecord, ApfsKey, ApfsValue, LeafRecord, LeafValue, NonLeafRecord, AnyRecords, InodeXdata, SpacemanFreeQueueValue, BtreeTypes, load_btree_generic};
+pub use btree::{Btree, BtreeIter, BtreeIssue, NodeArea, NodeView, import_inode_xfields, import_drec_xfields, OmapRecord, ApfsKey, ApfsSubKey, ApfsValue, LeafRecord, LeafValue, NonLeafRecord, OidValue, AnyRecords, InodeValue, InodeXdata, DrecValue, DrecXdata, SpacemanFreeQueueValue, BtreeTypes, Extent, ExtentTree, load_btree_generic};
 
 #[macro_use]
 mod int_strings;
 mod internal;
 mod fletcher;
+mod error;
+mod cache;
+mod source;
+
+pub use error::{ApfsError, Result};
+pub use cache::{CacheStats, DEFAULT_CACHE_SIZE};
+use cache::ObjectCache;
+pub use source::{BlockSource, ReadSeekSource, ImageSource};
+#[cfg(feature = "mmap")]
+pub use source::MmapSource;
 
 pub use internal::*;
 mod btree;
-use fletcher::fletcher64;
+mod volume;
+mod file;
+mod metadata;
+mod lzvn;
+mod lzfse;
+mod decmpfs;
+pub use volume::{Volume, DirEntry, ReadDir};
+pub use file::FileReader;
+pub use metadata::Metadata;
+pub use uuid::Uuid;
+pub use fletcher::fletcher64;
 
 pub use internal::Paddr;
 
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxSuperblockObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: NxSuperblock,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CheckpointMapPhysObject {
-    header: ObjPhys,
-    body: CheckpointMapPhys,
+    pub header: ObjPhys,
+    pub body: CheckpointMapPhys,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ObjectMapObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: OmapPhys,
 }
 
 #[derive(Debug)]
-pub struct BtreeNodeObject {
+pub struct BtreeNodeObject<'a> {
     pub header: ObjPhys,
-    pub body: BtreeNodePhys,
+    pub body: BtreeNodePhys<'a>,
 }
 
-#[derive(Debug)]
+impl BtreeNodeObject<'_> {
+    pub fn into_owned(self) -> BtreeNodeObject<'static> {
+        BtreeNodeObject { header: self.header, body: self.body.into_owned() }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ApfsSuperblockObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: ApfsSuperblock,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChunkInfoBlockObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: ChunkInfoBlock,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpacemanObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: SpacemanPhys,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxReaperObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: NxReaperPhys,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NxEfiJumpstartObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: NxEfiJumpstart,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SnapMetaExtObject {
-    header: ObjPhys,
+    pub header: ObjPhys,
     pub body: SnapMetaExtObjPhys,
 }
 
+/* B-tree nodes may borrow their data from the block they were decoded
+   from, every other object owns its contents */
 #[derive(Debug)]
-pub enum APFSObject {
+pub enum APFSObject<'a> {
     Superblock(NxSuperblockObject),
     CheckpointMapping(CheckpointMapPhysObject),
     ObjectMap(ObjectMapObject),
-    Btree(BtreeNodeObject),
-    BtreeNode(BtreeNodeObject),
+    Btree(BtreeNodeObject<'a>),
+    BtreeNode(BtreeNodeObject<'a>),
     ApfsSuperblock(ApfsSuperblockObject),
     Spaceman(SpacemanObject),
     SpacemanCib(ChunkInfoBlockObject),
@@ -418,47 +1543,147 @@ pub enum APFSObject {
     SnapMetaExt(SnapMetaExtObject),
 }
 
-pub struct APFS<S: Read + Seek> {
+impl APFSObject<'_> {
+    pub fn header(&self) -> &ObjPhys {
+        match self {
+            APFSObject::Superblock(x) => &x.header,
+            APFSObject::CheckpointMapping(x) => &x.header,
+            APFSObject::ObjectMap(x) => &x.header,
+            APFSObject::Btree(x) => &x.header,
+            APFSObject::BtreeNode(x) => &x.header,
+            APFSObject::ApfsSuperblock(x) => &x.header,
+            APFSObject::Spaceman(x) => &x.header,
+            APFSObject::SpacemanCib(x) => &x.header,
+            APFSObject::NxReaper(x) => &x.header,
+            APFSObject::EfiJumpstart(x) => &x.header,
+            APFSObject::SnapMetaExt(x) => &x.header,
+        }
+    }
+
+    pub fn object_type(&self) -> ObjectType {
+        self.header().r#type.r#type()
+    }
+
+    pub fn into_owned(self) -> APFSObject<'static> {
+        match self {
+            APFSObject::Superblock(x) => APFSObject::Superblock(x),
+            APFSObject::CheckpointMapping(x) => APFSObject::CheckpointMapping(x),
+            APFSObject::ObjectMap(x) => APFSObject::ObjectMap(x),
+            APFSObject::Btree(x) => APFSObject::Btree(x.into_owned()),
+            APFSObject::BtreeNode(x) => APFSObject::BtreeNode(x.into_owned()),
+            APFSObject::ApfsSuperblock(x) => APFSObject::ApfsSuperblock(x),
+            APFSObject::Spaceman(x) => APFSObject::Spaceman(x),
+            APFSObject::SpacemanCib(x) => APFSObject::SpacemanCib(x),
+            APFSObject::NxReaper(x) => APFSObject::NxReaper(x),
+            APFSObject::EfiJumpstart(x) => APFSObject::EfiJumpstart(x),
+            APFSObject::SnapMetaExt(x) => APFSObject::SnapMetaExt(x),
+        }
+    }
+}
+
+pub struct APFS<S: BlockSource> {
     source: S,
     block_size: usize,
+    cache: Mutex<ObjectCache>,
 }
 
-impl APFS<File> {
-    pub fn open<P: AsRef<Path>>(filename: P) -> io::Result<Self> {
-        let mut source = File::open(filename)?;
+impl APFS<ImageSource> {
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        let mut source = source::open_image(filename)?;
         APFS::open_source(source)
     }
 
-    pub fn load_btree<V: LeafValue>(&mut self, oid: Oid, r#type: StorageType) -> io::Result<btree::Btree<V>> {
+}
+
+impl<S: BlockSource> APFS<S> {
+    fn new(source: S, block_size: usize) -> Self {
+        APFS { source, block_size, cache: Mutex::new(ObjectCache::new(DEFAULT_CACHE_SIZE)) }
+    }
+
+    /* The cache holds no invariants that a panic elsewhere could break */
+    fn cache(&self) -> MutexGuard<'_, ObjectCache> {
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn set_cache_capacity(&self, capacity: usize) {
+        self.cache().set_capacity(capacity);
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache().clear();
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    /* Number of whole blocks in the image, when the source can tell */
+    pub fn block_count(&self) -> Option<u64> {
+        self.source.size().map(|size| size / self.block_size as u64)
+    }
+
+    pub fn load_btree<V: LeafValue>(&self, oid: Oid, r#type: StorageType) -> Result<btree::Btree<V>> {
         btree::Btree::load_btree(self, oid, r#type)
     }
-}
 
-impl<S: Read + Seek> APFS<S> {
-    pub fn open_source(mut source: S) -> io::Result<Self> {
+    pub fn load_btree_virtual<V: LeafValue>(&self, omap: Arc<Btree<OmapVal>>, oid: Oid, xid: Xid) -> Result<btree::Btree<V>> {
+        btree::Btree::load_btree_virtual(self, omap, oid, xid)
+    }
+
+    pub fn open_source(source: S) -> Result<Self> {
         let mut block0 = [0; NX_MINIMUM_BLOCK_SIZE];
-        source.read_exact(&mut block0[..])?;
+        source.read_at(0, &mut block0[..]).map_err(ApfsError::Io)?;
         let mut cursor = Cursor::new(&block0[..]);
-        let header = ObjPhys::import(&mut cursor).unwrap();
-        let superblock = NxSuperblock::import(&mut cursor).unwrap();
-        Ok(APFS { source, block_size: superblock.block_size as usize })
+        let header = ObjPhys::import(&mut cursor)?;
+        let superblock = NxSuperblock::import(&mut cursor)?;
+        let block_size = superblock.block_size as usize;
+        if !block_size.is_power_of_two() || !(NX_MINIMUM_BLOCK_SIZE..=NX_MAXIMUM_BLOCK_SIZE).contains(&block_size) {
+            return Err(ApfsError::Corrupt(format!("Invalid container block size {}", block_size)));
+        }
+        Ok(APFS::new(source, block_size))
     }
 
-    pub fn load_block(&mut self, addr: Paddr) -> io::Result<Vec<u8>> {
-        println!("Loading block {}", addr.0);
-        let mut block = vec![0; self.block_size];
-        self.source.seek(SeekFrom::Start((addr.0 as u64) * self.block_size as u64))?;
-        self.source.read_exact(&mut block)?;
-        Ok(block)
+    pub fn load_block(&self, addr: Paddr) -> Result<Cow<'_, [u8]>> {
+        let offset = u64::try_from(addr.0).ok()
+            .and_then(|block| block.checked_mul(self.block_size as u64))
+            .ok_or_else(|| ApfsError::Corrupt(format!("Block address {} out of range", addr.0)))?;
+        self.source.block_at(offset, self.block_size).map_err(ApfsError::Io)
     }
 
-    pub fn load_object_addr(&mut self, addr: Paddr) -> io::Result<APFSObject> {
+    /* Nodes in blocks the source lends out are decoded in place and
+       not cached, since the cache may only hold objects that own their
+       data. Everything else is cached once decoded */
+    pub fn load_object_addr(&self, addr: Paddr) -> Result<Arc<APFSObject<'_>>> {
+        if let Some(object) = self.cache().get(addr) {
+            return Ok(object);
+        }
         let block = self.load_block(addr)?;
-        let mut cursor = Cursor::new(&block[..]);
-        let header = ObjPhys::import(&mut cursor)?;
-        if header.cksum != fletcher64(&block[8..]) {
-            return Err(io::Error::new(io::ErrorKind::Other, "Bad object checksum"));
+        let size = block.len();
+        let object = match block {
+            Cow::Borrowed(block) => match Self::decode_object(addr, block)? {
+                object @ (APFSObject::Btree(_) | APFSObject::BtreeNode(_)) => return Ok(Arc::new(object)),
+                object => object.into_owned(),
+            },
+            Cow::Owned(block) => Self::decode_object(addr, &block)?.into_owned(),
+        };
+        let object = Arc::new(object);
+        self.cache().insert(addr, Arc::clone(&object), size);
+        Ok(object)
+    }
+
+    fn verify_object(addr: Paddr, block: &[u8]) -> Result<ObjPhys> {
+        let header = ObjPhys::parse(block)?;
+        let checksum = fletcher64(&block[8..]);
+        if header.cksum != checksum {
+            return Err(ApfsError::BadChecksum { paddr: addr, stored: header.cksum, computed: checksum });
         }
+        Ok(header)
+    }
+
+    fn decode_object(addr: Paddr, block: &[u8]) -> Result<APFSObject<'_>> {
+        let header = Self::verify_object(addr, block)?;
+        let body = &block[OBJ_PHYS_SIZE..];
+        let mut cursor = Cursor::new(body);
         let object = match header.r#type.r#type() {
             ObjectType::NxSuperblock =>
                 APFSObject::Superblock(NxSuperblockObject {
@@ -478,12 +1703,12 @@ impl<S: Read + Seek> APFS<S> {
             ObjectType::Btree =>
                 APFSObject::Btree(BtreeNodeObject {
                 header,
-                body: BtreeNodePhys::import(&mut cursor)?,
+                body: BtreeNodePhys::parse(body)?,
             }),
             ObjectType::BtreeNode =>
                 APFSObject::BtreeNode(BtreeNodeObject {
                 header,
-                body: BtreeNodePhys::import(&mut cursor)?,
+                body: BtreeNodePhys::parse(body)?,
             }),
             ObjectType::Fs =>
                 APFSObject::ApfsSuperblock(ApfsSuperblockObject {
@@ -515,47 +1740,405 @@ impl<S: Read + Seek> APFS<S> {
                 header,
                 body: SnapMetaExtObjPhys::import(&mut cursor)?,
             }),
-            _ => { return Err(io::Error::new(io::ErrorKind::Other, format!("Unsupported type: {:?}", header.r#type.r#type()))); },
+            found => { return Err(ApfsError::UnsupportedObjectType { paddr: addr, found }); },
         };
         Ok(object)
     }
 
-    pub fn load_object_oid(&mut self, oid: Oid, r#type: StorageType) -> io::Result<APFSObject> {
-        Ok(match r#type {
-            StorageType::Physical => {
+    /* Virtual objects are resolved through the given object map as of
+       its transaction, ephemeral ones need a mounted checkpoint */
+    pub fn load_object_oid(&self, oid: Oid, r#type: StorageType, omap: Option<(&Btree<OmapVal>, Xid)>) -> Result<Arc<APFSObject<'_>>> {
+        Ok(match (r#type, omap) {
+            (StorageType::Physical, _) => {
                 self.load_object_addr(Paddr(oid.0 as i64))?
             },
-            _ => {
-                panic!("Unsupported");
+            (StorageType::Virtual, Some((omap, xid))) => {
+                self.load_object_virtual(omap, oid, xid)?
+            },
+            (storage, _) => {
+                return Err(ApfsError::UnsupportedStorage { oid, storage });
             },
         })
     }
+
+    /* Find the newest mapping for a virtual object that is not
+       newer than the requested transaction */
+    pub fn resolve_virtual_oid(&self, omap: &Btree<OmapVal>, oid: Oid, xid: Xid) -> Result<OmapVal> {
+        let record = omap.lookup(self, &OmapKey { oid, xid })?
+            .ok_or(ApfsError::ObjectNotFound { oid, xid })?;
+        if record.value.flags.contains(OvFlags::DELETED) {
+            return Err(ApfsError::ObjectDeleted { oid, xid: record.key.xid });
+        }
+        Ok(record.value)
+    }
+
+    /* Address of a virtual object that can be read as a plain block
+       with an object header */
+    fn resolve_virtual_paddr(&self, omap: &Btree<OmapVal>, oid: Oid, xid: Xid) -> Result<Paddr> {
+        let value = self.resolve_virtual_oid(omap, oid, xid)?;
+        if value.flags.contains(OvFlags::ENCRYPTED) {
+            return Err(ApfsError::ObjectEncrypted { oid, paddr: value.paddr });
+        }
+        if value.flags.contains(OvFlags::NOHEADER) {
+            return Err(ApfsError::ObjectNoHeader { oid, paddr: value.paddr });
+        }
+        Ok(value.paddr)
+    }
+
+    pub fn load_object_virtual(&self, omap: &Btree<OmapVal>, oid: Oid, xid: Xid) -> Result<Arc<APFSObject<'_>>> {
+        let paddr = self.resolve_virtual_paddr(omap, oid, xid)?;
+        let object = self.load_object_addr(paddr)?;
+        let found = object.header().oid;
+        if found != oid {
+            return Err(ApfsError::OidMismatch { paddr, expected: oid, found });
+        }
+        Ok(object)
+    }
+
+    /* The snapshot tree of an object map, or None if the map has
+       never had a snapshot */
+    pub fn load_omap_snapshots(&self, omap: &OmapPhys) -> Result<Option<Btree<OmapSnapshot>>> {
+        if omap.snapshot_tree_oid == Oid(0) {
+            return Ok(None);
+        }
+        self.load_btree(omap.snapshot_tree_oid, omap.snapshot_tree_type.storage()).map(Some)
+    }
+
+    /* Check that a transaction is a snapshot that has not been deleted,
+       so that object map lookups at it see the snapshot's state */
+    pub fn snapshot_xid(&self, snapshots: &Btree<OmapSnapshot>, xid: Xid) -> Result<Xid> {
+        let record = snapshots.lookup(self, &xid)?
+            .ok_or(ApfsError::SnapshotNotFound { xid })?;
+        if record.value.flags.contains(OmsFlags::DELETED) {
+            return Err(ApfsError::SnapshotDeleted { xid });
+        }
+        Ok(record.key)
+    }
+
+    /* Transactions of the snapshots that have not been deleted,
+       oldest first */
+    pub fn snapshot_xids(&self, snapshots: &Btree<OmapSnapshot>) -> Result<Vec<Xid>> {
+        let mut xids = vec![];
+        for record in snapshots.iter(self) {
+            let record = record?;
+            if !record.value.flags.contains(OmsFlags::DELETED) {
+                xids.push(record.key);
+            }
+        }
+        Ok(xids)
+    }
+
+    pub fn load_object_snapshot(&self, omap: &Btree<OmapVal>, snapshots: &Btree<OmapSnapshot>, oid: Oid, xid: Xid) -> Result<Arc<APFSObject<'_>>> {
+        self.load_object_virtual(omap, oid, self.snapshot_xid(snapshots, xid)?)
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckpointStatus {
+    Valid,
+    Torn(ApfsError),
+    Inconsistent(String),
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub xid: Xid,
+    pub superblock_paddr: Paddr,
+    pub map_paddrs: Vec<Paddr>,
+    /* Missing when the superblock itself is torn */
+    pub superblock: Option<NxSuperblockObject>,
+    pub status: CheckpointStatus,
 }
 
-struct APFSMount<S: Read + Seek> {
+pub struct APFSMount<S: BlockSource> {
     apfs: APFS<S>,
     superblock: NxSuperblockObject,
+    checkpoint_maps: Vec<CheckpointMapPhysObject>,
+    /* Loaded once at mount, every virtual container object goes through it */
+    object_map: Arc<Btree<OmapVal>>,
 }
 
-impl<S: Read + Seek> APFSMount<S> {
-    fn mount(mut apfs: APFS<S>) -> io::Result<Self> {
-        let mut superblock = match apfs.load_object_addr(Paddr(0)).unwrap() {
-            APFSObject::Superblock(x) => x,
-            _ => { panic!("Wrong object type!"); },
-        };
-        let mut best_xid = 0;
-        for idx in 0..superblock.body.xp_desc_blocks {
-            let object = apfs.load_object_addr(Paddr(superblock.body.xp_desc_base.0+idx as i64));
-            if let Ok(APFSObject::Superblock(body)) = object {
-                let map_object = apfs.load_object_addr(Paddr(superblock.body.xp_desc_base.0 + ((idx + superblock.body.xp_desc_blocks - 1) % superblock.body.xp_desc_blocks) as i64));
-                if let Ok(APFSObject::CheckpointMapping(map_body)) = map_object {
-                    if body.header.xid.0 > best_xid {
-                        best_xid = body.header.xid.0;
-                        superblock = body;
+impl APFSMount<ImageSource> {
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        APFSMount::mount(APFS::open(filename)?)
+    }
+}
+
+impl<S: BlockSource> APFSMount<S> {
+    /* Mount the newest checkpoint that checkpoints() finds valid */
+    pub fn mount(apfs: APFS<S>) -> Result<Self> {
+        let checkpoint = Self::checkpoints(&apfs)?.into_iter().rev()
+            .find(|checkpoint| matches!(checkpoint.status, CheckpointStatus::Valid))
+            .ok_or_else(|| ApfsError::Corrupt("No valid checkpoint found".to_string()))?;
+        Self::mount_checkpoint(apfs, &checkpoint)
+    }
+
+    /* List every checkpoint superblock found in the descriptor ring,
+       oldest first, using the descriptor index and length recorded in
+       each superblock to locate its checkpoint maps */
+    pub fn checkpoints(apfs: &APFS<S>) -> Result<Vec<Checkpoint>> {
+        let superblock = Self::load_block0_superblock(apfs)?;
+        let desc_blocks = Self::checkpoint_desc_blocks(apfs, &superblock)?;
+        let mut checkpoints = vec![];
+        for idx in 0..desc_blocks {
+            let superblock_paddr = Paddr(superblock.body.xp_desc_base.0 + idx as i64);
+            let body = match apfs.load_object_addr(superblock_paddr) {
+                Ok(object) => match *object {
+                    APFSObject::Superblock(ref body) => body.clone(),
+                    _ => continue,
+                },
+                Err(err) => {
+                    /* Only a block whose header still claims to be a
+                       superblock was a checkpoint, unused slots and
+                       unreadable blocks are passed over */
+                    let header = apfs.load_block(superblock_paddr).ok()
+                        .and_then(|block| ObjPhys::parse(&block).ok())
+                        .filter(|header| header.r#type.r#type() == ObjectType::NxSuperblock);
+                    if let Some(header) = header {
+                        checkpoints.push(Checkpoint {
+                            xid: header.xid,
+                            superblock_paddr,
+                            map_paddrs: vec![],
+                            superblock: None,
+                            status: CheckpointStatus::Torn(err),
+                        });
                     }
-                }
+                    continue;
+                },
+            };
+            let (map_paddrs, status) = match Self::checkpoint_map_addrs(&body, idx) {
+                Ok(addrs) => {
+                    let first = (idx as u64 + body.body.xp_desc_blocks as u64 - addrs.len() as u64) % body.body.xp_desc_blocks as u64;
+                    let status = if first != body.body.xp_desc_index as u64 {
+                        CheckpointStatus::Inconsistent(format!("Checkpoint starts at descriptor {}, expected {}", first, body.body.xp_desc_index))
+                    } else {
+                        match Self::load_checkpoint_maps(apfs, &body, &addrs) {
+                            Ok(_) => CheckpointStatus::Valid,
+                            Err(ApfsError::Corrupt(reason)) => CheckpointStatus::Inconsistent(reason),
+                            Err(err) => CheckpointStatus::Torn(err),
+                        }
+                    };
+                    (addrs, status)
+                },
+                Err(ApfsError::Corrupt(reason)) => (vec![], CheckpointStatus::Inconsistent(reason)),
+                Err(err) => (vec![], CheckpointStatus::Torn(err)),
+            };
+            checkpoints.push(Checkpoint {
+                xid: body.header.xid,
+                superblock_paddr,
+                map_paddrs,
+                superblock: Some(body),
+                status,
+            });
+        }
+        checkpoints.sort_by_key(|checkpoint| checkpoint.xid);
+        Ok(checkpoints)
+    }
+
+    pub fn mount_checkpoint(apfs: APFS<S>, checkpoint: &Checkpoint) -> Result<Self> {
+        match checkpoint.status {
+            CheckpointStatus::Valid => {},
+            CheckpointStatus::Torn(ref err) => {
+                return Err(ApfsError::Corrupt(format!("Checkpoint {} is torn: {}", checkpoint.xid.0, err)));
+            },
+            CheckpointStatus::Inconsistent(ref reason) => {
+                return Err(ApfsError::Corrupt(format!("Checkpoint {} is inconsistent: {}", checkpoint.xid.0, reason)));
+            },
+        }
+        let superblock = match *apfs.load_object_addr(checkpoint.superblock_paddr)? {
+            APFSObject::Superblock(ref x) => x.clone(),
+            ref other => {
+                return Err(ApfsError::WrongObjectType { paddr: checkpoint.superblock_paddr, expected: ObjectType::NxSuperblock, found: other.object_type() });
+            },
+        };
+        let checkpoint_maps = Self::load_checkpoint_maps(&apfs, &superblock, &checkpoint.map_paddrs)?;
+        let omap = Self::load_object_map(&apfs, &superblock)?;
+        let object_map = Arc::new(apfs.load_btree(omap.body.tree_oid, StorageType::Physical)?);
+        Ok(APFSMount { apfs, superblock, checkpoint_maps, object_map })
+    }
+
+    fn load_block0_superblock(apfs: &APFS<S>) -> Result<NxSuperblockObject> {
+        match *apfs.load_object_addr(Paddr(0))? {
+            APFSObject::Superblock(ref x) => Ok(x.clone()),
+            ref other => Err(ApfsError::WrongObjectType { paddr: Paddr(0), expected: ObjectType::NxSuperblock, found: other.object_type() }),
+        }
+    }
+
+    /* Size of the descriptor area named by block 0, checked to be
+       contiguous and to lie within the image before any of it is read */
+    fn checkpoint_desc_blocks(apfs: &APFS<S>, superblock: &NxSuperblockObject) -> Result<u32> {
+        let desc_blocks = superblock.body.xp_desc_blocks;
+        let desc_base = superblock.body.xp_desc_base.0;
+        if desc_blocks & (1 << 31) != 0 {
+            return Err(ApfsError::Corrupt("Non-contiguous checkpoint descriptor area".to_string()));
+        }
+        let end = Some(desc_base).filter(|base| *base >= 0).and_then(|base| base.checked_add(desc_blocks as i64));
+        match (end, apfs.block_count()) {
+            (None, _) => Err(ApfsError::Corrupt(format!("Checkpoint descriptor area at block {} out of range", desc_base))),
+            (Some(end), Some(count)) if end as u64 > count => {
+                Err(ApfsError::Corrupt(format!("Checkpoint descriptor area of {} blocks at block {} exceeds the {} blocks of the image", desc_blocks, desc_base, count)))
+            },
+            _ => Ok(desc_blocks),
+        }
+    }
+
+    /* The checkpoint mapping blocks of a checkpoint are stored in the
+       descriptor ring immediately before its superblock */
+    fn checkpoint_map_addrs(superblock: &NxSuperblockObject, index: u32) -> Result<Vec<Paddr>> {
+        let desc_blocks = superblock.body.xp_desc_blocks as u64;
+        let desc_len = superblock.body.xp_desc_len as u64;
+        if desc_blocks & (1 << 31) != 0 {
+            return Err(ApfsError::Corrupt("Non-contiguous checkpoint descriptor area".to_string()));
+        }
+        if desc_len == 0 || desc_len > desc_blocks || index as u64 >= desc_blocks {
+            return Err(ApfsError::Corrupt(format!("Invalid checkpoint descriptor length {} at index {}", desc_len, index)));
+        }
+        let first = index as u64 + desc_blocks - (desc_len - 1);
+        (0..desc_len - 1)
+            .map(|offset| superblock.body.xp_desc_base.0.checked_add(((first + offset) % desc_blocks) as i64).map(Paddr))
+            .collect::<Option<Vec<Paddr>>>()
+            .ok_or_else(|| ApfsError::Corrupt("Checkpoint descriptor area out of range".to_string()))
+    }
+
+    fn load_checkpoint_maps(apfs: &APFS<S>, superblock: &NxSuperblockObject, addrs: &[Paddr]) -> Result<Vec<CheckpointMapPhysObject>> {
+        let mut maps = vec![];
+        for (idx, addr) in addrs.iter().enumerate() {
+            let map = match *apfs.load_object_addr(*addr)? {
+                APFSObject::CheckpointMapping(ref x) => x.clone(),
+                ref other => {
+                    return Err(ApfsError::WrongObjectType { paddr: *addr, expected: ObjectType::CheckpointMap, found: other.object_type() });
+                },
+            };
+            if map.header.xid != superblock.header.xid {
+                return Err(ApfsError::Corrupt(format!("Checkpoint map at block {} has transaction {}, expected {}", addr.0, map.header.xid.0, superblock.header.xid.0)));
+            }
+            if map.body.flags.contains(CpmFlags::LAST) != (idx == addrs.len() - 1) {
+                return Err(ApfsError::Corrupt(format!("Checkpoint map at block {} has unexpected flags {:?}", addr.0, map.body.flags)));
+            }
+            maps.push(map);
+        }
+        Ok(maps)
+    }
+
+    fn load_object_ephemeral(&self, oid: Oid) -> Result<Arc<APFSObject<'_>>> {
+        let mapping = self.checkpoint_maps.iter()
+            .flat_map(|map| map.body.map.iter())
+            .find(|mapping| mapping.oid == oid)
+            .ok_or(ApfsError::ObjectNotFound { oid, xid: self.superblock.header.xid })?;
+        let paddr = mapping.paddr;
+        let size = mapping.size as usize;
+        let data_base = self.superblock.body.xp_data_base;
+        let data_blocks = self.superblock.body.xp_data_blocks as i64;
+        let outside = || ApfsError::Corrupt(format!("Ephemeral object {} at block {} is outside the checkpoint data area", oid.0, paddr.0));
+        let start = paddr.0.checked_sub(data_base.0).ok_or_else(outside)?;
+        if start < 0 || start >= data_blocks {
+            return Err(outside());
+        }
+        /* Objects larger than a block may wrap around the end of the
+           checkpoint data ring */
+        let count = size.div_ceil(self.apfs.block_size);
+        if count == 0 || count as i64 > data_blocks {
+            return Err(outside());
+        }
+        let mut data = Vec::with_capacity(count * self.apfs.block_size);
+        for idx in 0..count as i64 {
+            let addr = data_base.0.checked_add((start + idx) % data_blocks).map(Paddr).ok_or_else(outside)?;
+            data.extend_from_slice(&self.apfs.load_block(addr)?);
+        }
+        let object = APFS::<S>::decode_object(paddr, &data)?.into_owned();
+        let found = object.header().oid;
+        if found != oid {
+            return Err(ApfsError::OidMismatch { paddr, expected: oid, found });
+        }
+        Ok(Arc::new(object))
+    }
+
+    /* Virtual objects are resolved through the container object map
+       as of the mounted checkpoint */
+    pub fn load_object_oid(&self, oid: Oid, r#type: StorageType) -> Result<Arc<APFSObject<'_>>> {
+        match r#type {
+            StorageType::Ephemeral => self.load_object_ephemeral(oid),
+            _ => self.apfs.load_object_oid(oid, r#type, Some((&self.object_map, self.xid()))),
+        }
+    }
+
+    pub fn apfs(&self) -> &APFS<S> {
+        &self.apfs
+    }
+
+    pub fn superblock(&self) -> &NxSuperblockObject {
+        &self.superblock
+    }
+
+    pub fn xid(&self) -> Xid {
+        self.superblock.header.xid
+    }
+
+    pub fn checkpoint_maps(&self) -> &[CheckpointMapPhysObject] {
+        &self.checkpoint_maps
+    }
+
+    fn load_object_map(apfs: &APFS<S>, superblock: &NxSuperblockObject) -> Result<ObjectMapObject> {
+        let oid = superblock.body.omap_oid;
+        match *apfs.load_object_oid(oid, StorageType::Physical, None)? {
+            APFSObject::ObjectMap(ref x) => Ok(x.clone()),
+            ref other => Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Omap, found: other.object_type() }),
+        }
+    }
+
+    pub fn object_map(&self) -> Result<ObjectMapObject> {
+        Self::load_object_map(&self.apfs, &self.superblock)
+    }
+
+    pub fn object_map_btree(&self) -> Arc<Btree<OmapVal>> {
+        Arc::clone(&self.object_map)
+    }
+
+    pub fn object_map_snapshots(&self) -> Result<Option<Btree<OmapSnapshot>>> {
+        self.apfs.load_omap_snapshots(&self.object_map()?.body)
+    }
+
+    pub fn spaceman(&self) -> Result<SpacemanObject> {
+        let oid = self.superblock.body.spaceman_oid;
+        match *self.load_object_oid(oid, StorageType::Ephemeral)? {
+            APFSObject::Spaceman(ref x) => Ok(x.clone()),
+            ref other => Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Spaceman, found: other.object_type() }),
+        }
+    }
+
+    pub fn reaper(&self) -> Result<NxReaperObject> {
+        let oid = self.superblock.body.reaper_oid;
+        match *self.load_object_oid(oid, StorageType::Ephemeral)? {
+            APFSObject::NxReaper(ref x) => Ok(x.clone()),
+            ref other => Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::NxReaper, found: other.object_type() }),
+        }
+    }
+
+    pub fn efi_jumpstart(&self) -> Result<Option<NxEfiJumpstartObject>> {
+        let paddr = self.superblock.body.efi_jumpstart;
+        if paddr == Paddr(0) {
+            return Ok(None);
+        }
+        match *self.apfs.load_object_addr(paddr)? {
+            APFSObject::EfiJumpstart(ref x) => Ok(Some(x.clone())),
+            ref other => Err(ApfsError::WrongObjectType { paddr, expected: ObjectType::EfiJumpstart, found: other.object_type() }),
+        }
+    }
+
+    pub fn volume_oids(&self) -> Vec<Oid> {
+        self.superblock.body.fs_oid.iter().copied().filter(|oid| *oid != Oid(0)).collect()
+    }
+
+    pub fn volumes(&self) -> Result<Vec<ApfsSuperblockObject>> {
+        let mut volumes = vec![];
+        for oid in self.volume_oids() {
+            match *self.load_object_oid(oid, StorageType::Virtual)? {
+                APFSObject::ApfsSuperblock(ref x) => volumes.push(x.clone()),
+                ref other => {
+                    return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Fs, found: other.object_type() });
+                },
             }
         }
-        Ok(APFSMount { apfs, superblock })
+        Ok(volumes)
     }
 }
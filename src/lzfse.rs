@@ -0,0 +1,556 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::error::{ApfsError, Result};
+use crate::lzvn;
+
+const ENDOFSTREAM_BLOCK_MAGIC: u32 = 0x24787662;
+const UNCOMPRESSED_BLOCK_MAGIC: u32 = 0x2d787662;
+const COMPRESSEDV1_BLOCK_MAGIC: u32 = 0x31787662;
+const COMPRESSEDV2_BLOCK_MAGIC: u32 = 0x32787662;
+const COMPRESSEDLZVN_BLOCK_MAGIC: u32 = 0x6e787662;
+
+const L_SYMBOLS: usize = 20;
+const M_SYMBOLS: usize = 20;
+const D_SYMBOLS: usize = 64;
+const LITERAL_SYMBOLS: usize = 256;
+const FREQ_COUNT: usize = L_SYMBOLS + M_SYMBOLS + D_SYMBOLS + LITERAL_SYMBOLS;
+
+const L_STATES: usize = 64;
+const M_STATES: usize = 64;
+const D_STATES: usize = 256;
+const LITERAL_STATES: usize = 1024;
+
+const MATCHES_PER_BLOCK: u32 = 10000;
+const LITERALS_PER_BLOCK: u32 = 4 * MATCHES_PER_BLOCK;
+
+/* 50 bytes of counts and states then the frequency tables, padded
+   to a multiple of four as the C structure is */
+const V1_HEADER_SIZE: usize = 52 + 2 * FREQ_COUNT;
+const V2_FIXED_HEADER_SIZE: usize = 32;
+
+const L_EXTRA_BITS: [u8; L_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 3, 5, 8];
+const L_BASE_VALUE: [u32; L_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 20, 28, 60];
+const M_EXTRA_BITS: [u8; M_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 5, 8, 11];
+const M_BASE_VALUE: [u32; M_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 24, 56, 312];
+const D_EXTRA_BITS: [u8; D_SYMBOLS] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3,
+    4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7,
+    8, 8, 8, 8, 9, 9, 9, 9, 10, 10, 10, 10, 11, 11, 11, 11,
+    12, 12, 12, 12, 13, 13, 13, 13, 14, 14, 14, 14, 15, 15, 15, 15,
+];
+const D_BASE_VALUE: [u32; D_SYMBOLS] = [
+    0, 1, 2, 3, 4, 6, 8, 10, 12, 16,
+    20, 24, 28, 36, 44, 52, 60, 76, 92, 108,
+    124, 156, 188, 220, 252, 316, 380, 444, 508, 636,
+    764, 892, 1020, 1276, 1532, 1788, 2044, 2556, 3068, 3580,
+    4092, 5116, 6140, 7164, 8188, 10236, 12284, 14332, 16380, 20476,
+    24572, 28668, 32764, 40956, 49148, 57340, 65532, 81916, 98300, 114684,
+    131068, 163836, 196604, 229372,
+];
+
+/* Decoder for LZFSE streams. A stream is a sequence of blocks, each
+   starting with a magic number: raw bytes, an LZVN payload, or literals
+   and literal/match/distance triples entropy coded with finite state
+   entropy (FSE) tables described by the block header. Matches may reach
+   back into earlier blocks and the stream ends with its own block. The
+   output may not grow past limit bytes */
+pub fn decode(src: &[u8], dst: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let mut pos = 0;
+    loop {
+        let block = src.get(pos..).ok_or(ApfsError::Truncated)?;
+        let magic = (&block[..]).read_u32::<LittleEndian>()?;
+        let start = dst.len();
+        let (n_raw_bytes, size) = match magic {
+            ENDOFSTREAM_BLOCK_MAGIC => return Ok(()),
+            UNCOMPRESSED_BLOCK_MAGIC => {
+                let n_raw_bytes = (&block[4..]).read_u32::<LittleEndian>()?;
+                lzvn::check_limit(dst, n_raw_bytes as usize, limit)?;
+                dst.extend_from_slice(block_slice(block, 8, n_raw_bytes as usize)?);
+                (n_raw_bytes, 8 + n_raw_bytes as usize)
+            },
+            COMPRESSEDLZVN_BLOCK_MAGIC => {
+                let mut header = &block[4..];
+                let n_raw_bytes = header.read_u32::<LittleEndian>()?;
+                let n_payload_bytes = header.read_u32::<LittleEndian>()? as usize;
+                lzvn::check_limit(dst, n_raw_bytes as usize, limit)?;
+                lzvn::decode(block_slice(block, 12, n_payload_bytes)?, dst, start + n_raw_bytes as usize)?;
+                (n_raw_bytes, 12 + n_payload_bytes)
+            },
+            COMPRESSEDV1_BLOCK_MAGIC => {
+                let header = BlockHeader::import_v1(block)?;
+                lzvn::check_limit(dst, header.n_raw_bytes as usize, limit)?;
+                header.decode(block, V1_HEADER_SIZE, dst)?;
+                (header.n_raw_bytes, V1_HEADER_SIZE + header.n_payload_bytes())
+            },
+            COMPRESSEDV2_BLOCK_MAGIC => {
+                let (header, header_size) = BlockHeader::import_v2(block)?;
+                lzvn::check_limit(dst, header.n_raw_bytes as usize, limit)?;
+                header.decode(block, header_size, dst)?;
+                (header.n_raw_bytes, header_size + header.n_payload_bytes())
+            },
+            _ => return Err(ApfsError::Corrupt(format!("Unknown LZFSE block magic 0x{:08x} at offset {}", magic, pos))),
+        };
+        if dst.len() - start != n_raw_bytes as usize {
+            return Err(ApfsError::Corrupt(format!("LZFSE block at offset {} decoded to {} bytes instead of {}", pos, dst.len() - start, n_raw_bytes)));
+        }
+        pos += size;
+    }
+}
+
+fn block_slice(block: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    block.get(offset..).and_then(|data| data.get(..len)).ok_or(ApfsError::Truncated)
+}
+
+/* The decoded form of both compressed block headers. Version 2 packs
+   the counts and initial states into bit fields and the frequency
+   tables into variable length codes */
+struct BlockHeader {
+    n_raw_bytes: u32,
+    n_literals: u32,
+    n_matches: u32,
+    n_literal_payload_bytes: u32,
+    n_lmd_payload_bytes: u32,
+    literal_bits: i32,
+    literal_state: [u16; 4],
+    lmd_bits: i32,
+    l_state: u16,
+    m_state: u16,
+    d_state: u16,
+    /* Frequencies of the L, M, D and literal symbols in that order */
+    freq: Vec<u16>,
+}
+
+impl BlockHeader {
+    fn import_v1(mut source: &[u8]) -> Result<Self> {
+        source.read_u32::<LittleEndian>()?;
+        let n_raw_bytes = source.read_u32::<LittleEndian>()?;
+        let _n_payload_bytes = source.read_u32::<LittleEndian>()?;
+        let mut header = BlockHeader {
+            n_raw_bytes,
+            n_literals: source.read_u32::<LittleEndian>()?,
+            n_matches: source.read_u32::<LittleEndian>()?,
+            n_literal_payload_bytes: source.read_u32::<LittleEndian>()?,
+            n_lmd_payload_bytes: source.read_u32::<LittleEndian>()?,
+            literal_bits: source.read_i32::<LittleEndian>()?,
+            literal_state: [0; 4],
+            lmd_bits: 0,
+            l_state: 0,
+            m_state: 0,
+            d_state: 0,
+            freq: vec![0; FREQ_COUNT],
+        };
+        source.read_u16_into::<LittleEndian>(&mut header.literal_state)?;
+        header.lmd_bits = source.read_i32::<LittleEndian>()?;
+        header.l_state = source.read_u16::<LittleEndian>()?;
+        header.m_state = source.read_u16::<LittleEndian>()?;
+        header.d_state = source.read_u16::<LittleEndian>()?;
+        source.read_u16_into::<LittleEndian>(&mut header.freq)?;
+        Ok(header)
+    }
+
+    fn import_v2(block: &[u8]) -> Result<(Self, usize)> {
+        let mut source = block;
+        source.read_u32::<LittleEndian>()?;
+        let n_raw_bytes = source.read_u32::<LittleEndian>()?;
+        let v0 = source.read_u64::<LittleEndian>()?;
+        let v1 = source.read_u64::<LittleEndian>()?;
+        let v2 = source.read_u64::<LittleEndian>()?;
+        let header_size = field(v2, 0, 32) as usize;
+        let tables = block.get(V2_FIXED_HEADER_SIZE..header_size)
+            .ok_or_else(|| ApfsError::Corrupt(format!("LZFSE header size {} out of range", header_size)))?;
+        let header = BlockHeader {
+            n_raw_bytes,
+            n_literals: field(v0, 0, 20),
+            n_matches: field(v0, 40, 20),
+            n_literal_payload_bytes: field(v0, 20, 20),
+            n_lmd_payload_bytes: field(v1, 40, 20),
+            literal_bits: field(v0, 60, 3) as i32 - 7,
+            literal_state: [field(v1, 0, 10) as u16, field(v1, 10, 10) as u16, field(v1, 20, 10) as u16, field(v1, 30, 10) as u16],
+            lmd_bits: field(v1, 60, 3) as i32 - 7,
+            l_state: field(v2, 32, 10) as u16,
+            m_state: field(v2, 42, 10) as u16,
+            d_state: field(v2, 52, 10) as u16,
+            freq: decode_freq_tables(tables)?,
+        };
+        Ok((header, header_size))
+    }
+
+    fn n_payload_bytes(&self) -> usize {
+        self.n_literal_payload_bytes as usize + self.n_lmd_payload_bytes as usize
+    }
+
+    /* The payload holds the literal stream followed by the L, M, D
+       stream, both read backwards from their end. Neither may read into
+       what comes before it, nor the output grow past the raw size */
+    fn decode(&self, block: &[u8], header_size: usize, dst: &mut Vec<u8>) -> Result<()> {
+        let limit = dst.len() + self.n_raw_bytes as usize;
+        if self.n_literals > LITERALS_PER_BLOCK || self.n_matches > MATCHES_PER_BLOCK
+            || self.literal_state.iter().any(|state| *state as usize >= LITERAL_STATES)
+            || self.l_state as usize >= L_STATES || self.m_state as usize >= M_STATES || self.d_state as usize >= D_STATES {
+            return Err(ApfsError::Corrupt("LZFSE block header out of range".to_string()));
+        }
+        let (l_freq, rest) = self.freq.split_at(L_SYMBOLS);
+        let (m_freq, rest) = rest.split_at(M_SYMBOLS);
+        let (d_freq, literal_freq) = rest.split_at(D_SYMBOLS);
+
+        let literal_table = LiteralTable::new(LITERAL_STATES, literal_freq)?;
+        let literals_end = header_size + self.n_literal_payload_bytes as usize;
+        if literals_end > block.len() {
+            return Err(ApfsError::Truncated);
+        }
+        let mut stream = BitStream::new(block, header_size, literals_end, self.literal_bits)?;
+        let mut states = self.literal_state.map(|state| state as usize);
+        let mut literals = Vec::with_capacity(self.n_literals as usize + 4);
+        for _ in (0..self.n_literals).step_by(4) {
+            stream.flush()?;
+            for state in states.iter_mut() {
+                literals.push(literal_table.decode(state, &mut stream)?);
+            }
+        }
+
+        let l_table = ValueTable::new(L_STATES, l_freq, &L_EXTRA_BITS, &L_BASE_VALUE)?;
+        let m_table = ValueTable::new(M_STATES, m_freq, &M_EXTRA_BITS, &M_BASE_VALUE)?;
+        let d_table = ValueTable::new(D_STATES, d_freq, &D_EXTRA_BITS, &D_BASE_VALUE)?;
+        let lmd_end = literals_end + self.n_lmd_payload_bytes as usize;
+        if lmd_end > block.len() {
+            return Err(ApfsError::Truncated);
+        }
+        let mut stream = BitStream::new(block, literals_end, lmd_end, self.lmd_bits)?;
+        let (mut l_state, mut m_state, mut d_state) = (self.l_state as usize, self.m_state as usize, self.d_state as usize);
+        let mut literal = 0;
+        let mut distance = 0;
+        for _ in 0..self.n_matches {
+            stream.flush()?;
+            let l = l_table.decode(&mut l_state, &mut stream)? as usize;
+            let m = m_table.decode(&mut m_state, &mut stream)? as usize;
+            let d = d_table.decode(&mut d_state, &mut stream)? as usize;
+            /* A zero distance repeats the previous one */
+            if d != 0 {
+                distance = d;
+            }
+            let run = literals.get(literal..literal + l)
+                .ok_or_else(|| ApfsError::Corrupt(format!("LZFSE literal run of {} past {} literals", l, literals.len())))?;
+            lzvn::check_limit(dst, l, limit)?;
+            dst.extend_from_slice(run);
+            literal += l;
+            lzvn::copy_match(dst, distance, m, limit)?;
+        }
+        Ok(())
+    }
+}
+
+fn field(value: u64, offset: u32, bits: u32) -> u32 {
+    ((value >> offset) & ((1 << bits) - 1)) as u32
+}
+
+/* Each frequency is a prefix code of 2 to 14 bits, packed from the
+   least significant bit up and ending exactly at the end of the header.
+   An empty area means the tables were left out */
+fn decode_freq_tables(tables: &[u8]) -> Result<Vec<u16>> {
+    const NBITS: [u32; 32] = [
+        2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14,
+        2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14,
+    ];
+    const VALUES: [u16; 32] = [
+        0, 2, 1, 4, 0, 3, 1, 0, 0, 2, 1, 5, 0, 3, 1, 0,
+        0, 2, 1, 6, 0, 3, 1, 0, 0, 2, 1, 7, 0, 3, 1, 0,
+    ];
+    let mut freq = vec![0; FREQ_COUNT];
+    if tables.is_empty() {
+        return Ok(freq);
+    }
+    let mut bytes = tables.iter();
+    let mut accum = 0u32;
+    let mut accum_nbits = 0;
+    for value in freq.iter_mut() {
+        while accum_nbits + 8 <= 32 {
+            match bytes.next() {
+                Some(byte) => {
+                    accum |= (*byte as u32) << accum_nbits;
+                    accum_nbits += 8;
+                },
+                None => break,
+            }
+        }
+        let code = (accum & 31) as usize;
+        let nbits = NBITS[code];
+        *value = match nbits {
+            8 => 8 + ((accum >> 4) & 0xf) as u16,
+            14 => 24 + ((accum >> 4) & 0x3ff) as u16,
+            _ => VALUES[code],
+        };
+        if nbits > accum_nbits {
+            return Err(ApfsError::Corrupt("LZFSE frequency tables truncated".to_string()));
+        }
+        accum >>= nbits;
+        accum_nbits -= nbits;
+    }
+    if accum_nbits >= 8 || bytes.next().is_some() {
+        return Err(ApfsError::Corrupt("LZFSE frequency tables do not fill the header".to_string()));
+    }
+    Ok(freq)
+}
+
+/* Bits are read backwards from the end of a stream, starting with
+   between 56 and 63 bits whose count is given in the block header,
+   and never going back before the start of the stream */
+struct BitStream<'a> {
+    data: &'a [u8],
+    start: usize,
+    pos: usize,
+    accum: u64,
+    accum_nbits: u32,
+}
+
+impl<'a> BitStream<'a> {
+    fn new(data: &'a [u8], start: usize, end: usize, nbits: i32) -> Result<Self> {
+        let (count, accum_nbits) = match nbits {
+            0 => (7, 56),
+            -7..=-1 => (8, (64 + nbits) as u32),
+            _ => return Err(ApfsError::Corrupt(format!("LZFSE stream with {} initial bits", nbits))),
+        };
+        let pos = end.checked_sub(count).filter(|pos| *pos >= start).ok_or(ApfsError::Truncated)?;
+        let accum = le_bytes(&data[pos..end]);
+        if accum >> accum_nbits != 0 {
+            return Err(ApfsError::Corrupt("LZFSE stream has bits set above its start".to_string()));
+        }
+        Ok(BitStream { data, start, pos, accum, accum_nbits })
+    }
+
+    /* Top up the accumulator with whole bytes to at least 56 bits */
+    fn flush(&mut self) -> Result<()> {
+        let nbits = (63 - self.accum_nbits) & !7;
+        let nbytes = (nbits / 8) as usize;
+        if nbytes == 0 {
+            return Ok(());
+        }
+        self.pos = self.pos.checked_sub(nbytes).filter(|pos| *pos >= self.start).ok_or(ApfsError::Truncated)?;
+        self.accum = (self.accum << nbits) | le_bytes(&self.data[self.pos..self.pos + nbytes]);
+        self.accum_nbits += nbits;
+        Ok(())
+    }
+
+    fn pull(&mut self, nbits: u32) -> Result<u32> {
+        if nbits > self.accum_nbits {
+            return Err(ApfsError::Corrupt("LZFSE stream ran out of bits".to_string()));
+        }
+        self.accum_nbits -= nbits;
+        let result = self.accum >> self.accum_nbits;
+        self.accum &= mask(self.accum_nbits);
+        Ok(result as u32)
+    }
+}
+
+fn le_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |accum, byte| (accum << 8) | *byte as u64)
+}
+
+fn mask(nbits: u32) -> u64 {
+    if nbits == 0 { 0 } else { u64::MAX >> (64 - nbits) }
+}
+
+/* Symbol s with frequency f owns f consecutive states. The first
+   states of the run read k bits and the rest k - 1, where k is chosen
+   so the next states cover the whole table */
+fn fill_table<T: Default + Clone, F: Fn(usize, u32, u32) -> T>(nstates: usize, freq: &[u16], entry: F) -> Result<Vec<T>> {
+    let mut table = Vec::with_capacity(nstates);
+    for (symbol, f) in freq.iter().enumerate() {
+        let f = *f as u32;
+        if f == 0 {
+            continue;
+        }
+        if table.len() + f as usize > nstates {
+            return Err(ApfsError::Corrupt("LZFSE frequencies exceed the number of states".to_string()));
+        }
+        let k = f.leading_zeros() - (nstates as u32).leading_zeros();
+        let j0 = ((2 * nstates as u32) >> k) - f;
+        for j in 0..f {
+            table.push(if j < j0 {
+                entry(symbol, k, ((f + j) << k) - nstates as u32)
+            } else {
+                entry(symbol, k - 1, (j - j0) << (k - 1))
+            });
+        }
+    }
+    table.resize(nstates, T::default());
+    Ok(table)
+}
+
+fn table_entry<T>(table: &[T], state: usize) -> Result<&T> {
+    table.get(state).ok_or_else(|| ApfsError::Corrupt(format!("LZFSE state {} out of range", state)))
+}
+
+#[derive(Debug, Clone, Default)]
+struct LiteralEntry {
+    symbol: u8,
+    nbits: u32,
+    delta: u32,
+}
+
+struct LiteralTable(Vec<LiteralEntry>);
+
+impl LiteralTable {
+    fn new(nstates: usize, freq: &[u16]) -> Result<Self> {
+        fill_table(nstates, freq, |symbol, nbits, delta| LiteralEntry { symbol: symbol as u8, nbits, delta }).map(LiteralTable)
+    }
+
+    fn decode(&self, state: &mut usize, stream: &mut BitStream) -> Result<u8> {
+        let entry = table_entry(&self.0, *state)?;
+        *state = (entry.delta + stream.pull(entry.nbits)?) as usize;
+        Ok(entry.symbol)
+    }
+}
+
+/* Values are read along with the state bits, in the low bits of the
+   same pull, and added to the base value of the symbol */
+#[derive(Debug, Clone, Default)]
+struct ValueEntry {
+    total_bits: u32,
+    value_bits: u32,
+    delta: u32,
+    base: u32,
+}
+
+struct ValueTable(Vec<ValueEntry>);
+
+impl ValueTable {
+    fn new(nstates: usize, freq: &[u16], extra_bits: &[u8], base_value: &[u32]) -> Result<Self> {
+        fill_table(nstates, freq, |symbol, nbits, delta| ValueEntry {
+            total_bits: nbits + extra_bits[symbol] as u32,
+            value_bits: extra_bits[symbol] as u32,
+            delta,
+            base: base_value[symbol],
+        }).map(ValueTable)
+    }
+
+    fn decode(&self, state: &mut usize, stream: &mut BitStream) -> Result<u32> {
+        let entry = table_entry(&self.0, *state)?;
+        let bits = stream.pull(entry.total_bits)?;
+        *state = (entry.delta + (bits >> entry.value_bits)) as usize;
+        Ok(entry.base + (bits & mask(entry.value_bits) as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decoded(src: &[u8]) -> Result<Vec<u8>> {
+        let mut dst = vec![];
+        decode(src, &mut dst, 64)?;
+        Ok(dst)
+    }
+
+    /* Prefix code of a frequency, as (bits, length) */
+    fn freq_code(value: u16) -> (u32, u32) {
+        match value {
+            0 => (0, 2),
+            1 => (2, 2),
+            2 => (1, 3),
+            3 => (5, 3),
+            4 => (3, 5),
+            5 => (11, 5),
+            6 => (19, 5),
+            7 => (27, 5),
+            8..=23 => (7 | ((value as u32 - 8) << 4), 8),
+            _ => (15 | ((value as u32 - 24) << 4), 14),
+        }
+    }
+
+    fn pack_freq_tables(freq: &[u16]) -> Vec<u8> {
+        let mut packed = vec![];
+        let (mut accum, mut accum_nbits) = (0u64, 0);
+        for value in freq {
+            let (bits, nbits) = freq_code(*value);
+            accum |= (bits as u64) << accum_nbits;
+            accum_nbits += nbits;
+            while accum_nbits >= 8 {
+                packed.push(accum as u8);
+                accum >>= 8;
+                accum_nbits -= 8;
+            }
+        }
+        if accum_nbits > 0 {
+            packed.push(accum as u8);
+        }
+        packed
+    }
+
+    /* A version 2 block with two literal symbols sharing the literal
+       states and a single L, M and D symbol each, so the only bits read
+       from the L, M, D stream are the extra bits of M and D */
+    fn v2_block() -> Vec<u8> {
+        let mut freq = vec![0u16; FREQ_COUNT];
+        freq[8] = 64;
+        freq[L_SYMBOLS + 16] = 64;
+        freq[L_SYMBOLS + M_SYMBOLS + 4] = 256;
+        freq[L_SYMBOLS + M_SYMBOLS + D_SYMBOLS + b'a' as usize] = 512;
+        freq[L_SYMBOLS + M_SYMBOLS + D_SYMBOLS + b'b' as usize] = 512;
+        let tables = pack_freq_tables(&freq);
+        let header_size = (V2_FIXED_HEADER_SIZE + tables.len()) as u64;
+        let v0 = 8 | (8 << 20) | (1 << 40) | (7 << 60);
+        let v1 = 300 | (512 << 10) | (1 << 20) | (900 << 30) | (7 << 40) | (7 << 60);
+        let v2 = header_size;
+
+        let mut block = vec![];
+        block.extend_from_slice(&COMPRESSEDV2_BLOCK_MAGIC.to_le_bytes());
+        block.extend_from_slice(&26u32.to_le_bytes());
+        for value in [v0, v1, v2] {
+            block.extend_from_slice(&value.to_le_bytes());
+        }
+        block.extend_from_slice(&tables);
+        /* Literal stream with all bits clear, long enough for the byte
+           read ahead of the second group of literals */
+        block.extend_from_slice(&[0; 8]);
+        /* M takes extra bits 010 for 18 and D takes 1 for 5 */
+        block.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x50]);
+        block
+    }
+
+    #[test]
+    fn decodes_compressed_blocks() {
+        let mut src = v2_block();
+        src.extend_from_slice(&ENDOFSTREAM_BLOCK_MAGIC.to_le_bytes());
+        assert_eq!(decoded(&src).unwrap(), b"ababbaabbbaabbbaabbbaabbba");
+    }
+
+    #[test]
+    fn decodes_raw_and_lzvn_blocks() {
+        let mut src = vec![];
+        src.extend_from_slice(&UNCOMPRESSED_BLOCK_MAGIC.to_le_bytes());
+        src.extend_from_slice(&5u32.to_le_bytes());
+        src.extend_from_slice(b"hello");
+        /* The LZVN block copies from the raw block before it */
+        let payload = [0xe1, b' ', 0x07, 0x06, 0x00, 0x06];
+        src.extend_from_slice(&COMPRESSEDLZVN_BLOCK_MAGIC.to_le_bytes());
+        src.extend_from_slice(&4u32.to_le_bytes());
+        src.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        src.extend_from_slice(&payload);
+        src.extend_from_slice(&ENDOFSTREAM_BLOCK_MAGIC.to_le_bytes());
+        assert_eq!(decoded(&src).unwrap(), b"hello hel");
+    }
+
+    #[test]
+    fn rejects_bad_streams() {
+        assert!(matches!(decoded(b"bvx?"), Err(ApfsError::Corrupt(_))));
+        /* Missing end of stream block */
+        assert!(matches!(decoded(&v2_block()), Err(ApfsError::Truncated)));
+        /* Raw size disagreeing with the decoded block */
+        let mut src = v2_block();
+        src[4] = 25;
+        src.extend_from_slice(&ENDOFSTREAM_BLOCK_MAGIC.to_le_bytes());
+        assert!(matches!(decoded(&src), Err(ApfsError::Corrupt(_))));
+        /* Output past the limit */
+        let mut src = v2_block();
+        src.extend_from_slice(&ENDOFSTREAM_BLOCK_MAGIC.to_le_bytes());
+        let mut dst = vec![];
+        assert!(matches!(decode(&src, &mut dst, 20), Err(ApfsError::Corrupt(_))));
+        /* L, M, D stream starting with 8 bytes reaching back into the
+           literals */
+        let mut src = v2_block();
+        src[23] &= 0x0f;
+        src[23] |= 6 << 4;
+        src.extend_from_slice(&ENDOFSTREAM_BLOCK_MAGIC.to_le_bytes());
+        assert!(matches!(decoded(&src), Err(ApfsError::Truncated)));
+    }
+}
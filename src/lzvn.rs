@@ -0,0 +1,129 @@
+use crate::error::{ApfsError, Result};
+
+/* Decoder for LZVN, the LZ77 format used for small compressed files
+   and for the short blocks of LZFSE streams. Each opcode carries a
+   run of literals followed by a match against earlier output, with
+   some opcodes reusing the previous match distance. Matches may reach
+   back into output already in the buffer before this stream. The
+   output may not grow past limit bytes */
+pub fn decode(src: &[u8], dst: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let mut pos = 0;
+    let mut distance = 0;
+    while pos < src.len() {
+        let op = src[pos] as usize;
+        /* Header length, literal count, match length and new distance */
+        let (header, literals, length, new_distance) = match op {
+            0x06 => return Ok(()),
+            0x0e | 0x16 => (1, 0, 0, None),
+            0x1e | 0x26 | 0x2e | 0x36 | 0x3e | 0x70..=0x7f | 0xd0..=0xdf => {
+                return Err(ApfsError::Corrupt(format!("Undefined LZVN opcode 0x{:02x} at offset {}", op, pos)));
+            },
+            0xa0..=0xbf => {
+                let (b1, b2) = (byte(src, pos + 1)?, byte(src, pos + 2)?);
+                (3, (op >> 3) & 3, (((op & 7) << 2) | (b1 & 3)) + 3, Some((b2 << 6) | (b1 >> 2)))
+            },
+            0xe0 => (2, byte(src, pos + 1)? + 16, 0, None),
+            0xe1..=0xef => (1, op & 0xf, 0, None),
+            0xf0 => (2, 0, byte(src, pos + 1)? + 16, None),
+            0xf1..=0xff => (1, 0, op & 0xf, None),
+            _ if op & 7 == 7 => {
+                let distance = byte(src, pos + 1)? | (byte(src, pos + 2)? << 8);
+                (3, op >> 6, ((op >> 3) & 7) + 3, Some(distance))
+            },
+            _ if op & 7 == 6 => (1, op >> 6, ((op >> 3) & 7) + 3, None),
+            _ => (2, op >> 6, ((op >> 3) & 7) + 3, Some(((op & 7) << 8) | byte(src, pos + 1)?)),
+        };
+        pos += header;
+        let literal = src.get(pos..pos + literals)
+            .ok_or_else(|| ApfsError::Corrupt(format!("LZVN literals at offset {} run past the input", pos)))?;
+        check_limit(dst, literals, limit)?;
+        dst.extend_from_slice(literal);
+        pos += literals;
+        if let Some(new_distance) = new_distance {
+            distance = new_distance;
+        }
+        copy_match(dst, distance, length, limit)?;
+    }
+    Ok(())
+}
+
+/* Matches may overlap the bytes they produce, so copy a byte at a time */
+pub(crate) fn copy_match(dst: &mut Vec<u8>, distance: usize, length: usize, limit: usize) -> Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+    check_limit(dst, length, limit)?;
+    if distance == 0 || distance > dst.len() {
+        return Err(ApfsError::Corrupt(format!("Match distance {} with {} bytes of output", distance, dst.len())));
+    }
+    for _ in 0..length {
+        dst.push(dst[dst.len() - distance]);
+    }
+    Ok(())
+}
+
+pub(crate) fn check_limit(dst: &[u8], count: usize, limit: usize) -> Result<()> {
+    if count > limit.saturating_sub(dst.len()) {
+        return Err(ApfsError::Corrupt(format!("Decoded data runs past {} bytes", limit)));
+    }
+    Ok(())
+}
+
+fn byte(src: &[u8], pos: usize) -> Result<usize> {
+    src.get(pos).map(|b| *b as usize).ok_or(ApfsError::Truncated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decoded(src: &[u8]) -> Result<Vec<u8>> {
+        let mut dst = vec![];
+        decode(src, &mut dst, 64)?;
+        Ok(dst)
+    }
+
+    #[test]
+    fn decodes_literals_and_matches() {
+        let src = [
+            0xe4, b'a', b'b', b'c', b'd',   /* four literals */
+            0x40, 0x05,                     /* one literal, 3 bytes from 5 back */
+            b'e',
+            0xf2,                           /* 2 more bytes from the same distance */
+            0x06, 0, 0, 0, 0, 0, 0, 0,      /* end of stream and padding */
+        ];
+        assert_eq!(decoded(&src).unwrap(), b"abcdeabcde");
+    }
+
+    #[test]
+    fn decodes_long_runs() {
+        let mut src = vec![0xe0, 2];
+        src.extend_from_slice(b"0123456789abcdefgh");
+        /* Overlapping matches of 3 and 20 bytes at distance 1, then a
+           medium distance opcode copying 4 bytes from the start */
+        src.extend_from_slice(&[0x07, 0x01, 0x00, 0xf0, 4, 0xa0, 0xa5, 0x00, 0x06]);
+        let mut expected = b"0123456789abcdefgh".to_vec();
+        expected.extend_from_slice(&[b'h'; 23]);
+        expected.extend_from_slice(b"0123");
+        assert_eq!(decoded(&src).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(matches!(decoded(&[0x1e]), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(decoded(&[0xe4, b'a']), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(decoded(&[0xe1, b'a', 0xf2]), Err(ApfsError::Corrupt(_))));
+        assert!(matches!(decoded(&[0x07, 0x01]), Err(ApfsError::Truncated)));
+        /* Output past the limit */
+        assert!(matches!(decoded(&[0xe1, b'a', 0xf0, 0xff]), Err(ApfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_undefined_opcodes() {
+        for op in 0xd0..=0xdf {
+            /* After output and a distance any match opcode could use */
+            let src = [0xe1, b'a', 0x07, 0x01, 0x00, op, 0x01, 0x00, b'x', b'y', b'z', 0x06];
+            assert!(matches!(decoded(&src), Err(ApfsError::Corrupt(_))), "opcode 0x{:02x}", op);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{FileType, Gid, InodeFlags, InodeValue, InodeXdata, InoExtType, Mode, Uid};
+use crate::error::Result;
+
+/* The stat(2) view of an inode */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub ino: u64,
+    pub parent_id: u64,
+    /* Permission bits along with the S_IF* type bits */
+    pub mode: Mode,
+    pub file_type: FileType,
+    pub uid: Uid,
+    pub gid: Gid,
+    /* Number of hard links, or the number of children of a directory */
+    pub nlink: u32,
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    pub changed: SystemTime,
+    pub accessed: SystemTime,
+    pub bsd_flags: u32,
+    pub internal_flags: InodeFlags,
+    /* Logical and allocated size of the data stream, zero without one.
+       The size of a compressed file is that of its decoded contents */
+    pub size: u64,
+    pub allocated_size: u64,
+    pub generation: u32,
+}
+
+impl Metadata {
+    pub fn new(ino: u64, inode: &InodeValue) -> Result<Self> {
+        let value = &inode.value;
+        let (mut size, allocated_size) = match inode.xdata.get(&InoExtType::Dstream) {
+            Some(InodeXdata::Dstream(dstream)) => (dstream.size, dstream.alloced_size),
+            _ => (0, 0),
+        };
+        if value.internal_flags.contains(InodeFlags::HAS_UNCOMPRESSED_SIZE) {
+            size = value.uncompressed_size;
+        }
+        Ok(Metadata {
+            ino,
+            parent_id: value.parent_id,
+            mode: value.mode,
+            file_type: value.file_type()?,
+            uid: value.owner,
+            gid: value.group,
+            nlink: value.nchildren_or_nlink.max(0) as u32,
+            created: timestamp(value.create_time),
+            modified: timestamp(value.mod_time),
+            changed: timestamp(value.change_time),
+            accessed: timestamp(value.access_time),
+            bsd_flags: value.bsd_flags,
+            internal_flags: value.internal_flags,
+            size,
+            allocated_size,
+            generation: value.write_generation_counter,
+        })
+    }
+
+    /* Permission bits without the file type */
+    pub fn permissions(&self) -> Mode {
+        self.mode & 0o7777
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::Regular
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+}
+
+/* Times are stored as nanoseconds since the epoch */
+fn timestamp(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
@@ -0,0 +1,274 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/* Positional reads that fill the whole buffer or fail, so a single
+   source can be shared between threads without a seek position */
+pub trait BlockSource: Send + Sync {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /* Sources already holding the image in memory can lend out a
+       slice of it instead of copying into a new buffer */
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        let mut block = vec![0; len];
+        self.read_at(offset, &mut block)?;
+        Ok(Cow::Owned(block))
+    }
+
+    /* Size of the image in bytes, when the source can tell */
+    fn size(&self) -> Option<u64> {
+        None
+    }
+}
+
+fn slice_at(data: &[u8], offset: u64, len: usize) -> io::Result<&[u8]> {
+    usize::try_from(offset).ok()
+        .and_then(|start| Some(start..start.checked_add(len)?))
+        .and_then(|range| data.get(range))
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+#[cfg(unix)]
+impl BlockSource for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    /* Seeking rather than the metadata length also sizes block devices */
+    fn size(&self) -> Option<u64> {
+        (&*self).seek(SeekFrom::End(0)).ok()
+    }
+}
+
+#[cfg(windows)]
+impl BlockSource for File {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(count) => {
+                    buf = &mut buf[count..];
+                    offset += count as u64;
+                },
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Option<u64> {
+        (&*self).seek(SeekFrom::End(0)).ok()
+    }
+}
+
+impl BlockSource for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(slice_at(self, offset, buf.len())?);
+        Ok(())
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        slice_at(self, offset, len).map(Cow::Borrowed)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
+
+impl BlockSource for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self[..].read_at(offset, buf)
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        self[..].block_at(offset, len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self[..].size()
+    }
+}
+
+impl<T: BlockSource + ?Sized> BlockSource for &T {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        (**self).block_at(offset, len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: BlockSource + ?Sized> BlockSource for Box<T> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        (**self).block_at(offset, len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: BlockSource + ?Sized> BlockSource for Arc<T> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        (**self).block_at(offset, len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        (**self).size()
+    }
+}
+
+/* Adapter for sources that only support Read and Seek, such as
+   decompressors or Cursor, which serialises access behind a lock */
+pub struct ReadSeekSource<S: Read + Seek + Send> {
+    source: Mutex<S>,
+}
+
+impl<S: Read + Seek + Send> ReadSeekSource<S> {
+    pub fn new(source: S) -> Self {
+        Self { source: Mutex::new(source) }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source.into_inner().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<S: Read + Seek + Send> BlockSource for ReadSeekSource<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut source = self.source.lock().unwrap_or_else(|err| err.into_inner());
+        source.seek(SeekFrom::Start(offset))?;
+        source.read_exact(buf)
+    }
+
+    fn size(&self) -> Option<u64> {
+        let mut source = self.source.lock().unwrap_or_else(|err| err.into_inner());
+        source.seek(SeekFrom::End(0)).ok()
+    }
+}
+
+/* Read-only mapping of a whole image, so that loading a block is a
+   bounds check rather than a system call and a copy */
+#[cfg(feature = "mmap")]
+pub struct MmapSource {
+    map: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_file(&File::open(path)?)
+    }
+
+    /* The image must not be modified or truncated while mapped */
+    pub fn from_file(file: &File) -> io::Result<Self> {
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        Ok(Self { map })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl BlockSource for MmapSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.map[..].read_at(offset, buf)
+    }
+
+    fn block_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        self.map[..].block_at(offset, len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.map[..].size()
+    }
+}
+
+/* Source used by the path based constructors */
+#[cfg(feature = "mmap")]
+pub type ImageSource = MmapSource;
+#[cfg(not(feature = "mmap"))]
+pub type ImageSource = File;
+
+#[cfg(feature = "mmap")]
+pub fn open_image<P: AsRef<Path>>(path: P) -> io::Result<ImageSource> {
+    MmapSource::open(path)
+}
+
+#[cfg(not(feature = "mmap"))]
+pub fn open_image<P: AsRef<Path>>(path: P) -> io::Result<ImageSource> {
+    File::open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn can_read_from_memory() {
+        let data: Vec<u8> = (0..16).collect();
+        let mut buf = [0u8; 4];
+        data.read_at(4, &mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6, 7]);
+        data.read_at(12, &mut buf).unwrap();
+        assert_eq!(buf, [12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn short_memory_read_is_an_error() {
+        let data: Vec<u8> = (0..16).collect();
+        let mut buf = [0u8; 4];
+        let err = data.read_at(13, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = data[..].read_at(u64::MAX, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn sources_know_their_size() {
+        let data: Vec<u8> = (0..16).collect();
+        assert_eq!(data.size(), Some(16));
+        assert_eq!(ReadSeekSource::new(Cursor::new(&data[..])).size(), Some(16));
+    }
+
+    #[test]
+    fn memory_blocks_are_borrowed() {
+        let data: Vec<u8> = (0..16).collect();
+        match data.block_at(4, 4).unwrap() {
+            Cow::Borrowed(block) => assert_eq!(block, [4, 5, 6, 7]),
+            Cow::Owned(_) => panic!("block was copied"),
+        }
+        let source = ReadSeekSource::new(Cursor::new(data));
+        assert_eq!(&source.block_at(4, 4).unwrap()[..], [4, 5, 6, 7]);
+        assert!(source.block_at(14, 4).is_err());
+    }
+
+    #[test]
+    fn can_read_through_read_seek_adapter() {
+        let source = ReadSeekSource::new(Cursor::new((0..16).collect::<Vec<u8>>()));
+        let mut buf = [0u8; 4];
+        source.read_at(8, &mut buf).unwrap();
+        assert_eq!(buf, [8, 9, 10, 11]);
+        source.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert!(source.read_at(14, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+
+use caseless::Caseless;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+use crate::{APFS, APFSMount, APFSObject, ApfsKey, ApfsSubKey, ApfsSuperblockObject, ApfsValue, BtreeIter, DrecExtType, DrecValue, DrecXdata, FileReader, FileType, InodeValue, InodeXdata, Metadata, InoExtType, JDrecHashedKey, JObjTypes, JXattrDstream, JXattrVal, XattrFlags, ROOT_DIR_INO_NUM, UF_COMPRESSED, XATTR_DECMPFS_NAME, XATTR_RESOURCEFORK_NAME, BlockSource, Btree, ExtentTree, ObjectType, OmapVal, Oid, StorageType, VolumeFeatureFlags, VolumeFlags, VolumeIncompatFlags, VolumeRocompatFlags, VolumeRoles, Xid};
+use crate::decmpfs::{Decmpfs, ForkSource};
+use crate::error::{ApfsError, Result};
+
+/* A volume of a mounted container along with the trees needed to read
+   its file system, all resolved as of the container's transaction */
+pub struct Volume<'a, S: BlockSource> {
+    apfs: &'a APFS<S>,
+    index: usize,
+    superblock: ApfsSuperblockObject,
+    xid: Xid,
+    object_map: Arc<Btree<OmapVal>>,
+    root_tree: Arc<Btree<ApfsValue>>,
+    extentref_tree: Option<Btree<ApfsValue>>,
+    extents: ExtentTree,
+}
+
+impl<'a, S: BlockSource> Volume<'a, S> {
+    pub fn load(mount: &'a APFSMount<S>, index: usize, superblock: ApfsSuperblockObject) -> Result<Self> {
+        let apfs = mount.apfs();
+        let xid = mount.xid();
+        let oid = superblock.body.omap_oid;
+        let omap = match *apfs.load_object_oid(oid, StorageType::Physical, None)? {
+            APFSObject::ObjectMap(ref x) => x.clone(),
+            ref other => {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Omap, found: other.object_type() });
+            },
+        };
+        let object_map = Arc::new(apfs.load_btree::<OmapVal>(omap.body.tree_oid, StorageType::Physical)?);
+        let root_tree = Arc::new(apfs.load_btree_virtual::<ApfsValue>(object_map.clone(), superblock.body.root_tree_oid, xid)?);
+        let extentref_tree = if superblock.body.extentref_tree_oid != Oid(0) {
+            Some(apfs.load_btree(superblock.body.extentref_tree_oid, superblock.body.extentref_tree_type.storage())?)
+        } else {
+            None
+        };
+        let extents = ExtentTree::for_volume(apfs, &superblock.body, root_tree.clone())?;
+        Ok(Volume { apfs, index, superblock, xid, object_map, root_tree, extentref_tree, extents })
+    }
+
+    pub fn apfs(&self) -> &'a APFS<S> {
+        self.apfs
+    }
+
+    /* Position of the volume among those in use, skipping the empty
+       slots of the container superblock, as taken by APFSMount::volume */
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn superblock(&self) -> &ApfsSuperblockObject {
+        &self.superblock
+    }
+
+    pub fn xid(&self) -> Xid {
+        self.xid
+    }
+
+    pub fn name(&self) -> Result<&str> {
+        self.superblock.body.name()
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.superblock.body.vol_uuid
+    }
+
+    pub fn role(&self) -> VolumeRoles {
+        self.superblock.body.role
+    }
+
+    pub fn features(&self) -> VolumeFeatureFlags {
+        self.superblock.body.features
+    }
+
+    pub fn readonly_compatible_features(&self) -> VolumeRocompatFlags {
+        self.superblock.body.readonly_compatible_features
+    }
+
+    pub fn incompatible_features(&self) -> VolumeIncompatFlags {
+        self.superblock.body.incompatible_features
+    }
+
+    /* The subset of the volume flags describing its encryption */
+    pub fn crypto_flags(&self) -> VolumeFlags {
+        self.superblock.body.fs_flags & VolumeFlags::CRYPTOFLAGS
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !self.crypto_flags().contains(VolumeFlags::UNENCRYPTED)
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.superblock.body.is_sealed()
+    }
+
+    pub fn object_map(&self) -> &Arc<Btree<OmapVal>> {
+        &self.object_map
+    }
+
+    pub fn root_tree(&self) -> &Btree<ApfsValue> {
+        &self.root_tree
+    }
+
+    pub fn extentref_tree(&self) -> Option<&Btree<ApfsValue>> {
+        self.extentref_tree.as_ref()
+    }
+
+    pub fn extents(&self) -> &ExtentTree {
+        &self.extents
+    }
+
+    /* Resolve a path from the root directory to its inode ID and type.
+       Empty and "." components are skipped and ".." follows the parent
+       recorded in the directory's inode, stopping at the root. A
+       trailing slash requires the target to be a directory */
+    pub fn lookup(&self, path: &str) -> Result<(u64, FileType)> {
+        let mut ino = ROOT_DIR_INO_NUM;
+        let mut file_type = FileType::Directory;
+        let mut walked = String::new();
+        for name in path.split('/') {
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            if file_type != FileType::Directory {
+                return Err(ApfsError::NotADirectory(walked));
+            }
+            walked.push('/');
+            walked.push_str(name);
+            if name == ".." {
+                if ino != ROOT_DIR_INO_NUM {
+                    ino = self.parent_id(ino)?;
+                }
+                continue;
+            }
+            let (child, child_type) = self.find_entry(ino, name)?
+                .ok_or_else(|| ApfsError::PathNotFound(walked.clone()))?;
+            ino = child;
+            file_type = child_type;
+        }
+        if path.ends_with('/') && file_type != FileType::Directory {
+            return Err(ApfsError::NotADirectory(walked));
+        }
+        Ok((ino, file_type))
+    }
+
+    fn inode(&self, ino: u64) -> Result<InodeValue> {
+        let key = ApfsKey::new(ino, JObjTypes::Inode, ApfsSubKey::None);
+        match self.root_tree.lookup(self.apfs, &key)? {
+            Some(record) => match record.value {
+                ApfsValue::Inode(inode) => Ok(inode),
+                other => Err(ApfsError::Corrupt(format!("Inode record for {} holds {:?}", ino, other))),
+            },
+            None => Err(ApfsError::Corrupt(format!("No inode record for {}", ino))),
+        }
+    }
+
+    fn parent_id(&self, ino: u64) -> Result<u64> {
+        Ok(self.inode(ino)?.value.parent_id)
+    }
+
+    /* Compressed files report the size of their decoded contents */
+    pub fn metadata(&self, ino: u64) -> Result<Metadata> {
+        let inode = self.inode(ino)?;
+        let mut metadata = Metadata::new(ino, &inode)?;
+        if inode.value.bsd_flags & UF_COMPRESSED != 0 {
+            if let Some(xattr) = self.get_xattr(ino, XATTR_DECMPFS_NAME)? {
+                metadata.size = Decmpfs::header(&xattr)?.uncompressed_size;
+            }
+        }
+        Ok(metadata)
+    }
+
+    /* Reader over the data stream of an inode, which is empty for
+       inodes without one such as directories. Files compressed by
+       decmpfs read as their decoded contents */
+    pub fn open_file(&self, ino: u64) -> Result<FileReader<'_, S>> {
+        let inode = self.inode(ino)?;
+        if inode.value.bsd_flags & UF_COMPRESSED != 0 {
+            if let Some(xattr) = self.get_xattr(ino, XATTR_DECMPFS_NAME)? {
+                let fork = if Decmpfs::header(&xattr)?.compression_type.in_resource_fork() {
+                    Some(self.resource_fork(ino)?)
+                } else {
+                    None
+                };
+                return Ok(FileReader::compressed(self.apfs, Decmpfs::new(&xattr, fork)?));
+            }
+        }
+        let size = match inode.xdata.get(&InoExtType::Dstream) {
+            Some(InodeXdata::Dstream(dstream)) => dstream.size,
+            _ => 0,
+        };
+        Ok(FileReader::new(self.apfs, &self.extents, inode.value.private_id, size))
+    }
+
+    /* Names of the extended attributes of an inode in fs-tree order */
+    pub fn list_xattrs(&self, ino: u64) -> Result<Vec<String>> {
+        let start = ApfsKey::new(ino, JObjTypes::Xattr, ApfsSubKey::None);
+        let mut names = vec![];
+        for record in self.root_tree.range(self.apfs, start..) {
+            let record = record?;
+            let id_and_type = &record.key.key.obj_id_and_type;
+            if id_and_type.id() != ino || id_and_type.r#type() != JObjTypes::Xattr {
+                break;
+            }
+            if let ApfsSubKey::Name(name) = record.key.subkey {
+                names.push(name.trim_end_matches('\0').to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    /* The value of an extended attribute, read from its data stream
+       when it is too large to be embedded in the record */
+    pub fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>> {
+        let value = match self.xattr(ino, name)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if !value.flags.contains(XattrFlags::DATA_STREAM) {
+            return Ok(Some(value.xdata));
+        }
+        let mut data = vec![];
+        self.xattr_stream(&value)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn xattr(&self, ino: u64, name: &str) -> Result<Option<JXattrVal>> {
+        let key = ApfsKey::new(ino, JObjTypes::Xattr, ApfsSubKey::Name(format!("{}\0", name)));
+        match self.root_tree.lookup(self.apfs, &key)? {
+            Some(record) => match record.value {
+                ApfsValue::Xattr(value) => Ok(Some(value)),
+                other => Err(ApfsError::Corrupt(format!("Extended attribute record for {} holds {:?}", ino, other))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn xattr_stream(&self, value: &JXattrVal) -> Result<FileReader<'_, S>> {
+        let stream = JXattrDstream::import(&mut &value.xdata[..])?;
+        Ok(FileReader::new(self.apfs, &self.extents, stream.xattr_obj_id, stream.dstream.size))
+    }
+
+    /* The resource fork is read in place when it has its own stream */
+    fn resource_fork(&self, ino: u64) -> Result<Box<dyn ForkSource + '_>> {
+        let value = self.xattr(ino, XATTR_RESOURCEFORK_NAME)?
+            .ok_or_else(|| ApfsError::Corrupt(format!("Compressed file {} has no resource fork", ino)))?;
+        if value.flags.contains(XattrFlags::DATA_STREAM) {
+            Ok(Box::new(self.xattr_stream(&value)?))
+        } else {
+            Ok(Box::new(Cursor::new(value.xdata)))
+        }
+    }
+
+    /* Entries of a directory in fs-tree order, which is by name hash
+       rather than by name */
+    pub fn read_dir(&self, ino: u64) -> ReadDir<'_, S> {
+        let start = ApfsKey::new(ino, JObjTypes::DirRec, ApfsSubKey::None);
+        ReadDir {
+            parent: ino,
+            records: self.root_tree.range(self.apfs, start..),
+            done: false,
+        }
+    }
+
+    /* Entries are keyed on the hash of the canonical form of their
+       name, so the name is looked up as given and, on volumes that
+       ignore case or normalization, the other entries sharing its hash
+       are compared the way the volume compares names. Names that are
+       not valid UTF-8 cannot match and are skipped */
+    fn find_entry(&self, parent: u64, name: &str) -> Result<Option<(u64, FileType)>> {
+        let features = self.incompatible_features();
+        let hash = name_hash(name, features.contains(VolumeIncompatFlags::CASE_INSENSITIVE));
+        let key = |name: &str| ApfsKey::new(parent, JObjTypes::DirRec, ApfsSubKey::DrecHashed(JDrecHashedKey::new(hash, name)));
+        if let Some(record) = self.root_tree.lookup(self.apfs, &key(name))? {
+            return Self::entry_target(parent, &record.value).map(Some);
+        }
+        if !features.intersects(VolumeIncompatFlags::CASE_INSENSITIVE | VolumeIncompatFlags::NORMALIZATION_INSENSITIVE) {
+            return Ok(None);
+        }
+        let name = self.normalize_name(name);
+        for record in self.root_tree.range(self.apfs, key("")..) {
+            let record = record?;
+            let id_and_type = &record.key.key.obj_id_and_type;
+            let found = match record.key.subkey {
+                ApfsSubKey::DrecHashed(ref found) if id_and_type.id() == parent && id_and_type.r#type() == JObjTypes::DirRec && found.hash() == hash => found,
+                _ => break,
+            };
+            if found.file_name().is_ok_and(|found| self.normalize_name(found) == name) {
+                return Self::entry_target(parent, &record.value).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn entry_target(parent: u64, value: &ApfsValue) -> Result<(u64, FileType)> {
+        match value {
+            ApfsValue::DirRec(entry) => Ok((entry.value.file_id(), entry.value.file_type()?)),
+            other => Err(ApfsError::Corrupt(format!("Directory {} has a malformed entry: {:?}", parent, other))),
+        }
+    }
+
+    /* Names are compared in canonical form on volumes that ignore case
+       or normalization, and byte for byte otherwise */
+    fn normalize_name<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        let features = self.incompatible_features();
+        if features.intersects(VolumeIncompatFlags::CASE_INSENSITIVE | VolumeIncompatFlags::NORMALIZATION_INSENSITIVE) {
+            Cow::Owned(canonical_name(name, features.contains(VolumeIncompatFlags::CASE_INSENSITIVE)))
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+}
+
+/* Names decomposed, and fully case folded when case is ignored */
+fn canonical_name(name: &str, case_insensitive: bool) -> String {
+    if case_insensitive {
+        name.nfd().default_case_fold().nfd().collect()
+    } else {
+        name.nfd().collect()
+    }
+}
+
+/* Hash of a name in directory entry keys: the low 22 bits of the
+   CRC-32C, without its final inversion, of the UTF-32 code points of
+   the name in canonical form */
+pub(crate) fn name_hash(name: &str, case_insensitive: bool) -> u32 {
+    let data: Vec<u8> = canonical_name(name, case_insensitive).chars()
+        .flat_map(|c| (c as u32).to_le_bytes())
+        .collect();
+    !crc32c::crc32c(&data) & 0x3fffff
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+    /* Set for hard links, naming the sibling link of this entry */
+    pub sibling_id: Option<u64>,
+}
+
+pub struct ReadDir<'a, S: BlockSource> {
+    parent: u64,
+    records: BtreeIter<'a, S, ApfsValue>,
+    done: bool,
+}
+
+impl<'a, S: BlockSource> ReadDir<'a, S> {
+    /* An entry that cannot be decoded, such as one whose name is not
+       valid UTF-8, is reported on its own and the listing goes on */
+    fn next_entry(&mut self) -> Result<Option<Result<DirEntry>>> {
+        let record = match self.records.next() {
+            Some(record) => record?,
+            None => return Ok(None),
+        };
+        let id_and_type = &record.key.key.obj_id_and_type;
+        if id_and_type.id() != self.parent || id_and_type.r#type() != JObjTypes::DirRec {
+            return Ok(None);
+        }
+        match (&record.key.subkey, &record.value) {
+            (ApfsSubKey::DrecHashed(key), ApfsValue::DirRec(entry)) => Ok(Some(Self::decode_entry(key, entry))),
+            _ => Err(ApfsError::Corrupt(format!("Directory {} has a malformed entry: {:?}", self.parent, record.key))),
+        }
+    }
+
+    fn decode_entry(key: &JDrecHashedKey, entry: &DrecValue) -> Result<DirEntry> {
+        Ok(DirEntry {
+            name: key.file_name()?.to_string(),
+            inode: entry.value.file_id(),
+            file_type: entry.value.file_type()?,
+            sibling_id: entry.xdata.get(&DrecExtType::DrecExtTypeSiblingId).map(|DrecXdata::SiblingId(id)| *id),
+        })
+    }
+}
+
+impl<'a, S: BlockSource> Iterator for ReadDir<'a, S> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(entry) => {
+                self.done = entry.is_none();
+                entry
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+impl<S: BlockSource> APFSMount<S> {
+    pub fn volume(&self, index: usize) -> Result<Volume<'_, S>> {
+        let oid = *self.volume_oids().get(index)
+            .ok_or_else(|| ApfsError::VolumeNotFound(format!("index {}", index)))?;
+        let superblock = match *self.load_object_oid(oid, StorageType::Virtual)? {
+            APFSObject::ApfsSuperblock(ref x) => x.clone(),
+            ref other => {
+                return Err(ApfsError::WrongObjectTypeForOid { oid, expected: ObjectType::Fs, found: other.object_type() });
+            },
+        };
+        Volume::load(self, index, superblock)
+    }
+
+    pub fn volume_by_name(&self, name: &str) -> Result<Volume<'_, S>> {
+        self.find_volume(|volume| volume.body.name().is_ok_and(|found| found == name))
+            .unwrap_or_else(|| Err(ApfsError::VolumeNotFound(format!("name {:?}", name))))
+    }
+
+    pub fn volume_by_uuid(&self, uuid: &Uuid) -> Result<Volume<'_, S>> {
+        self.find_volume(|volume| volume.body.vol_uuid == *uuid)
+            .unwrap_or_else(|| Err(ApfsError::VolumeNotFound(format!("UUID {}", uuid))))
+    }
+
+    pub fn volume_by_role(&self, role: VolumeRoles) -> Result<Volume<'_, S>> {
+        self.find_volume(|volume| volume.body.role == role)
+            .unwrap_or_else(|| Err(ApfsError::VolumeNotFound(format!("role {:?}", role))))
+    }
+
+    fn find_volume<F: Fn(&ApfsSuperblockObject) -> bool>(&self, matches: F) -> Option<Result<Volume<'_, S>>> {
+        let volumes = match self.volumes() {
+            Ok(volumes) => volumes,
+            Err(err) => return Some(Err(err)),
+        };
+        let index = volumes.iter().position(matches)?;
+        let superblock = volumes.into_iter().nth(index)?;
+        Some(Volume::load(self, index, superblock))
+    }
+}